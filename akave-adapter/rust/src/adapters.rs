@@ -1,16 +1,21 @@
-use anyhow::{anyhow, Result};
 use aws_sdk_s3::{self, primitives::ByteStream, Client as S3Client};
+use aws_sdk_s3::types::{CompletedMultipartUpload, CompletedPart};
 use aws_credential_types::{Credentials, provider::SharedCredentialsProvider};
 // The AWS SDK imports we actually need
 use aws_smithy_types::date_time::Format;
+use futures::stream::{self, Stream, TryStreamExt};
 use serde::{Deserialize, Serialize};
+use std::path::Path;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
 
-#[cfg(test)]
-use tokio;
+use crate::error::{AkaveError, ErrorClass, Result};
+use crate::retry::RetryPolicy;
 
 #[derive(Debug, Clone)]
 pub struct AkaveClient {
     s3_client: S3Client,
+    retry_policy: RetryPolicy,
     // Fields below are kept for future use but not currently used
     #[allow(dead_code)]
     endpoint: String,
@@ -53,8 +58,49 @@ pub struct ListObjectsOutput {
     pub name: String,
     pub prefix: String,
     pub delimiter: Option<String>,
+    /// Keys up to and including the first delimiter after the prefix, one entry per sub-prefix
+    pub common_prefixes: Vec<String>,
     pub max_keys: i32,
     pub is_truncated: bool,
+    /// Token to pass back to fetch the next page, set when the listing is truncated
+    pub next_continuation_token: Option<String>,
+}
+
+/// Options of a single `list_objects` request
+#[derive(Debug, Clone, Default)]
+pub struct ListObjectsOptions {
+    pub prefix: Option<String>,
+    /// Group keys that contain the delimiter after the prefix into common prefixes
+    pub delimiter: Option<String>,
+    /// Continue a truncated listing from the `next_continuation_token` of its previous page
+    pub continuation_token: Option<String>,
+    /// Maximum number of keys in the page; the service caps this at 1000
+    pub max_keys: Option<i32>,
+}
+
+/// Smallest part S3 accepts in a multipart upload, except for the last one
+pub const MIN_PART_SIZE: usize = 5 * 1024 * 1024;
+
+/// Most parts a multipart upload can have
+const MAX_PARTS: i32 = 10_000;
+
+/// How `upload_file` and `upload_reader` split large objects into parts
+#[derive(Debug, Clone)]
+pub struct MultipartOptions {
+    /// Size of every part but the last, at least `MIN_PART_SIZE`. Sources smaller than one part
+    /// are sent with a single PUT instead
+    pub part_size: usize,
+    /// Parts uploaded at the same time; memory use is about `part_size * (parallelism + 1)`
+    pub parallelism: usize,
+}
+
+impl Default for MultipartOptions {
+    fn default() -> Self {
+        Self {
+            part_size: 8 * 1024 * 1024,
+            parallelism: 4,
+        }
+    }
 }
 
 impl AkaveClient {
@@ -85,25 +131,37 @@ impl AkaveClient {
         let s3_config = aws_sdk_s3::config::Builder::from(&config)
             .endpoint_url(endpoint_str.clone())
             .force_path_style(true) // Important for S3-compatible services
+            .retry_config(aws_sdk_s3::config::retry::RetryConfig::disabled()) // Retries follow our RetryPolicy instead
             .build();
         
         let s3_client = aws_sdk_s3::Client::from_conf(s3_config);
         
         Self {
             s3_client,
+            retry_policy: RetryPolicy::default(),
             endpoint: endpoint_str,
             region: "akave-network".to_string(),
         }
     }
 
+    /// Retry failed requests according to `retry_policy` instead of the default policy
+    pub fn with_retry_policy(mut self, retry_policy: RetryPolicy) -> Self {
+        self.retry_policy = retry_policy;
+        self
+    }
+
     // Bucket operations
     pub async fn create_bucket(&self, bucket_name: &str) -> Result<()> {
-        match self.s3_client
-            .create_bucket()
-            .bucket(bucket_name)
-            .send()
-            .await 
-        {
+        let create_bucket_request = self.retry_policy.run(|| async {
+            self.s3_client
+                .create_bucket()
+                .bucket(bucket_name)
+                .send()
+                .await
+                .map_err(|err| AkaveError::from_sdk("Failed to create bucket", err))
+        }).await;
+        
+        match create_bucket_request {
             Ok(_) => {
                 // Successfully created bucket
                 Ok(())
@@ -114,12 +172,12 @@ impl AkaveClient {
                 match self.head_bucket(bucket_name).await {
                     Ok(true) => {
                         // Bucket exists, so creation probably succeeded
-                        println!("⚠️ Bucket creation returned an error but bucket exists. Considering operation successful.");
+                        tracing::warn!("Bucket {} exists although creating it failed, considering it created: {}", bucket_name, err);
                         Ok(())
                     },
                     _ => {
                         // Bucket doesn't exist, so this is a genuine error
-                        Err(anyhow!("Failed to create bucket: {}", err))
+                        Err(err)
                     }
                 }
             }
@@ -128,66 +186,69 @@ impl AkaveClient {
 
     pub async fn delete_bucket(&self, bucket_name: &str) -> Result<()> {
         // First, ensure the bucket is empty by listing objects
-        let list_result = self.list_objects(bucket_name, None).await;
-        
-        if let Ok(listing) = list_result {
-            if !listing.contents.is_empty() {
-                // Delete all objects in the bucket first
-                for object in &listing.contents {
-                    if let Err(e) = self.delete_object(bucket_name, &object.key).await {
-                        // Log error but continue with other objects
-                        eprintln!("Warning: Failed to delete object {} during bucket emptying: {}", object.key, e);
-                    }
+        let list_result: Result<Vec<Object>> = self.list_all_objects(bucket_name, None).try_collect().await;
+        
+        if let Ok(objects) = list_result
+            && !objects.is_empty()
+        {
+            // Delete all objects in the bucket first
+            for object in &objects {
+                if let Err(e) = self.delete_object(bucket_name, &object.key).await {
+                    // Log error but continue with other objects
+                    tracing::warn!("Failed to delete object {} while emptying bucket {}: {}", object.key, bucket_name, e);
                 }
-                
-                // Small delay to allow delete operations to complete
-                tokio::time::sleep(tokio::time::Duration::from_millis(500)).await;
             }
         }
         
-        // Now attempt to delete the empty bucket
-        let delete_bucket_request = self.s3_client
-            .delete_bucket()
-            .bucket(bucket_name)
-            .send()
-            .await;
-            
-        match delete_bucket_request {
-            Ok(_) => Ok(()),
-            Err(err) => {
-                // Specific error message with more context
-                Err(anyhow!("Failed to delete bucket '{}': {}", bucket_name, err))
-            },
-        }
+        // Now attempt to delete the empty bucket. Deletes of its objects may not have propagated
+        // yet, so a bucket that still looks non-empty is retried as well
+        let mut retry_policy = self.retry_policy.clone();
+        retry_policy.retryable.push(ErrorClass::Conflict);
+        
+        retry_policy.run(|| async {
+            self.s3_client
+                .delete_bucket()
+                .bucket(bucket_name)
+                .send()
+                .await
+                .map_err(|err| {
+                    // Specific error message with more context
+                    AkaveError::from_sdk(&format!("Failed to delete bucket '{}'", bucket_name), err)
+                })
+        }).await?;
+        
+        Ok(())
     }
 
     pub async fn head_bucket(&self, bucket_name: &str) -> Result<bool> {
-        let head_bucket_request = self.s3_client
-            .head_bucket()
-            .bucket(bucket_name)
-            .send()
-            .await;
+        let head_bucket_request = self.retry_policy.run(|| async {
+            self.s3_client
+                .head_bucket()
+                .bucket(bucket_name)
+                .send()
+                .await
+                .map_err(|err| AkaveError::from_sdk("Error checking bucket", err))
+        }).await;
             
         match head_bucket_request {
             Ok(_) => Ok(true),
-            Err(err) => {
-                if err.to_string().contains("404") {
-                    // Bucket doesn't exist
-                    Ok(false)
-                } else {
-                    // Other error
-                    Err(anyhow!("Error checking bucket: {}", err))
-                }
-            }
+            Err(err) => match err {
+                // Bucket doesn't exist
+                AkaveError::NotFound(_) => Ok(false),
+                // Other error
+                err => Err(err),
+            },
         }
     }
 
     pub async fn list_buckets(&self) -> Result<ListBucketsOutput> {
-        let response = self.s3_client
-            .list_buckets()
-            .send()
-            .await
-            .map_err(|err| anyhow!("Failed to list buckets: {}", err))?;
+        let response = self.retry_policy.run(|| async {
+            self.s3_client
+                .list_buckets()
+                .send()
+                .await
+                .map_err(|err| AkaveError::from_sdk("Failed to list buckets", err))
+        }).await?;
             
         // Convert from AWS SDK types to our types
         let buckets = response.buckets()
@@ -217,93 +278,285 @@ impl AkaveClient {
 
     // Object operations
     pub async fn put_object(&self, bucket_name: &str, key: &str, content: Vec<u8>) -> Result<()> {
-        self.s3_client
-            .put_object()
-            .bucket(bucket_name)
-            .key(key)
-            .body(ByteStream::from(content))
-            .send()
-            .await?;
+        // Every attempt sends its own body; cloning `Bytes` only bumps a reference count
+        let content = bytes::Bytes::from(content);
+        self.retry_policy.run(|| async {
+            self.s3_client
+                .put_object()
+                .bucket(bucket_name)
+                .key(key)
+                .body(ByteStream::from(content.clone()))
+                .send()
+                .await
+                .map_err(|err| AkaveError::from_sdk("Failed to put object", err))
+        }).await?;
+
+        Ok(())
+    }
+
+    /// Upload the file at `path`, in parts if it is larger than one part
+    pub async fn upload_file(&self, bucket_name: &str, key: &str, path: impl AsRef<Path>, options: &MultipartOptions) -> Result<()> {
+        let path = path.as_ref();
+        let file = tokio::fs::File::open(path).await
+            .map_err(|err| AkaveError::Other(format!("Failed to open {}: {}", path.display(), err)))?;
+        
+        self.upload_reader(bucket_name, key, file, options).await
+    }
+
+    /// Upload everything `reader` yields, in parts if it is larger than one part. A failed
+    /// multipart upload is aborted so its parts do not linger in the bucket
+    pub async fn upload_reader<R>(&self, bucket_name: &str, key: &str, mut reader: R, options: &MultipartOptions) -> Result<()>
+    where
+        R: AsyncRead + Unpin + Send,
+    {
+        if options.part_size < MIN_PART_SIZE {
+            return Err(AkaveError::Other(format!("Part size {} is below the minimum of {} bytes", options.part_size, MIN_PART_SIZE)));
+        }
+        
+        let first_part = read_part(&mut reader, options.part_size).await?;
+        if first_part.len() < options.part_size {
+            // The whole source fits in one part
+            return self.put_object(bucket_name, key, first_part).await;
+        }
+        
+        let upload_id = self.retry_policy.run(|| async {
+            self.s3_client
+                .create_multipart_upload()
+                .bucket(bucket_name)
+                .key(key)
+                .send()
+                .await
+                .map_err(|err| AkaveError::from_sdk("Failed to create multipart upload", err))
+        }).await?
+            .upload_id()
+            .map(|id| id.to_string())
+            .ok_or_else(|| AkaveError::ServiceQuirk(format!("Multipart upload of '{}' has no upload ID", key)))?;
+        
+        match self.upload_parts(bucket_name, key, &upload_id, first_part, reader, options).await {
+            Ok(()) => Ok(()),
+            Err(err) => {
+                let abort_request = self.retry_policy.run(|| async {
+                    self.s3_client
+                        .abort_multipart_upload()
+                        .bucket(bucket_name)
+                        .key(key)
+                        .upload_id(&upload_id)
+                        .send()
+                        .await
+                        .map_err(|err| AkaveError::from_sdk("Failed to abort multipart upload", err))
+                }).await;
+                if let Err(abort_err) = abort_request {
+                    // Log error but report the one that made the upload fail
+                    tracing::warn!("Could not abort multipart upload '{}' of '{}': {}", upload_id, key, abort_err);
+                }
+                Err(err)
+            }
+        }
+    }
 
+    /// Upload `first_part` and the rest of `reader` as the parts of `upload_id` and complete it
+    async fn upload_parts<R>(&self, bucket_name: &str, key: &str, upload_id: &str, first_part: Vec<u8>, reader: R, options: &MultipartOptions) -> Result<()>
+    where
+        R: AsyncRead + Unpin + Send,
+    {
+        let part_size = options.part_size;
+        
+        // Parts are read one after the other and uploaded `parallelism` at a time, in order
+        let parts = stream::try_unfold((Some(first_part), reader, 1), move |(first_part, mut reader, part_number)| async move {
+            let part = match first_part {
+                Some(part) => part,
+                None => read_part(&mut reader, part_size).await?,
+            };
+            if part.is_empty() {
+                return Ok(None);
+            }
+            if part_number > MAX_PARTS {
+                return Err(AkaveError::Other(format!("Object '{}' needs more than {} parts of {} bytes", key, MAX_PARTS, part_size)));
+            }
+            Ok(Some(((part_number, bytes::Bytes::from(part)), (None, reader, part_number + 1))))
+        });
+        
+        let completed_parts: Vec<CompletedPart> = parts
+            .map_ok(|(part_number, body)| self.upload_part(bucket_name, key, upload_id, part_number, body))
+            .try_buffered(options.parallelism.max(1))
+            .try_collect()
+            .await?;
+        
+        self.retry_policy.run(|| async {
+            self.s3_client
+                .complete_multipart_upload()
+                .bucket(bucket_name)
+                .key(key)
+                .upload_id(upload_id)
+                .multipart_upload(
+                    CompletedMultipartUpload::builder()
+                        .set_parts(Some(completed_parts.clone()))
+                        .build()
+                )
+                .send()
+                .await
+                .map_err(|err| AkaveError::from_sdk("Failed to complete multipart upload", err))
+        }).await?;
+        
         Ok(())
     }
 
+    async fn upload_part(&self, bucket_name: &str, key: &str, upload_id: &str, part_number: i32, body: bytes::Bytes) -> Result<CompletedPart> {
+        let response = self.retry_policy.run(|| async {
+            self.s3_client
+                .upload_part()
+                .bucket(bucket_name)
+                .key(key)
+                .upload_id(upload_id)
+                .part_number(part_number)
+                .body(ByteStream::from(body.clone()))
+                .send()
+                .await
+                .map_err(|err| AkaveError::from_sdk(&format!("Failed to upload part {}", part_number), err))
+        }).await?;
+        
+        Ok(CompletedPart::builder()
+            .set_e_tag(response.e_tag().map(|etag| etag.to_string()))
+            .part_number(part_number)
+            .build())
+    }
+
     pub async fn get_object(&self, bucket_name: &str, key: &str) -> Result<Vec<u8>> {
-        let response = self.s3_client
-            .get_object()
-            .bucket(bucket_name)
-            .key(key)
-            .send()
-            .await
-            .map_err(|err| anyhow!("Failed to get object: {}", err))?;
+        self.retry_policy.run(|| async {
+            let response = self.s3_client
+                .get_object()
+                .bucket(bucket_name)
+                .key(key)
+                .send()
+                .await
+                .map_err(|err| AkaveError::from_sdk("Failed to get object", err))?;
+                
+            // Read the body stream into a Vec<u8>; a connection dropped halfway is worth another try
+            let bytes = response.body.collect().await
+                .map_err(|err| AkaveError::Transient(format!("Failed to read object: {}", err)))?
+                .to_vec();
+            Ok(bytes)
+        }).await
+    }
+
+    /// Download an object to the file at `path` without holding it in memory and return its size.
+    /// The file is written afresh on every attempt, so a retried download does not leave the bytes
+    /// of a dropped connection behind
+    pub async fn download_file(&self, bucket_name: &str, key: &str, path: impl AsRef<Path>) -> Result<u64> {
+        let path = path.as_ref();
+        self.retry_policy.run(|| async {
+            let mut response = self.s3_client
+                .get_object()
+                .bucket(bucket_name)
+                .key(key)
+                .send()
+                .await
+                .map_err(|err| AkaveError::from_sdk("Failed to get object", err))?;
             
-        // Read the body stream into a Vec<u8>
-        let bytes = response.body.collect().await?.to_vec();
-        Ok(bytes)
+            let mut file = tokio::fs::File::create(path).await
+                .map_err(|err| AkaveError::Other(format!("Failed to create {}: {}", path.display(), err)))?;
+            let mut size = 0;
+            while let Some(chunk) = response.body.try_next().await
+                .map_err(|err| AkaveError::Transient(format!("Failed to read object: {}", err)))?
+            {
+                file.write_all(&chunk).await
+                    .map_err(|err| AkaveError::Other(format!("Failed to write {}: {}", path.display(), err)))?;
+                size += chunk.len() as u64;
+            }
+            file.sync_all().await
+                .map_err(|err| AkaveError::Other(format!("Failed to write {}: {}", path.display(), err)))?;
+            Ok(size)
+        }).await
     }
 
     pub async fn delete_object(&self, bucket_name: &str, key: &str) -> Result<()> {
-        // Send the delete request
-        self.s3_client
-            .delete_object()
-            .bucket(bucket_name)
-            .key(key)
-            .send()
-            .await
-            .map_err(|err| anyhow!("Failed to delete object: {}", err))?;
-        
-        // The AWS S3 API returns a 204 No Content for successful deletion
-        // Add a small delay to allow deletion to propagate (this helps with eventual consistency)
-        tokio::time::sleep(tokio::time::Duration::from_millis(500)).await;
+        // Send the delete request; the AWS S3 API returns a 204 No Content for successful deletion.
+        // Akave O3 may keep serving the object for a moment, see `wait_until_object_deleted`
+        self.retry_policy.run(|| async {
+            self.s3_client
+                .delete_object()
+                .bucket(bucket_name)
+                .key(key)
+                .send()
+                .await
+                .map_err(|err| AkaveError::from_sdk("Failed to delete object", err))
+        }).await?;
         
         Ok(())
     }
 
     pub async fn head_object(&self, bucket_name: &str, key: &str) -> Result<bool> {
-        let head_request = self.s3_client
-            .head_object()
-            .bucket(bucket_name)
-            .key(key)
-            .send()
-            .await;
+        let head_request = self.retry_policy.run(|| async {
+            self.s3_client
+                .head_object()
+                .bucket(bucket_name)
+                .key(key)
+                .send()
+                .await
+                .map_err(|err| AkaveError::from_sdk("Error checking object", err))
+        }).await;
         
         match head_request {
             Ok(_) => Ok(true),
-            Err(err) => {
-                let error_str = err.to_string().to_lowercase();
-                
-                // Check for various error messages that indicate object doesn't exist
-                if error_str.contains("404") || 
-                   error_str.contains("not found") || 
-                   error_str.contains("no such key") ||
-                   error_str.contains("does not exist") {
-                    // Object doesn't exist
-                    Ok(false)
-                } else if error_str.contains("service error") && 
-                          (error_str.contains("head_object") || error_str.contains("head object")) {
-                    // For Akave O3, sometimes service errors might be returned instead of 404
-                    // when checking objects that don't exist (service-specific behavior)
-                    Ok(false)
-                } else {
-                    // Other error
-                    Err(anyhow!("Error checking object: {}", err))
-                }
-            }
+            Err(err) => match err {
+                // Object doesn't exist
+                AkaveError::NotFound(_) => Ok(false),
+                // For Akave O3, sometimes bare service errors might be returned instead of 404
+                // when checking objects that don't exist (service-specific behavior)
+                AkaveError::ServiceQuirk(_) => Ok(false),
+                // Other error
+                err => Err(err),
+            },
         }
     }
 
-    pub async fn list_objects(&self, bucket_name: &str, prefix: Option<&str>) -> Result<ListObjectsOutput> {
-        let mut request = self.s3_client.list_objects_v2().bucket(bucket_name);
-        
-        // Add prefix if specified
-        if let Some(prefix_value) = prefix {
-            request = request.prefix(prefix_value);
+    /// Poll `head_object` until the object exists, e.g. before reading an object just written.
+    /// Fails with `NotFound` if it still does not after `timeout`
+    pub async fn wait_until_object_exists(&self, bucket_name: &str, key: &str, timeout: Duration) -> Result<()> {
+        self.wait_for_object(bucket_name, key, true, timeout).await
+    }
+
+    /// Poll `head_object` until the object is gone, e.g. before reusing the key or deleting the
+    /// bucket. Fails with `Conflict` if it is still there after `timeout`
+    pub async fn wait_until_object_deleted(&self, bucket_name: &str, key: &str, timeout: Duration) -> Result<()> {
+        self.wait_for_object(bucket_name, key, false, timeout).await
+    }
+
+    async fn wait_for_object(&self, bucket_name: &str, key: &str, exists: bool, timeout: Duration) -> Result<()> {
+        let deadline = Instant::now() + timeout;
+        let mut attempt = 1;
+        
+        while self.head_object(bucket_name, key).await? != exists {
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(if exists {
+                    AkaveError::NotFound(format!("Object '{}' did not appear within {:?}", key, timeout))
+                } else {
+                    AkaveError::Conflict(format!("Object '{}' still exists after {:?}", key, timeout))
+                });
+            }
+            
+            // Poll on the retry policy's backoff schedule, without sleeping past the deadline
+            tokio::time::sleep(self.retry_policy.backoff(attempt).min(deadline - now)).await;
+            attempt += 1;
         }
         
-        let response = request
-            .send()
-            .await
-            .map_err(|err| anyhow!("Failed to list objects: {}", err))?;
+        Ok(())
+    }
+
+    pub async fn list_objects(&self, bucket_name: &str, options: &ListObjectsOptions) -> Result<ListObjectsOutput> {
+        let response = self.retry_policy.run(|| async {
+            self.s3_client
+                .list_objects_v2()
+                .bucket(bucket_name)
+                .set_prefix(options.prefix.clone())
+                .set_delimiter(options.delimiter.clone())
+                .set_continuation_token(options.continuation_token.clone())
+                .set_max_keys(options.max_keys)
+                .send()
+                .await
+                .map_err(|err| AkaveError::from_sdk("Failed to list objects", err))
+        }).await?;
             
         // Convert from AWS SDK types to our types
         let contents = response.contents()
@@ -320,15 +573,88 @@ impl AkaveClient {
             })
             .collect();
             
+        let common_prefixes = response.common_prefixes()
+            .unwrap_or_default()
+            .iter()
+            .filter_map(|p| p.prefix().map(|s| s.to_string()))
+            .collect();
+            
         Ok(ListObjectsOutput {
             contents,
             name: bucket_name.to_string(),
-            prefix: prefix.unwrap_or("").to_string(),
+            prefix: options.prefix.clone().unwrap_or_default(),
             delimiter: response.delimiter().map(|s| s.to_string()),
+            common_prefixes,
             max_keys: response.max_keys() as i32,
             is_truncated: response.is_truncated(),
+            next_continuation_token: response.next_continuation_token().map(|s| s.to_string()),
         })
     }
+
+    /// List one level of the hierarchy under `prefix`, like a directory: the objects directly
+    /// under it and the sub-prefixes up to the next `delimiter`, across all pages
+    pub async fn list_directory(&self, bucket_name: &str, prefix: Option<&str>, delimiter: &str) -> Result<ListObjectsOutput> {
+        let mut options = ListObjectsOptions {
+            prefix: prefix.map(|p| p.to_string()),
+            delimiter: Some(delimiter.to_string()),
+            ..Default::default()
+        };
+        let mut listing = self.list_objects(bucket_name, &options).await?;
+        
+        while listing.is_truncated {
+            let Some(token) = listing.next_continuation_token.take() else {
+                return Err(AkaveError::ServiceQuirk(format!("Listing of bucket '{}' is truncated but has no continuation token", bucket_name)));
+            };
+            options.continuation_token = Some(token);
+            let page = self.list_objects(bucket_name, &options).await?;
+            
+            listing.contents.extend(page.contents);
+            listing.common_prefixes.extend(page.common_prefixes);
+            listing.is_truncated = page.is_truncated;
+            listing.next_continuation_token = page.next_continuation_token;
+        }
+        
+        Ok(listing)
+    }
+
+    /// Stream every object under `prefix`, requesting the next page as the previous one runs out
+    pub fn list_all_objects<'a>(
+        &'a self,
+        bucket_name: &'a str,
+        prefix: Option<&'a str>,
+    ) -> impl Stream<Item = Result<Object>> + 'a {
+        // The state is the token of the next page to request, or `None` once the last page is in
+        let pages = stream::try_unfold(Some(None), move |next: Option<Option<String>>| async move {
+            let Some(continuation_token) = next else {
+                return Ok(None);
+            };
+            let options = ListObjectsOptions {
+                prefix: prefix.map(|p| p.to_string()),
+                continuation_token,
+                ..Default::default()
+            };
+            let page = self.list_objects(bucket_name, &options).await?;
+            
+            let next = match (page.is_truncated, page.next_continuation_token) {
+                (false, _) => None,
+                (true, Some(token)) => Some(Some(token)),
+                (true, None) => {
+                    return Err(AkaveError::ServiceQuirk(format!("Listing of bucket '{}' is truncated but has no continuation token", bucket_name)));
+                }
+            };
+            Ok(Some((stream::iter(page.contents.into_iter().map(Ok)), next)))
+        });
+        
+        pages.try_flatten()
+    }
+}
+
+/// Read up to `part_size` bytes from `reader`, fewer only at the end of the source
+async fn read_part<R: AsyncRead + Unpin>(reader: &mut R, part_size: usize) -> Result<Vec<u8>> {
+    let mut part = Vec::with_capacity(part_size);
+    reader.take(part_size as u64).read_to_end(&mut part).await
+        .map_err(|err| AkaveError::Other(format!("Failed to read upload source: {}", err)))?;
+    Ok(part)
 }
 
 #[cfg(test)]
@@ -342,7 +668,7 @@ mod mock_tests {
         let mut server = mockito::Server::new_async().await;
         
         // Print the expected URL for debugging
-        let expected_url = format!("/{}" , bucket_name);
+        let expected_url = format!("/{}/", bucket_name);
         println!("🧪 Expected URL: {}", expected_url);
         
         // Create a mock for the PUT request to create a bucket
@@ -391,7 +717,7 @@ mod mock_tests {
         let mut server = mockito::Server::new_async().await;
         
         // Print the expected URL for debugging
-        let expected_url = format!("/{}/{}", bucket_name, object_key);
+        let expected_url = format!("/{}/{}?x-id=PutObject", bucket_name, object_key);
         println!("🧪 Expected URL: {}", expected_url);
         
         // Create a mock for the PUT request to upload an object
@@ -432,10 +758,342 @@ mod mock_tests {
         mock.assert_async().await;
         println!("✅ Mock assertions passed");
     }
+    
+    #[tokio::test]
+    async fn test_list_all_objects_follows_continuation_tokens() {
+        use futures::TryStreamExt;
+        use mockito::Matcher;
+        
+        let bucket_name = "test-bucket";
+        let page = |keys: &[&str], next: Option<&str>| {
+            let contents: String = keys.iter()
+                .map(|key| format!("<Contents><Key>{}</Key><Size>1</Size></Contents>", key))
+                .collect();
+            let next = next
+                .map(|token| format!("<NextContinuationToken>{}</NextContinuationToken>", token))
+                .unwrap_or_default();
+            format!(
+                "<ListBucketResult><Name>{}</Name><MaxKeys>2</MaxKeys><IsTruncated>{}</IsTruncated>{}{}</ListBucketResult>",
+                bucket_name, !next.is_empty(), contents, next
+            )
+        };
+        
+        let mut server = mockito::Server::new_async().await;
+        let expected_url = format!("/{}/", bucket_name);
+        
+        // The first page answers the requests without a continuation token
+        let first = server.mock("GET", expected_url.as_str())
+            .match_query(Matcher::Regex("^list-type=2(&max-keys=2)?$".into()))
+            .with_status(200)
+            .expect(2)
+            .with_body(page(&["a", "b"], Some("token-1")))
+            .create_async()
+            .await;
+        let second = server.mock("GET", expected_url.as_str())
+            .match_query(Matcher::UrlEncoded("continuation-token".into(), "token-1".into()))
+            .with_status(200)
+            .with_body(page(&["c"], None))
+            .create_async()
+            .await;
+        
+        let client = super::AkaveClient::new(
+            &server.url(),
+            "test_access_key",
+            "test_secret_key"
+        ).await;
+        
+        // A single page reports where the next one starts
+        let options = super::ListObjectsOptions { max_keys: Some(2), ..Default::default() };
+        let listing = client.list_objects(bucket_name, &options).await
+            .expect("Failed to list objects");
+        assert!(listing.is_truncated);
+        assert_eq!(listing.next_continuation_token.as_deref(), Some("token-1"));
+        
+        // The stream walks all of them
+        let objects: Vec<super::Object> = client.list_all_objects(bucket_name, None)
+            .try_collect()
+            .await
+            .expect("Failed to list all objects");
+        let keys: Vec<&str> = objects.iter().map(|obj| obj.key.as_str()).collect();
+        assert_eq!(keys, ["a", "b", "c"]);
+        
+        first.assert_async().await;
+        second.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn test_list_directory_returns_common_prefixes() {
+        use mockito::Matcher;
+        
+        let bucket_name = "test-bucket";
+        let mut server = mockito::Server::new_async().await;
+        let expected_url = format!("/{}/", bucket_name);
+        
+        let mock = server.mock("GET", expected_url.as_str())
+            .match_query(Matcher::AllOf(vec![
+                Matcher::UrlEncoded("delimiter".into(), "/".into()),
+                Matcher::UrlEncoded("prefix".into(), "sales/".into()),
+            ]))
+            .with_status(200)
+            .with_body(
+                "<ListBucketResult><Name>test-bucket</Name><Prefix>sales/</Prefix><Delimiter>/</Delimiter>\
+                 <MaxKeys>1000</MaxKeys><IsTruncated>false</IsTruncated>\
+                 <Contents><Key>sales/README</Key><Size>1</Size></Contents>\
+                 <CommonPrefixes><Prefix>sales/v1/</Prefix></CommonPrefixes>\
+                 <CommonPrefixes><Prefix>sales/v2/</Prefix></CommonPrefixes>\
+                 </ListBucketResult>"
+            )
+            .create_async()
+            .await;
+        
+        let client = super::AkaveClient::new(
+            &server.url(),
+            "test_access_key",
+            "test_secret_key"
+        ).await;
+        
+        let listing = client.list_directory(bucket_name, Some("sales/"), "/").await
+            .expect("Failed to list directory");
+        
+        assert_eq!(listing.delimiter.as_deref(), Some("/"));
+        assert_eq!(listing.contents.len(), 1);
+        assert_eq!(listing.contents[0].key, "sales/README");
+        assert_eq!(listing.common_prefixes, ["sales/v1/", "sales/v2/"]);
+        mock.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn test_errors_are_classified_from_the_response() {
+        let bucket_name = "test-bucket";
+        let mut server = mockito::Server::new_async().await;
+        
+        let _missing = server.mock("HEAD", format!("/{}/missing", bucket_name).as_str())
+            .with_status(404)
+            .create_async()
+            .await;
+        let _down = server.mock("HEAD", format!("/{}/down", bucket_name).as_str())
+            .with_status(503)
+            .create_async()
+            .await;
+        let _denied = server.mock("GET", format!("/{}/secret?x-id=GetObject", bucket_name).as_str())
+            .with_status(403)
+            .with_body("<Error><Code>AccessDenied</Code><Message>Access Denied</Message></Error>")
+            .create_async()
+            .await;
+        
+        let client = super::AkaveClient::new(
+            &server.url(),
+            "test_access_key",
+            "test_secret_key"
+        ).await;
+        
+        // A missing object is an answer, an outage is not
+        assert!(!client.head_object(bucket_name, "missing").await.expect("404 should mean missing"));
+        let err = client.head_object(bucket_name, "down").await.unwrap_err();
+        assert!(matches!(err, super::AkaveError::Transient(_)), "unexpected error: {:?}", err);
+        
+        let err = client.get_object(bucket_name, "secret").await.unwrap_err();
+        assert!(matches!(err, super::AkaveError::AccessDenied(_)), "unexpected error: {:?}", err);
+    }
+
+    #[tokio::test]
+    async fn test_wait_until_object_exists_polls_head_object() {
+        use std::time::Duration;
+        
+        let bucket_name = "test-bucket";
+        let expected_url = format!("/{}/test-key", bucket_name);
+        let mut server = mockito::Server::new_async().await;
+        
+        // The object shows up on the third check; until a mock has all its expected hits it is
+        // preferred over the ones created after it
+        let missing = server.mock("HEAD", expected_url.as_str())
+            .with_status(404)
+            .expect(2)
+            .create_async()
+            .await;
+        let present = server.mock("HEAD", expected_url.as_str())
+            .with_status(200)
+            .create_async()
+            .await;
+        
+        let retry_policy = super::RetryPolicy {
+            initial_backoff: Duration::from_millis(1),
+            ..Default::default()
+        };
+        let client = super::AkaveClient::new(
+            &server.url(),
+            "test_access_key",
+            "test_secret_key"
+        ).await.with_retry_policy(retry_policy);
+        
+        client.wait_until_object_exists(bucket_name, "test-key", Duration::from_secs(5)).await
+            .expect("Object should appear");
+        missing.assert_async().await;
+        present.assert_async().await;
+        
+        // An object that never goes away runs into the timeout
+        let err = client.wait_until_object_deleted(bucket_name, "test-key", Duration::from_millis(20)).await
+            .unwrap_err();
+        assert!(matches!(err, super::AkaveError::Conflict(_)), "unexpected error: {:?}", err);
+    }
+
+    #[tokio::test]
+    async fn test_download_file_streams_object_to_disk() {
+        let bucket_name = "test-bucket";
+        let mut server = mockito::Server::new_async().await;
+        
+        let mock = server.mock("GET", format!("/{}/data.parquet?x-id=GetObject", bucket_name).as_str())
+            .with_status(200)
+            .with_body(vec![7u8; 100_000])
+            .create_async()
+            .await;
+        
+        let client = super::AkaveClient::new(
+            &server.url(),
+            "test_access_key",
+            "test_secret_key"
+        ).await;
+        
+        // Whatever the file held before is replaced
+        let path = std::env::temp_dir().join(format!("akave-download-test-{}", std::process::id()));
+        std::fs::write(&path, vec![1u8; 200_000]).unwrap();
+        let result = client.download_file(bucket_name, "data.parquet", &path).await;
+        let content = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        
+        assert_eq!(result.expect("Failed to download object"), 100_000);
+        assert_eq!(content, vec![7u8; 100_000]);
+        mock.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn test_upload_reader_sends_small_sources_in_one_put() {
+        let bucket_name = "test-bucket";
+        let mut server = mockito::Server::new_async().await;
+        
+        let mock = server.mock("PUT", format!("/{}/small?x-id=PutObject", bucket_name).as_str())
+            .match_body("small object")
+            .with_status(200)
+            .create_async()
+            .await;
+        
+        let client = super::AkaveClient::new(
+            &server.url(),
+            "test_access_key",
+            "test_secret_key"
+        ).await;
+        
+        client.upload_reader(bucket_name, "small", &b"small object"[..], &super::MultipartOptions::default()).await
+            .expect("Failed to upload object");
+        mock.assert_async().await;
+    }
+    
+    #[tokio::test]
+    async fn test_upload_file_in_parts() {
+        use mockito::Matcher;
+        
+        let bucket_name = "test-bucket";
+        let object_path = format!("/{}/big", bucket_name);
+        let mut server = mockito::Server::new_async().await;
+        
+        let create = server.mock("POST", object_path.as_str())
+            .match_query(Matcher::UrlEncoded("uploads".into(), "".into()))
+            .with_status(200)
+            .with_body("<InitiateMultipartUploadResult><Bucket>test-bucket</Bucket><Key>big</Key><UploadId>upload-1</UploadId></InitiateMultipartUploadResult>")
+            .create_async()
+            .await;
+        let mut part = |part_number: usize, size: usize| {
+            server.mock("PUT", object_path.as_str())
+                .match_query(Matcher::AllOf(vec![
+                    Matcher::UrlEncoded("uploadId".into(), "upload-1".into()),
+                    Matcher::UrlEncoded("partNumber".into(), part_number.to_string()),
+                ]))
+                .match_header("content-length", size.to_string().as_str())
+                .with_status(200)
+                .with_header("ETag", &format!("\"etag-{}\"", part_number))
+        };
+        let first = part(1, super::MIN_PART_SIZE).create_async().await;
+        let second = part(2, 10).create_async().await;
+        let complete = server.mock("POST", object_path.as_str())
+            .match_query(Matcher::UrlEncoded("uploadId".into(), "upload-1".into()))
+            .match_body(Matcher::AllOf(vec![
+                Matcher::Regex("<PartNumber>1</PartNumber>".into()),
+                Matcher::Regex("<PartNumber>2</PartNumber>".into()),
+            ]))
+            .with_status(200)
+            .with_body("<CompleteMultipartUploadResult><Bucket>test-bucket</Bucket><Key>big</Key><ETag>\"etag\"</ETag></CompleteMultipartUploadResult>")
+            .create_async()
+            .await;
+        
+        let client = super::AkaveClient::new(
+            &server.url(),
+            "test_access_key",
+            "test_secret_key"
+        ).await;
+        
+        // One full part and a short last one
+        let path = std::env::temp_dir().join(format!("akave-upload-test-{}", std::process::id()));
+        std::fs::write(&path, vec![7u8; super::MIN_PART_SIZE + 10]).unwrap();
+        let options = super::MultipartOptions { part_size: super::MIN_PART_SIZE, parallelism: 2 };
+        let result = client.upload_file(bucket_name, "big", &path, &options).await;
+        std::fs::remove_file(&path).unwrap();
+        
+        result.expect("Failed to upload file");
+        create.assert_async().await;
+        first.assert_async().await;
+        second.assert_async().await;
+        complete.assert_async().await;
+    }
+    
+    #[tokio::test]
+    async fn test_failed_multipart_upload_is_aborted() {
+        use mockito::Matcher;
+        
+        let bucket_name = "test-bucket";
+        let object_path = format!("/{}/big", bucket_name);
+        let mut server = mockito::Server::new_async().await;
+        
+        let _create = server.mock("POST", object_path.as_str())
+            .match_query(Matcher::UrlEncoded("uploads".into(), "".into()))
+            .with_status(200)
+            .with_body("<InitiateMultipartUploadResult><Bucket>test-bucket</Bucket><Key>big</Key><UploadId>upload-1</UploadId></InitiateMultipartUploadResult>")
+            .create_async()
+            .await;
+        let _part = server.mock("PUT", object_path.as_str())
+            .match_query(Matcher::UrlEncoded("uploadId".into(), "upload-1".into()))
+            .with_status(403)
+            .with_body("<Error><Code>AccessDenied</Code><Message>Access Denied</Message></Error>")
+            .create_async()
+            .await;
+        let complete = server.mock("POST", object_path.as_str())
+            .match_query(Matcher::UrlEncoded("uploadId".into(), "upload-1".into()))
+            .expect(0)
+            .create_async()
+            .await;
+        let abort = server.mock("DELETE", object_path.as_str())
+            .match_query(Matcher::UrlEncoded("uploadId".into(), "upload-1".into()))
+            .with_status(204)
+            .create_async()
+            .await;
+        
+        let client = super::AkaveClient::new(
+            &server.url(),
+            "test_access_key",
+            "test_secret_key"
+        ).await;
+        
+        let body = vec![7u8; 2 * super::MIN_PART_SIZE];
+        let options = super::MultipartOptions { part_size: super::MIN_PART_SIZE, parallelism: 1 };
+        let err = client.upload_reader(bucket_name, "big", &body[..], &options).await.unwrap_err();
+        
+        assert!(matches!(err, super::AkaveError::AccessDenied(_)), "unexpected error: {:?}", err);
+        complete.assert_async().await;
+        abort.assert_async().await;
+    }
 }
 
 
-// Test using 'cargo test --package akave-adapter --bin akave-adapter -- integration_tests:: --ignored --test-threads=1 '
+// Test using 'cargo test --package akave-adapter --lib -- integration_tests:: --ignored --test-threads=1 '
 #[cfg(test)]
 mod integration_tests {
     use super::*;
@@ -450,7 +1108,7 @@ mod integration_tests {
     }
 
     /// Setup function for integration tests
-    async fn setup_test_environment() -> Result<TestConfig> {
+    async fn setup_test_environment() -> anyhow::Result<TestConfig> {
         dotenv().ok();
         let endpoint = env::var("AKAVE_ENDPOINT").expect("AKAVE_ENDPOINT must be set");
         let access_key = env::var("AKAVE_ACCESS_KEY").expect("AKAVE_ACCESS_KEY must be set");
@@ -468,12 +1126,12 @@ mod integration_tests {
         // Create test bucket
         println!("🔧 Creating test bucket: {}", test_bucket);
         client.create_bucket(&test_bucket).await
-            .map_err(|e| anyhow!("Failed to create test bucket: {}", e))?;
+            .map_err(|e| anyhow::anyhow!("Failed to create test bucket: {}", e))?;
         
         // Verify bucket was created
         match client.head_bucket(&test_bucket).await {
             Ok(true) => println!("✅ Test bucket created successfully"),
-            _ => return Err(anyhow!("Failed to verify bucket creation")),
+            _ => return Err(anyhow::anyhow!("Failed to verify bucket creation")),
         }
         
         Ok(TestConfig { client, test_bucket })
@@ -540,7 +1198,7 @@ mod integration_tests {
         println!("✅ Object retrieved and content verified");
         
         // List objects
-        let objects = config.client.list_objects(&config.test_bucket, None).await
+        let objects = config.client.list_objects(&config.test_bucket, &ListObjectsOptions::default()).await
             .expect("Failed to list objects");
         assert!(objects.contents.iter().any(|obj| obj.key == object_key), "Uploaded object not found in listing");
         println!("✅ Object found in bucket listing");
@@ -552,7 +1210,9 @@ mod integration_tests {
         
         // Verify deletion with appropriate error handling for Akave O3
         println!("🧪 Verifying object deletion...");
-        std::thread::sleep(std::time::Duration::from_secs(1)); // Small delay for eventual consistency
+        if let Err(err) = config.client.wait_until_object_deleted(&config.test_bucket, object_key, Duration::from_secs(5)).await {
+            println!("⚠️ {}", err);
+        }
         
         match config.client.s3_client.get_object()
             .bucket(&config.test_bucket)
@@ -624,7 +1284,7 @@ mod integration_tests {
         println!("✅ Object retrieved and content verified");
         
         // 4. List objects
-        let objects = client.list_objects(&test_bucket, None).await
+        let objects = client.list_objects(&test_bucket, &ListObjectsOptions::default()).await
             .expect("Failed to list objects");
         assert!(objects.contents.iter().any(|obj| obj.key == object_key), "Uploaded object not found in listing");
         println!("✅ Object found in bucket listing");
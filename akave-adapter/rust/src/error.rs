@@ -0,0 +1,116 @@
+use aws_sdk_s3::error::{ProvideErrorMetadata, SdkError};
+use aws_smithy_types::error::display::DisplayErrorContext;
+use thiserror::Error;
+
+pub type Result<T, E = AkaveError> = std::result::Result<T, E>;
+
+/// Errors returned by `AkaveClient`, classified from the S3 error code and HTTP status of the
+/// response. Every variant carries a message naming the failed operation and the service's answer.
+#[derive(Debug, Error)]
+pub enum AkaveError {
+    /// The bucket or object does not exist
+    #[error("{0}")]
+    NotFound(String),
+    /// The credentials were rejected or lack permission for the request
+    #[error("{0}")]
+    AccessDenied(String),
+    /// The request conflicts with the current state, e.g. deleting a bucket that is not empty
+    #[error("{0}")]
+    Conflict(String),
+    /// The service asked us to slow down
+    #[error("{0}")]
+    Throttled(String),
+    /// Timeouts, connection failures and server errors that may succeed when tried again
+    #[error("{0}")]
+    Transient(String),
+    /// Akave O3 answered in a way S3 would not, such as an error without a code or a response the
+    /// SDK cannot parse
+    #[error("{0}")]
+    ServiceQuirk(String),
+    #[error("{0}")]
+    Other(String),
+}
+
+/// The class of an `AkaveError`, without its message
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorClass {
+    NotFound,
+    AccessDenied,
+    Conflict,
+    Throttled,
+    Transient,
+    ServiceQuirk,
+    Other,
+}
+
+impl AkaveError {
+    pub fn class(&self) -> ErrorClass {
+        match self {
+            Self::NotFound(_) => ErrorClass::NotFound,
+            Self::AccessDenied(_) => ErrorClass::AccessDenied,
+            Self::Conflict(_) => ErrorClass::Conflict,
+            Self::Throttled(_) => ErrorClass::Throttled,
+            Self::Transient(_) => ErrorClass::Transient,
+            Self::ServiceQuirk(_) => ErrorClass::ServiceQuirk,
+            Self::Other(_) => ErrorClass::Other,
+        }
+    }
+
+    /// Classify a failed SDK request, prefixing its message with `context`
+    pub(crate) fn from_sdk<E, B>(context: &str, err: SdkError<E, http::Response<B>>) -> Self
+    where
+        E: ProvideErrorMetadata + std::error::Error + 'static,
+        B: std::fmt::Debug,
+    {
+        let message = format!("{}: {}", context, DisplayErrorContext(&err));
+        match &err {
+            SdkError::ServiceError(service_error) => {
+                let status = service_error.raw().status().as_u16();
+                Self::classify(err.code(), status, message)
+            }
+            SdkError::TimeoutError(_) | SdkError::DispatchFailure(_) => Self::Transient(message),
+            // The response came back but could not be parsed as an S3 response
+            SdkError::ResponseError(_) => Self::ServiceQuirk(message),
+            _ => Self::Other(message),
+        }
+    }
+
+    fn classify(code: Option<&str>, status: u16, message: String) -> Self {
+        match (code, status) {
+            (Some("NoSuchKey" | "NoSuchBucket" | "NoSuchUpload" | "NotFound"), _) | (_, 404) => {
+                Self::NotFound(message)
+            }
+            (Some("AccessDenied" | "InvalidAccessKeyId" | "SignatureDoesNotMatch"), _)
+            | (_, 401 | 403) => Self::AccessDenied(message),
+            (Some("BucketAlreadyExists" | "BucketAlreadyOwnedByYou" | "BucketNotEmpty" | "OperationAborted"), _)
+            | (_, 409) => Self::Conflict(message),
+            (Some("SlowDown" | "Throttling" | "ThrottlingException" | "RequestLimitExceeded"), _)
+            | (_, 429) => Self::Throttled(message),
+            (Some("InternalError" | "ServiceUnavailable" | "RequestTimeout"), _)
+            | (_, 500 | 502 | 503 | 504) => Self::Transient(message),
+            // S3 always names the error; Akave O3 sometimes answers with a bare status
+            (None, _) => Self::ServiceQuirk(message),
+            _ => Self::Other(message),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_classify_prefers_error_code_over_status() {
+        let classify = |code, status| AkaveError::classify(code, status, String::new());
+
+        assert!(matches!(classify(Some("NoSuchKey"), 400), AkaveError::NotFound(_)));
+        assert!(matches!(classify(None, 404), AkaveError::NotFound(_)));
+        assert!(matches!(classify(Some("AccessDenied"), 403), AkaveError::AccessDenied(_)));
+        assert!(matches!(classify(Some("BucketNotEmpty"), 409), AkaveError::Conflict(_)));
+        assert!(matches!(classify(Some("SlowDown"), 503), AkaveError::Throttled(_)));
+        assert!(matches!(classify(None, 503), AkaveError::Transient(_)));
+        assert!(matches!(classify(Some("InternalError"), 500), AkaveError::Transient(_)));
+        assert!(matches!(classify(None, 400), AkaveError::ServiceQuirk(_)));
+        assert!(matches!(classify(Some("InvalidArgument"), 400), AkaveError::Other(_)));
+    }
+}
@@ -0,0 +1,8 @@
+//! S3-compatible client for the Akave O3 storage network.
+mod adapters;
+mod error;
+mod retry;
+
+pub use adapters::*;
+pub use error::*;
+pub use retry::*;
@@ -1,5 +0,0 @@
-mod adapters;
-
-fn main() {
-    println!("Hello, world!");
-}
@@ -0,0 +1,143 @@
+use std::future::Future;
+use std::time::Duration;
+
+use rand::Rng;
+
+use crate::error::{AkaveError, ErrorClass, Result};
+
+/// How `AkaveClient` retries failed requests.
+///
+/// Attempt `n` that fails with a retryable error is followed by a delay drawn between half and
+/// all of `initial_backoff * 2^(n - 1)`, capped at `max_backoff`.
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    /// Attempts per operation, including the first; 1 disables retries
+    pub max_attempts: u32,
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+    /// Error classes worth another attempt
+    pub retryable: Vec<ErrorClass>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 3,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(5),
+            retryable: vec![ErrorClass::Throttled, ErrorClass::Transient],
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// A policy that tries every operation exactly once
+    pub fn never() -> Self {
+        Self {
+            max_attempts: 1,
+            ..Default::default()
+        }
+    }
+
+    pub fn is_retryable(&self, err: &AkaveError) -> bool {
+        self.retryable.contains(&err.class())
+    }
+
+    /// Delay before the attempt following failed attempt number `attempt`, starting at 1
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        let exponential = self
+            .initial_backoff
+            .saturating_mul(2u32.saturating_pow(attempt.saturating_sub(1)));
+        let ceiling = exponential.min(self.max_backoff);
+        let floor = ceiling / 2;
+        rand::thread_rng().gen_range(floor..=ceiling)
+    }
+
+    /// Run `operation` until it succeeds, fails with an error that is not retryable, or runs out
+    /// of attempts
+    pub async fn run<T, F, Fut>(&self, mut operation: F) -> Result<T>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T>>,
+    {
+        let mut attempt = 1;
+        loop {
+            match operation().await {
+                Err(err) if attempt < self.max_attempts && self.is_retryable(&err) => {
+                    let delay = self.backoff(attempt);
+                    tracing::warn!("Attempt {} failed, retrying in {:?}: {}", attempt, delay, err);
+                    tokio::time::sleep(delay).await;
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    #[test]
+    fn test_backoff_grows_exponentially_within_jitter() {
+        let policy = RetryPolicy {
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_millis(350),
+            ..Default::default()
+        };
+
+        for _ in 0..100 {
+            let first = policy.backoff(1);
+            assert!(first >= Duration::from_millis(50) && first <= Duration::from_millis(100));
+            let third = policy.backoff(3);
+            assert!(third >= Duration::from_millis(175) && third <= Duration::from_millis(350));
+            assert!(policy.backoff(40) <= Duration::from_millis(350));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_run_retries_only_retryable_errors() {
+        let policy = RetryPolicy {
+            max_attempts: 3,
+            initial_backoff: Duration::from_millis(1),
+            ..Default::default()
+        };
+
+        // Transient failures are retried until the attempts run out
+        let attempts = AtomicU32::new(0);
+        let result: Result<()> = policy
+            .run(|| async {
+                attempts.fetch_add(1, Ordering::SeqCst);
+                Err(AkaveError::Transient("connection reset".into()))
+            })
+            .await;
+        assert!(matches!(result, Err(AkaveError::Transient(_))));
+        assert_eq!(attempts.load(Ordering::SeqCst), 3);
+
+        // A later success ends the retries
+        let attempts = AtomicU32::new(0);
+        let result = policy
+            .run(|| async {
+                match attempts.fetch_add(1, Ordering::SeqCst) {
+                    0 => Err(AkaveError::Throttled("slow down".into())),
+                    _ => Ok("done"),
+                }
+            })
+            .await;
+        assert_eq!(result.unwrap(), "done");
+        assert_eq!(attempts.load(Ordering::SeqCst), 2);
+
+        // Errors outside the retryable classes are returned at once
+        let attempts = AtomicU32::new(0);
+        let result: Result<()> = policy
+            .run(|| async {
+                attempts.fetch_add(1, Ordering::SeqCst);
+                Err(AkaveError::AccessDenied("denied".into()))
+            })
+            .await;
+        assert!(matches!(result, Err(AkaveError::AccessDenied(_))));
+        assert_eq!(attempts.load(Ordering::SeqCst), 1);
+    }
+}
@@ -0,0 +1,171 @@
+//! Dataset access through Akave O3.
+use std::path::Path;
+
+use akave_adapter::{AkaveClient, Object};
+use anyhow::Result;
+use futures::TryStreamExt;
+
+use crate::config::AkaveConfig;
+use crate::delivery::ResultPointer;
+use crate::query::{self, Dataset};
+
+pub struct AkaveAdapter {
+    client: AkaveClient,
+    bucket: String,
+}
+
+impl AkaveAdapter {
+    pub fn new(client: AkaveClient, bucket: impl Into<String>) -> Self {
+        Self {
+            client,
+            bucket: bucket.into(),
+        }
+    }
+
+    /// Connect to the configured Akave endpoint.
+    pub async fn connect(config: &AkaveConfig) -> Self {
+        let client = AkaveClient::new(
+            &config.endpoint,
+            config.access_key.expose(),
+            config.secret_key.expose(),
+        )
+        .await;
+
+        Self::new(client, config.bucket.clone())
+    }
+
+    /// Bucket holding the vault's datasets.
+    pub fn bucket(&self) -> &str {
+        &self.bucket
+    }
+
+    /// Dataset objects in `bucket`, across all pages of the listing. Objects the query executor
+    /// cannot load are skipped.
+    pub async fn list_datasets(&self, bucket: &str) -> Result<Vec<Object>> {
+        let objects = self
+            .client
+            .list_all_objects(bucket, None)
+            .try_filter(|object| {
+                let parquet = query::is_parquet(&object.key);
+                if !parquet {
+                    tracing::info!("Skipping non-parquet object: {}", object.key);
+                }
+                std::future::ready(parquet)
+            })
+            .try_collect()
+            .await?;
+
+        Ok(objects)
+    }
+
+    /// Download the given dataset objects from `bucket` into files in `dir`, streaming each one
+    /// to disk rather than holding it in memory.
+    pub async fn fetch_datasets(
+        &self,
+        bucket: &str,
+        objects: &[Object],
+        dir: &Path,
+    ) -> Result<Vec<Dataset>> {
+        let mut datasets = Vec::with_capacity(objects.len());
+        for (index, object) in objects.iter().enumerate() {
+            // Keys may hold slashes, so the files are numbered instead.
+            let path = dir.join(format!("{}.parquet", index));
+            self.client
+                .download_file(bucket, &object.key, &path)
+                .await?;
+            datasets.push(Dataset {
+                key: object.key.clone(),
+                path,
+            });
+        }
+
+        Ok(datasets)
+    }
+
+    /// Upload an encrypted result to `bucket` under `key` and return the pointer to it.
+    /// Uploading the same result again overwrites the object with the same bytes.
+    pub async fn upload_result(
+        &self,
+        bucket: &str,
+        key: &str,
+        envelope: &str,
+    ) -> Result<ResultPointer> {
+        let envelope = envelope.as_bytes();
+        self.client
+            .put_object(bucket, key, envelope.to_vec())
+            .await?;
+        Ok(ResultPointer::new(bucket, key, envelope))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn fetches_parquet_objects_only() {
+        let mut server = mockito::Server::new_async().await;
+
+        let listing = server
+            .mock("GET", "/datasets/?list-type=2")
+            .with_status(200)
+            .with_header("content-type", "application/xml")
+            .with_body(
+                r#"<?xml version="1.0" encoding="UTF-8"?>
+<ListBucketResult xmlns="http://s3.amazonaws.com/doc/2006-03-01/">
+  <Name>datasets</Name>
+  <Prefix></Prefix>
+  <KeyCount>2</KeyCount>
+  <MaxKeys>1000</MaxKeys>
+  <IsTruncated>false</IsTruncated>
+  <Contents>
+    <Key>names-and-cities.parquet</Key>
+    <LastModified>2025-06-01T00:00:00.000Z</LastModified>
+    <ETag>"abc"</ETag>
+    <Size>4</Size>
+    <StorageClass>STANDARD</StorageClass>
+  </Contents>
+  <Contents>
+    <Key>README.md</Key>
+    <LastModified>2025-06-01T00:00:00.000Z</LastModified>
+    <ETag>"def"</ETag>
+    <Size>2</Size>
+    <StorageClass>STANDARD</StorageClass>
+  </Contents>
+</ListBucketResult>"#,
+            )
+            .create_async()
+            .await;
+        let parquet = server
+            .mock("GET", "/datasets/names-and-cities.parquet?x-id=GetObject")
+            .with_status(200)
+            .with_body("PAR1")
+            .create_async()
+            .await;
+        let readme = server
+            .mock("GET", "/datasets/README.md?x-id=GetObject")
+            .expect(0)
+            .create_async()
+            .await;
+
+        let client = AkaveClient::new(&server.url(), "access", "secret").await;
+        let adapter = AkaveAdapter::new(client, "datasets");
+
+        let objects = adapter.list_datasets(adapter.bucket()).await.unwrap();
+        assert_eq!(objects.len(), 1);
+        assert_eq!(objects[0].size, 4);
+        let dir = tempfile::tempdir().unwrap();
+        let datasets = adapter
+            .fetch_datasets(adapter.bucket(), &objects, dir.path())
+            .await
+            .unwrap();
+        assert_eq!(datasets.len(), 1);
+        assert_eq!(datasets[0].key, "names-and-cities.parquet");
+        assert!(datasets[0].path.starts_with(dir.path()));
+        assert_eq!(std::fs::read(&datasets[0].path).unwrap(), b"PAR1");
+
+        listing.assert_async().await;
+        parquet.assert_async().await;
+        readme.assert_async().await;
+    }
+}
@@ -0,0 +1,334 @@
+//! Minimum group size (k-anonymity) of query results.
+//!
+//! The outermost `SELECT` of an aggregate query is rewritten to also return `COUNT(*)`, the
+//! number of input rows behind each output group, in a hidden trailing column. Groups backed by
+//! fewer rows than the minimum of the datasets the query reads are dropped from the result and
+//! counted as suppressed. A result that is not grouped this way, because the query does not
+//! aggregate or its outermost part is not a plain `SELECT`, releases individual rows and is
+//! suppressed entirely when the minimum is above one.
+//!
+//! Group sizes count the rows reaching the aggregation, so a join that repeats rows of a dataset
+//! inflates them.
+use std::collections::HashSet;
+use std::ops::ControlFlow;
+
+use anyhow::{Result, anyhow};
+use sqlparser::ast::{
+    Expr, Function, GroupByExpr, Ident, ObjectName, Query, SelectItem, SetExpr, Statement, Visit,
+    Visitor,
+};
+use sqlparser::dialect::DuckDbDialect;
+use sqlparser::parser::Parser;
+
+use crate::policy::last_part;
+use crate::query::{QueryResult, Value};
+
+/// Name of the hidden group size column.
+const GROUP_SIZE_COLUMN: &str = "__group_size";
+
+/// Functions that make a `SELECT` without `GROUP BY` aggregate all its rows into one group.
+const AGGREGATES: &[&str] = &[
+    "any_value",
+    "approx_count_distinct",
+    "arg_max",
+    "arg_min",
+    "array_agg",
+    "avg",
+    "bit_and",
+    "bit_or",
+    "bit_xor",
+    "bool_and",
+    "bool_or",
+    "count",
+    "first",
+    "histogram",
+    "last",
+    "list",
+    "max",
+    "mean",
+    "median",
+    "min",
+    "mode",
+    "product",
+    "quantile",
+    "quantile_cont",
+    "quantile_disc",
+    "stddev",
+    "stddev_pop",
+    "stddev_samp",
+    "string_agg",
+    "sum",
+    "var_pop",
+    "var_samp",
+    "variance",
+];
+
+/// A query prepared for running under a minimum group size.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GroupedQuery {
+    /// The query to run.
+    pub sql: String,
+    /// Whether `sql` returns the size of each group in a trailing hidden column.
+    pub grouped: bool,
+    /// Tables the query reads, in lower case and without its CTEs.
+    pub tables: Vec<String>,
+}
+
+/// Rewrite an aggregate query to return its group sizes.
+pub fn prepare(sql: &str) -> Result<GroupedQuery> {
+    let mut statements = Parser::parse_sql(&DuckDbDialect {}, sql)?;
+    let [Statement::Query(query)] = statements.as_mut_slice() else {
+        return Err(anyhow!("expected a single query"));
+    };
+
+    let mut relations = Relations::default();
+    let _ = query.visit(&mut relations);
+    let tables = relations.tables();
+
+    let grouped = match query.body.as_mut() {
+        SetExpr::Select(select) if is_grouped(&select.group_by, &select.projection) => {
+            let count = Parser::new(&DuckDbDialect {})
+                .try_with_sql("COUNT(*)")?
+                .parse_expr()?;
+            select.projection.push(SelectItem::ExprWithAlias {
+                expr: count,
+                alias: Ident::with_quote('"', GROUP_SIZE_COLUMN),
+            });
+            true
+        }
+        _ => false,
+    };
+
+    Ok(GroupedQuery {
+        sql: if grouped {
+            query.to_string()
+        } else {
+            sql.to_owned()
+        },
+        grouped,
+        tables,
+    })
+}
+
+/// Drop the groups of a result backed by fewer than `min_group_size` rows, along with the hidden
+/// group size column, and record how many were dropped.
+pub fn suppress(result: &mut QueryResult, query: &GroupedQuery, min_group_size: u64) -> Result<()> {
+    let before = result.rows.len();
+
+    if query.grouped {
+        if result.columns.pop().as_deref() != Some(GROUP_SIZE_COLUMN) {
+            return Err(anyhow!("query result is missing its group sizes"));
+        }
+        for mut row in std::mem::take(&mut result.rows) {
+            let size = match row.pop() {
+                Some(Value::Integer(size)) => size,
+                size => return Err(anyhow!("invalid group size {:?}", size)),
+            };
+            if u64::try_from(size).is_ok_and(|size| size >= min_group_size) {
+                result.rows.push(row);
+            }
+        }
+    } else if min_group_size > 1 {
+        result.rows.clear();
+    }
+
+    result.suppressed_groups = (before - result.rows.len()) as u64;
+    Ok(())
+}
+
+/// Whether a `SELECT` folds its input rows into groups.
+pub fn is_grouped(group_by: &GroupByExpr, projection: &[SelectItem]) -> bool {
+    match group_by {
+        GroupByExpr::All(_) => true,
+        GroupByExpr::Expressions(exprs, _) if !exprs.is_empty() => true,
+        GroupByExpr::Expressions(..) => projection.iter().any(contains_aggregate),
+    }
+}
+
+/// Whether a projection item calls an aggregate, outside window functions and subqueries.
+pub fn contains_aggregate(item: &SelectItem) -> bool {
+    let mut aggregates = Aggregates::default();
+    let _ = item.visit(&mut aggregates);
+    aggregates.found
+}
+
+/// Finds aggregate calls outside window functions and subqueries.
+#[derive(Default)]
+struct Aggregates {
+    found: bool,
+    /// Aggregates of a subquery do not group the outer rows.
+    query_depth: usize,
+}
+
+impl Visitor for Aggregates {
+    type Break = ();
+
+    fn pre_visit_query(&mut self, _query: &Query) -> ControlFlow<()> {
+        self.query_depth += 1;
+        ControlFlow::Continue(())
+    }
+
+    fn post_visit_query(&mut self, _query: &Query) -> ControlFlow<()> {
+        self.query_depth -= 1;
+        ControlFlow::Continue(())
+    }
+
+    fn pre_visit_expr(&mut self, expr: &Expr) -> ControlFlow<()> {
+        if let Expr::Function(Function {
+            name, over: None, ..
+        }) = expr
+            && self.query_depth == 0
+            && AGGREGATES.contains(&last_part(name).as_str())
+        {
+            self.found = true;
+        }
+        ControlFlow::Continue(())
+    }
+}
+
+/// Collects the tables a query reads and the CTEs it defines.
+#[derive(Default)]
+struct Relations {
+    relations: Vec<String>,
+    ctes: HashSet<String>,
+}
+
+impl Relations {
+    fn tables(self) -> Vec<String> {
+        let mut tables: Vec<String> = self
+            .relations
+            .into_iter()
+            .filter(|relation| !self.ctes.contains(relation))
+            .collect();
+        tables.sort();
+        tables.dedup();
+        tables
+    }
+}
+
+impl Visitor for Relations {
+    type Break = ();
+
+    fn pre_visit_query(&mut self, query: &Query) -> ControlFlow<()> {
+        if let Some(with) = &query.with {
+            self.ctes.extend(
+                with.cte_tables
+                    .iter()
+                    .map(|cte| cte.alias.name.value.to_lowercase()),
+            );
+        }
+        ControlFlow::Continue(())
+    }
+
+    fn pre_visit_relation(&mut self, relation: &ObjectName) -> ControlFlow<()> {
+        self.relations.push(last_part(relation));
+        ControlFlow::Continue(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn result(rows: &[(&str, i64)], grouped: bool) -> QueryResult {
+        let mut columns = vec!["city".to_owned()];
+        if grouped {
+            columns.push(GROUP_SIZE_COLUMN.to_owned());
+        }
+        QueryResult {
+            columns,
+            rows: rows
+                .iter()
+                .map(|(city, size)| {
+                    let mut row = vec![Value::Text((*city).to_owned())];
+                    if grouped {
+                        row.push(Value::Integer(*size));
+                    }
+                    row
+                })
+                .collect(),
+            ..QueryResult::default()
+        }
+    }
+
+    #[test]
+    fn counts_the_rows_of_aggregate_queries() {
+        let query =
+            prepare("SELECT city, COUNT(*) AS n FROM Names_And_Cities GROUP BY city ORDER BY 2")
+                .unwrap();
+        assert_eq!(
+            query,
+            GroupedQuery {
+                sql: "SELECT city, COUNT(*) AS n, COUNT(*) AS \"__group_size\" \
+                      FROM Names_And_Cities GROUP BY city ORDER BY 2"
+                    .into(),
+                grouped: true,
+                tables: vec!["names_and_cities".into()],
+            }
+        );
+
+        for sql in [
+            "SELECT AVG(age) FROM people",
+            "SELECT city, MAX(age) FROM people GROUP BY ALL",
+            "SELECT COUNT(DISTINCT city) + 1 FROM people WHERE age > (SELECT 1)",
+            "SELECT (SELECT MAX(age) FROM people) - AVG(age) FROM people",
+            "WITH p AS (SELECT * FROM people) SELECT city FROM p JOIN visits USING (city) GROUP BY city",
+        ] {
+            assert!(prepare(sql).unwrap().grouped, "{}", sql);
+        }
+        assert_eq!(
+            prepare("WITH p AS (SELECT * FROM people) SELECT city FROM p JOIN Visits USING (city)")
+                .unwrap()
+                .tables,
+            vec!["people", "visits"]
+        );
+    }
+
+    #[test]
+    fn treats_other_queries_as_row_level() {
+        for sql in [
+            "SELECT city FROM people",
+            "SELECT city, COUNT(*) OVER () FROM people",
+            "SELECT city, (SELECT COUNT(*) FROM visits) FROM people",
+            "SELECT city, n FROM (SELECT city, COUNT(*) AS n FROM people GROUP BY city)",
+            "SELECT COUNT(*) FROM people UNION ALL SELECT COUNT(*) FROM visits",
+        ] {
+            let query = prepare(sql).unwrap();
+            assert!(!query.grouped, "{}", sql);
+            assert_eq!(query.sql, sql);
+        }
+
+        assert!(prepare("SELECT 1; SELECT 2").is_err());
+        assert!(prepare("DELETE FROM people").is_err());
+    }
+
+    #[test]
+    fn suppresses_small_groups() {
+        let query = prepare("SELECT city, COUNT(*) FROM people GROUP BY city").unwrap();
+        let mut grouped = result(&[("Prague", 12), ("Brno", 4), ("Ostrava", 5)], true);
+
+        suppress(&mut grouped, &query, 5).unwrap();
+        assert_eq!(grouped, {
+            let mut expected = result(&[("Prague", 0), ("Ostrava", 0)], false);
+            expected.suppressed_groups = 1;
+            expected
+        });
+
+        let mut missing = result(&[("Prague", 12)], false);
+        assert!(suppress(&mut missing, &query, 5).is_err());
+    }
+
+    #[test]
+    fn suppresses_row_level_results() {
+        let query = prepare("SELECT city FROM people").unwrap();
+
+        let mut rows = result(&[("Prague", 0), ("Brno", 0)], false);
+        suppress(&mut rows, &query, 1).unwrap();
+        assert_eq!(rows.rows.len(), 2);
+        assert_eq!(rows.suppressed_groups, 0);
+
+        suppress(&mut rows, &query, 2).unwrap();
+        assert!(rows.rows.is_empty());
+        assert_eq!(rows.suppressed_groups, 2);
+    }
+}
@@ -0,0 +1,455 @@
+//! Signed log records and the hash-chained log the collector keeps of them.
+//!
+//! Every record the engine ships is signed with the app's secp256k1 signer, the same key it sends
+//! transactions with. The signature covers the exact JSON bytes of a [`Payload`], which carries
+//! the record together with a random session ID chosen at startup and a sequence number, so a
+//! collector can tell forged records from genuine ones and replayed records from new ones.
+//!
+//! The collector appends accepted records to a file of JSON lines in which every entry commits to
+//! the hash of the previous one, so the log can be audited for removed, reordered or altered
+//! entries with [`Chain::open`].
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result, anyhow};
+use k256::ecdsa::signature::{DigestSigner, DigestVerifier};
+use k256::ecdsa::{Signature, SigningKey, VerifyingKey};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256, Sha512_256};
+
+/// Domain separation context of log record signatures.
+pub const SIGNATURE_CONTEXT: &[u8] = b"vault-engine: log record v1";
+
+/// A log event as sent to the collector.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Record {
+    /// RFC 3339 time the event was emitted at.
+    pub timestamp: String,
+    pub level: String,
+    pub target: String,
+    pub message: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub proposal_id: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub round: Option<u64>,
+}
+
+/// What a signature covers: a record and its position in the engine's stream of records.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Payload {
+    /// Hex-encoded random ID of the engine run that produced the record.
+    pub session: String,
+    /// Position of the record within its session, starting at 0.
+    pub seq: u64,
+    #[serde(flatten)]
+    pub record: Record,
+}
+
+/// A record as shipped: its payload and the signature over exactly these bytes.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SignedRecord {
+    /// JSON encoding of a [`Payload`].
+    pub payload: String,
+    /// Hex-encoded DER signature.
+    pub signature: String,
+}
+
+/// Body of a POST to the collector.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Batch {
+    pub records: Vec<SignedRecord>,
+}
+
+/// Response of the collector to an accepted batch.
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Receipt {
+    pub accepted: usize,
+    /// Records that had been received before and were not stored again.
+    pub replayed: usize,
+}
+
+/// A batch refused because one of its records is not signed by a registered key.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Rejected {
+    pub index: usize,
+}
+
+impl fmt::Display for Rejected {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "record {} is not signed by a registered key", self.index)
+    }
+}
+
+impl std::error::Error for Rejected {}
+
+/// Signs log records. Implementations sign the SHA-512/256 digest of the context followed by the
+/// message and return a DER-encoded secp256k1 signature, as the SDK's secp256k1 signer does.
+pub trait RecordSigner: Send + Sync {
+    fn sign(&self, context: &[u8], message: &[u8]) -> Result<Vec<u8>>;
+}
+
+impl RecordSigner for SigningKey {
+    fn sign(&self, context: &[u8], message: &[u8]) -> Result<Vec<u8>> {
+        let signature: Signature = self.sign_digest(digest(context, message));
+        Ok(signature.to_der().as_bytes().to_vec())
+    }
+}
+
+fn digest(context: &[u8], message: &[u8]) -> Sha512_256 {
+    Sha512_256::new()
+        .chain_update(context)
+        .chain_update(message)
+}
+
+impl SignedRecord {
+    pub fn sign(payload: &Payload, signer: &dyn RecordSigner) -> Result<Self> {
+        let payload = serde_json::to_string(payload)?;
+        let signature = signer.sign(SIGNATURE_CONTEXT, payload.as_bytes())?;
+        Ok(Self {
+            payload,
+            signature: hex::encode(signature),
+        })
+    }
+
+    /// Check the signature against `key` and decode the payload.
+    pub fn verify(&self, key: &VerifyingKey) -> Result<Payload> {
+        let signature = hex::decode(&self.signature)
+            .ok()
+            .and_then(|der| Signature::from_der(&der).ok())
+            .ok_or_else(|| anyhow!("malformed signature"))?;
+        key.verify_digest(
+            digest(SIGNATURE_CONTEXT, self.payload.as_bytes()),
+            &signature,
+        )
+        .map_err(|_| anyhow!("invalid signature"))?;
+
+        serde_json::from_str(&self.payload).context("malformed payload")
+    }
+}
+
+/// Parse a hex-encoded SEC1 secp256k1 public key.
+pub fn parse_public_key(key: &str) -> Result<VerifyingKey> {
+    let bytes = hex::decode(key.trim_start_matches("0x"))
+        .map_err(|_| anyhow!("public key {:?} is not hex", key))?;
+    VerifyingKey::from_sec1_bytes(&bytes)
+        .map_err(|_| anyhow!("{:?} is not a secp256k1 public key", key))
+}
+
+/// An entry of the collector's log.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChainEntry {
+    pub index: u64,
+    /// Hex-encoded hash of the previous entry, zero for the first.
+    pub prev_hash: String,
+    /// Hex-encoded compressed key the record was signed with.
+    pub public_key: String,
+    pub payload: String,
+    pub signature: String,
+    /// Hex-encoded SHA-256 of the previous hash and the JSON encoding of this entry without it.
+    pub hash: String,
+}
+
+/// The part of an entry its hash covers.
+#[derive(Serialize)]
+struct ChainEntryBody<'a> {
+    index: u64,
+    prev_hash: &'a str,
+    public_key: &'a str,
+    payload: &'a str,
+    signature: &'a str,
+}
+
+impl ChainEntry {
+    fn compute_hash(&self) -> Result<String> {
+        let prev_hash = hex::decode(&self.prev_hash)?;
+        let body = serde_json::to_vec(&ChainEntryBody {
+            index: self.index,
+            prev_hash: &self.prev_hash,
+            public_key: &self.public_key,
+            payload: &self.payload,
+            signature: &self.signature,
+        })?;
+        Ok(hex::encode(
+            Sha256::new()
+                .chain_update(prev_hash)
+                .chain_update(body)
+                .finalize(),
+        ))
+    }
+}
+
+/// The collector's append-only, hash-chained log.
+pub struct Chain {
+    path: PathBuf,
+    file: File,
+    len: u64,
+    head: String,
+    /// Highest sequence number seen per session.
+    sessions: BTreeMap<String, u64>,
+}
+
+impl Chain {
+    /// Open the log at `path`, creating it if needed. Every entry is checked: its hash, its link
+    /// to the previous entry and its signature. Opening fails on the first entry that does not
+    /// check out, so a successful open is an audit of the whole log.
+    pub fn open(path: impl Into<PathBuf>) -> Result<Self> {
+        let path = path.into();
+        if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            fs::create_dir_all(dir)
+                .with_context(|| format!("failed to create {}", dir.display()))?;
+        }
+
+        let mut len = 0;
+        let mut head = hex::encode([0u8; 32]);
+        let mut sessions = BTreeMap::new();
+        if path.exists() {
+            let reader = BufReader::new(File::open(&path)?);
+            for (number, line) in reader.lines().enumerate() {
+                let line = line?;
+                let entry: ChainEntry = serde_json::from_str(&line)
+                    .with_context(|| format!("malformed entry on line {}", number + 1))?;
+                let payload = check_entry(&entry, len, &head)
+                    .with_context(|| format!("entry on line {} does not verify", number + 1))?;
+                sessions.insert(payload.session, payload.seq);
+                len += 1;
+                head = entry.hash;
+            }
+        }
+
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        Ok(Self {
+            path,
+            file,
+            len,
+            head,
+            sessions,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Number of entries in the log.
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Hash of the last entry.
+    pub fn head(&self) -> &str {
+        &self.head
+    }
+
+    /// Verify a batch against `keys` and append its new records. Fails without writing anything
+    /// if any record is forged or malformed; records at or below the last sequence number of
+    /// their session are replays and are skipped.
+    pub fn append(&mut self, records: &[SignedRecord], keys: &[VerifyingKey]) -> Result<Receipt> {
+        let mut verified = Vec::with_capacity(records.len());
+        for (index, record) in records.iter().enumerate() {
+            let (key, payload) = keys
+                .iter()
+                .find_map(|key| record.verify(key).ok().map(|payload| (key, payload)))
+                .ok_or(Rejected { index })?;
+            verified.push((key, payload, record));
+        }
+
+        let mut sessions = self.sessions.clone();
+        let mut lines = String::new();
+        let mut entries = Vec::new();
+        let mut replayed = 0;
+        let mut head = self.head.clone();
+        for (key, payload, record) in verified {
+            if sessions
+                .get(&payload.session)
+                .is_some_and(|&last| payload.seq <= last)
+            {
+                replayed += 1;
+                continue;
+            }
+            sessions.insert(payload.session, payload.seq);
+
+            let mut entry = ChainEntry {
+                index: self.len + entries.len() as u64,
+                prev_hash: head,
+                public_key: hex::encode(key.to_encoded_point(true).as_bytes()),
+                payload: record.payload.clone(),
+                signature: record.signature.clone(),
+                hash: String::new(),
+            };
+            entry.hash = entry.compute_hash()?;
+            head = entry.hash.clone();
+            lines.push_str(&serde_json::to_string(&entry)?);
+            lines.push('\n');
+            entries.push(entry);
+        }
+
+        if !entries.is_empty() {
+            self.file.write_all(lines.as_bytes())?;
+            self.file.sync_data()?;
+        }
+
+        self.len += entries.len() as u64;
+        self.head = head;
+        self.sessions = sessions;
+        Ok(Receipt {
+            accepted: entries.len(),
+            replayed,
+        })
+    }
+}
+
+/// Check an entry read back from the log and return its payload.
+fn check_entry(entry: &ChainEntry, index: u64, prev_hash: &str) -> Result<Payload> {
+    if entry.index != index {
+        return Err(anyhow!("expected index {}, found {}", index, entry.index));
+    }
+    if entry.prev_hash != prev_hash {
+        return Err(anyhow!("does not link to the previous entry"));
+    }
+    if entry.compute_hash()? != entry.hash {
+        return Err(anyhow!("hash mismatch"));
+    }
+
+    let record = SignedRecord {
+        payload: entry.payload.clone(),
+        signature: entry.signature.clone(),
+    };
+    record.verify(&parse_public_key(&entry.public_key)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::OsRng;
+
+    fn payload(session: &str, seq: u64, message: &str) -> Payload {
+        Payload {
+            session: session.into(),
+            seq,
+            record: Record {
+                timestamp: "2025-06-01T00:00:00.000Z".into(),
+                level: "INFO".into(),
+                target: "engine".into(),
+                message: message.into(),
+                proposal_id: Some(3),
+                round: None,
+            },
+        }
+    }
+
+    #[test]
+    fn verifies_signatures() {
+        let key = SigningKey::random(&mut OsRng);
+        let other = SigningKey::random(&mut OsRng);
+
+        let signed = SignedRecord::sign(&payload("aa", 0, "hello"), &key).unwrap();
+        assert_eq!(
+            signed.verify(key.verifying_key()).unwrap(),
+            payload("aa", 0, "hello")
+        );
+        assert!(signed.verify(other.verifying_key()).is_err());
+
+        let mut forged = signed.clone();
+        forged.payload = forged.payload.replace("hello", "hijacked");
+        assert!(forged.verify(key.verifying_key()).is_err());
+
+        let key_hex = hex::encode(key.verifying_key().to_encoded_point(true).as_bytes());
+        assert_eq!(&parse_public_key(&key_hex).unwrap(), key.verifying_key());
+        assert!(parse_public_key("0x1234").is_err());
+    }
+
+    #[test]
+    fn appends_only_new_genuine_records() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("collector").join("log.jsonl");
+        let key = SigningKey::random(&mut OsRng);
+        let keys = [*key.verifying_key()];
+        let sign = |seq, message| SignedRecord::sign(&payload("aa", seq, message), &key).unwrap();
+
+        let mut chain = Chain::open(&path).unwrap();
+        let receipt = chain
+            .append(&[sign(0, "one"), sign(1, "two")], &keys)
+            .unwrap();
+        assert_eq!(
+            receipt,
+            Receipt {
+                accepted: 2,
+                replayed: 0
+            }
+        );
+
+        // A batch with a forged record is refused as a whole.
+        let forged =
+            SignedRecord::sign(&payload("aa", 2, "forged"), &SigningKey::random(&mut OsRng))
+                .unwrap();
+        let err = chain
+            .append(&[sign(2, "three"), forged], &keys)
+            .unwrap_err();
+        assert_eq!(err.downcast_ref(), Some(&Rejected { index: 1 }));
+        assert_eq!(chain.len(), 2);
+
+        // Replays are skipped, also across a restart of the collector.
+        let head = chain.head().to_owned();
+        drop(chain);
+        let mut chain = Chain::open(&path).unwrap();
+        assert_eq!(chain.head(), head);
+        let receipt = chain
+            .append(&[sign(1, "two"), sign(2, "three")], &keys)
+            .unwrap();
+        assert_eq!(
+            receipt,
+            Receipt {
+                accepted: 1,
+                replayed: 1
+            }
+        );
+        assert_eq!(chain.len(), 3);
+    }
+
+    #[test]
+    fn detects_tampering() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("log.jsonl");
+        let key = SigningKey::random(&mut OsRng);
+        let records: Vec<_> = (0..3)
+            .map(|seq| SignedRecord::sign(&payload("bb", seq, "entry"), &key).unwrap())
+            .collect();
+        Chain::open(&path)
+            .unwrap()
+            .append(&records, &[*key.verifying_key()])
+            .unwrap();
+
+        let contents = fs::read_to_string(&path).unwrap();
+        let lines: Vec<&str> = contents.lines().collect();
+
+        // Dropping an entry breaks the chain.
+        fs::write(&path, format!("{}\n{}\n", lines[0], lines[2])).unwrap();
+        assert!(Chain::open(&path).is_err());
+
+        // So does altering one, even with a valid hash.
+        let mut entry: ChainEntry = serde_json::from_str(lines[1]).unwrap();
+        entry.payload = entry.payload.replace("entry", "edited");
+        entry.hash = entry.compute_hash().unwrap();
+        fs::write(
+            &path,
+            format!("{}\n{}\n", lines[0], serde_json::to_string(&entry).unwrap()),
+        )
+        .unwrap();
+        let err = Chain::open(&path).err().unwrap();
+        assert!(
+            format!("{:#}", err).contains("invalid signature"),
+            "{:#}",
+            err
+        );
+
+        fs::write(&path, &contents).unwrap();
+        assert_eq!(Chain::open(&path).unwrap().len(), 3);
+    }
+}
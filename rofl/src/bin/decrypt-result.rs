@@ -0,0 +1,55 @@
+//! Decrypt a query result envelope offline with the requester's secret key.
+//!
+//! Usage: `decrypt-result <secret key> [result]`. The secret key is given as hex or base64 and
+//! the result, as stored by the vault, is read from stdin when it is not passed as an argument.
+//! A result that points at an envelope uploaded to Akave is downloaded with the credentials in
+//! `AKAVE_ENDPOINT`, `AKAVE_ACCESS_KEY` and `AKAVE_SECRET_KEY` and checked against the pointer's
+//! size and SHA-256 before it is decrypted.
+use std::env;
+use std::io::{self, Read, Write};
+
+use akave_adapter::AkaveClient;
+use anyhow::{Context, Result, anyhow};
+
+use engine::delivery::ResultPointer;
+use engine::encryption;
+
+fn main() -> Result<()> {
+    let mut args = env::args().skip(1);
+    let secret_key = args
+        .next()
+        .ok_or_else(|| anyhow!("usage: decrypt-result <secret key> [result]"))?;
+    let result = match args.next() {
+        Some(result) => result,
+        None => {
+            let mut result = String::new();
+            io::stdin().read_to_string(&mut result)?;
+            result
+        }
+    };
+
+    let envelope = match ResultPointer::parse(&result)? {
+        Some(pointer) => tokio::runtime::Runtime::new()?.block_on(download(&pointer))?,
+        None => result,
+    };
+
+    let plaintext = encryption::decrypt(&encryption::decode_key(&secret_key)?, &envelope)?;
+    io::stdout().write_all(&plaintext)?;
+
+    Ok(())
+}
+
+/// Download the envelope a pointer refers to and check it.
+async fn download(pointer: &ResultPointer) -> Result<String> {
+    let var = |name: &str| env::var(name).with_context(|| format!("{} must be set", name));
+    let client = AkaveClient::new(
+        &var("AKAVE_ENDPOINT")?,
+        &var("AKAVE_ACCESS_KEY")?,
+        &var("AKAVE_SECRET_KEY")?,
+    )
+    .await;
+
+    let object = client.get_object(&pointer.bucket, &pointer.key).await?;
+    pointer.verify(&object)?;
+    Ok(String::from_utf8(object)?)
+}
@@ -0,0 +1,133 @@
+//! Collect the engine's signed log records into a hash-chained, append-only log.
+//!
+//! Usage:
+//!
+//! - `log-collector serve <log file> <public key>...` accepts batches on `POST /logs`, on the
+//!   address in `LOG_COLLECTOR_LISTEN` (default `0.0.0.0:5560`). Records must be signed by one of
+//!   the given hex-encoded secp256k1 keys, the keys of the app's registered instances. Batches
+//!   with a forged record are refused; replayed records are acknowledged but not stored again.
+//! - `log-collector verify <log file>` audits an existing log and prints its records.
+use std::env;
+use std::sync::{Arc, Mutex};
+
+use anyhow::{Context, Result, anyhow};
+use axum::extract::State;
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::routing::post;
+use axum::{Json, Router};
+use k256::ecdsa::VerifyingKey;
+
+use engine::audit_log::{self, Batch, Chain, ChainEntry, Payload, Rejected};
+
+const DEFAULT_LISTEN: &str = "0.0.0.0:5560";
+const USAGE: &str =
+    "usage: log-collector serve <log file> <public key>...\n       log-collector verify <log file>";
+
+struct Collector {
+    chain: Mutex<Chain>,
+    keys: Vec<VerifyingKey>,
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let args: Vec<String> = env::args().skip(1).collect();
+    match args
+        .iter()
+        .map(String::as_str)
+        .collect::<Vec<_>>()
+        .as_slice()
+    {
+        ["serve", path, keys @ ..] if !keys.is_empty() => {
+            let keys = keys
+                .iter()
+                .map(|key| audit_log::parse_public_key(key))
+                .collect::<Result<Vec<_>>>()?;
+            serve(path, keys).await
+        }
+        ["verify", path] => verify(path),
+        _ => Err(anyhow!(USAGE)),
+    }
+}
+
+async fn serve(path: &str, keys: Vec<VerifyingKey>) -> Result<()> {
+    let chain = Chain::open(path).with_context(|| format!("failed to open log {}", path))?;
+    println!(
+        "Appending to {} ({} entries, head {})",
+        chain.path().display(),
+        chain.len(),
+        chain.head()
+    );
+
+    let collector = Arc::new(Collector {
+        chain: Mutex::new(chain),
+        keys,
+    });
+    let app = Router::new()
+        .route("/logs", post(receive_logs))
+        .with_state(collector);
+
+    let listen = env::var("LOG_COLLECTOR_LISTEN").unwrap_or_else(|_| DEFAULT_LISTEN.to_owned());
+    let listener = tokio::net::TcpListener::bind(&listen).await?;
+    println!("Listening on {}", listen);
+    axum::serve(listener, app).await?;
+
+    Ok(())
+}
+
+async fn receive_logs(
+    State(collector): State<Arc<Collector>>,
+    Json(batch): Json<Batch>,
+) -> Response {
+    let mut chain = collector.chain.lock().expect("log lock poisoned");
+    match chain.append(&batch.records, &collector.keys) {
+        Ok(receipt) => {
+            if receipt.replayed > 0 {
+                println!("Ignored {} replayed record(s)", receipt.replayed);
+            }
+            (StatusCode::OK, Json(receipt)).into_response()
+        }
+        Err(err) if err.is::<Rejected>() => {
+            println!("Refused batch: {}", err);
+            (StatusCode::FORBIDDEN, format!("{}\n", err)).into_response()
+        }
+        Err(err) => {
+            println!("Failed to append batch: {:?}", err);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "failed to store records\n",
+            )
+                .into_response()
+        }
+    }
+}
+
+/// Audit the log at `path` and print its records.
+fn verify(path: &str) -> Result<()> {
+    let chain = Chain::open(path).with_context(|| format!("log {} failed the audit", path))?;
+    for line in std::fs::read_to_string(path)?.lines() {
+        let entry: ChainEntry = serde_json::from_str(line)?;
+        let payload: Payload = serde_json::from_str(&entry.payload)?;
+        let record = payload.record;
+        println!(
+            "{} {} {:>5} {}:{} {}{}{}",
+            entry.index,
+            record.timestamp,
+            record.level,
+            payload.session,
+            payload.seq,
+            record
+                .proposal_id
+                .map(|id| format!("proposal={} ", id))
+                .unwrap_or_default(),
+            record
+                .round
+                .map(|round| format!("round={} ", round))
+                .unwrap_or_default(),
+            record.message
+        );
+    }
+    println!("{} entries verified, head {}", chain.len(), chain.head());
+
+    Ok(())
+}
@@ -0,0 +1,241 @@
+//! Privacy budget of the datasets: how much epsilon differentially private results have spent.
+//!
+//! Like the journal, the ledger is an append-only file of JSON lines on the persistent storage of
+//! the ROFL app, one per charged proposal with its epsilon and, for Gaussian noise, its delta,
+//! flushed before the result is released. A proposal is
+//! charged once, so re-running it after a restart does not spend its epsilon again; the noisy
+//! result of the first run was never released.
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result, anyhow};
+use serde::{Deserialize, Serialize};
+
+/// Slack for rounding when comparing sums of epsilon against a budget.
+const TOLERANCE: f64 = 1e-9;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct Charge {
+    proposal_id: u64,
+    datasets: Vec<String>,
+    epsilon: f64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    delta: Option<f64>,
+}
+
+pub struct PrivacyBudget {
+    path: PathBuf,
+    file: File,
+    charges: BTreeMap<u64, Charge>,
+}
+
+impl PrivacyBudget {
+    /// Open the ledger at `path`, creating it if needed, and replay it. A torn final line left by
+    /// a crash during an append is discarded.
+    pub fn open(path: impl Into<PathBuf>) -> Result<Self> {
+        let path = path.into();
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)
+                .with_context(|| format!("failed to create {}", dir.display()))?;
+        }
+
+        let mut charges = BTreeMap::new();
+        if path.exists() {
+            let reader = BufReader::new(File::open(&path)?);
+            let lines = reader.lines().collect::<std::io::Result<Vec<_>>>()?;
+            let last = lines.len().saturating_sub(1);
+            for (index, line) in lines.iter().enumerate() {
+                if line.trim().is_empty() {
+                    continue;
+                }
+                match serde_json::from_str::<Charge>(line) {
+                    Ok(charge) => {
+                        charges.insert(charge.proposal_id, charge);
+                    }
+                    Err(_) if index == last => {
+                        tracing::warn!("Discarding torn budget record in {}", path.display());
+                    }
+                    Err(err) => {
+                        return Err(anyhow!(
+                            "corrupt budget record on line {} of {}: {}",
+                            index + 1,
+                            path.display(),
+                            err
+                        ));
+                    }
+                }
+            }
+        }
+
+        let file = rewrite(&path, &charges)?;
+        Ok(Self {
+            path,
+            file,
+            charges,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Epsilon a dataset has spent.
+    pub fn spent(&self, dataset: &str) -> f64 {
+        self.charges
+            .values()
+            .filter(|charge| charge.datasets.iter().any(|d| d == dataset))
+            .map(|charge| charge.epsilon)
+            .sum()
+    }
+
+    /// Fail if a dataset does not have `epsilon` left of its total budget.
+    pub fn check(
+        &self,
+        datasets: &[String],
+        epsilon: f64,
+        budget: impl Fn(&str) -> f64,
+    ) -> Result<()> {
+        for dataset in datasets {
+            let spent = self.spent(dataset);
+            let budget = budget(dataset);
+            if spent + epsilon > budget + TOLERANCE {
+                return Err(anyhow!(
+                    "privacy budget of {} exceeded: {} of {} spent, the query needs {}",
+                    dataset,
+                    spent,
+                    budget,
+                    epsilon
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Charge `epsilon` to every dataset a proposal read, given the total budget of each, and
+    /// record the `delta` spent with it. Fails without charging anything if a dataset does not
+    /// have that much epsilon left. A proposal that was already charged is not charged again.
+    pub fn charge(
+        &mut self,
+        proposal_id: u64,
+        datasets: &[String],
+        epsilon: f64,
+        delta: Option<f64>,
+        budget: impl Fn(&str) -> f64,
+    ) -> Result<()> {
+        if self.charges.contains_key(&proposal_id) {
+            return Ok(());
+        }
+        self.check(datasets, epsilon, budget)?;
+
+        let charge = Charge {
+            proposal_id,
+            datasets: datasets.to_vec(),
+            epsilon,
+            delta,
+        };
+        let mut line = serde_json::to_string(&charge)?;
+        line.push('\n');
+        self.file.write_all(line.as_bytes())?;
+        self.file.sync_data()?;
+
+        self.charges.insert(proposal_id, charge);
+        Ok(())
+    }
+}
+
+/// Atomically replace the ledger with the given charges and return it opened for appending.
+fn rewrite(path: &Path, charges: &BTreeMap<u64, Charge>) -> Result<File> {
+    let tmp = path.with_extension("tmp");
+    let mut file = File::create(&tmp)?;
+    for charge in charges.values() {
+        let mut line = serde_json::to_string(charge)?;
+        line.push('\n');
+        file.write_all(line.as_bytes())?;
+    }
+    file.sync_all()?;
+    fs::rename(&tmp, path)?;
+
+    // Persist the rename itself.
+    if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        File::open(dir)?.sync_all()?;
+    }
+
+    Ok(OpenOptions::new().append(true).open(path)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn datasets(names: &[&str]) -> Vec<String> {
+        names.iter().map(|name| (*name).to_owned()).collect()
+    }
+
+    #[test]
+    fn charges_each_proposal_once_across_restarts() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("state").join("privacy-budget.jsonl");
+        let budget = |dataset: &str| if dataset == "visits" { 1.0 } else { 10.0 };
+
+        let mut ledger = PrivacyBudget::open(&path).unwrap();
+        ledger
+            .charge(1, &datasets(&["people", "visits"]), 0.5, None, budget)
+            .unwrap();
+        ledger
+            .charge(2, &datasets(&["people"]), 0.25, Some(1e-6), budget)
+            .unwrap();
+        // Charging a proposal again is a no-op.
+        ledger
+            .charge(1, &datasets(&["people", "visits"]), 0.5, None, budget)
+            .unwrap();
+        drop(ledger);
+
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(br#"{"proposal_id":3,"data"#).unwrap();
+        drop(file);
+
+        let mut ledger = PrivacyBudget::open(&path).unwrap();
+        assert_eq!(ledger.spent("people"), 0.75);
+        assert_eq!(ledger.spent("visits"), 0.5);
+        assert_eq!(ledger.spent("other"), 0.0);
+
+        // The second half of the visits budget can be spent, but no more.
+        let err = ledger
+            .charge(3, &datasets(&["people", "visits"]), 0.6, None, budget)
+            .unwrap_err();
+        assert!(err.to_string().contains("visits"), "{}", err);
+        assert_eq!(ledger.spent("people"), 0.75);
+        ledger
+            .charge(3, &datasets(&["people", "visits"]), 0.5, None, budget)
+            .unwrap();
+        drop(ledger);
+
+        let ledger = PrivacyBudget::open(&path).unwrap();
+        assert_eq!(ledger.spent("visits"), 1.0);
+        assert_eq!(ledger.charges[&2].delta, Some(1e-6));
+        let records = fs::read_to_string(&path).unwrap();
+        assert_eq!(records.lines().count(), 3);
+        let second = records.lines().nth(1).unwrap();
+        assert!(
+            second.ends_with(r#""epsilon":0.25,"delta":1e-6}"#),
+            "{}",
+            second
+        );
+    }
+
+    #[test]
+    fn rejects_corruption_before_the_end() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("privacy-budget.jsonl");
+        fs::write(
+            &path,
+            "garbage\n{\"proposal_id\":1,\"datasets\":[],\"epsilon\":1.0}\n",
+        )
+        .unwrap();
+
+        assert!(PrivacyBudget::open(&path).is_err());
+    }
+}
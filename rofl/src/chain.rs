@@ -0,0 +1,402 @@
+//! The engine's view of the chain the vault lives on.
+//!
+//! The proposal pipeline only talks to the vault through [`ChainBackend`], so it runs the same
+//! against Sapphire (`oasis::OasisChain`) and against the scripted vault in [`fake`], which the
+//! tests use to drive whole proposals through the engine without a node.
+use std::ops::RangeInclusive;
+
+use anyhow::Result;
+use async_trait::async_trait;
+
+use crate::events::Log;
+use crate::submitter::TxFailed;
+
+/// How a submitted transaction ended.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TxOutcome {
+    /// The transaction was included and executed.
+    Succeeded,
+    /// The transaction was included but failed, e.g. because the call reverted.
+    Failed(TxFailed),
+}
+
+#[async_trait]
+pub trait ChainBackend: Send + Sync {
+    /// A transaction that is ready to be signed and submitted.
+    type Tx: Send;
+
+    /// Latest round known to the node.
+    async fn current_round(&self) -> Result<u64>;
+
+    /// Run a read-only call against the vault at `round` and return the raw ABI-encoded output.
+    /// Reverts are reported as errors carrying the decoded `ContractError`.
+    async fn simulate_call(&self, round: u64, data: Vec<u8>) -> Result<Vec<u8>>;
+
+    /// Logs emitted by the vault in the given range of rounds, in chain order.
+    async fn get_logs(&self, round: u64, range: &RangeInclusive<u64>) -> Result<Vec<Log>>;
+
+    /// Build a vault call transaction with its gas estimated and its fee set. A call that would
+    /// revert fails here, with the decoded `ContractError`.
+    async fn prepare_call(&self, data: Vec<u8>) -> Result<Self::Tx>;
+
+    /// Sign and submit a transaction and wait for its outcome. An error means the outcome is
+    /// unknown: the transaction may or may not have been executed.
+    async fn submit_tx(&self, tx: Self::Tx) -> Result<TxOutcome>;
+}
+
+/// An in-memory vault that follows the contract's rules closely enough to test the engine.
+#[cfg(test)]
+pub mod fake {
+    use std::collections::BTreeMap;
+    use std::ops::RangeInclusive;
+    use std::sync::Mutex;
+
+    use anyhow::{Result, anyhow};
+    use async_trait::async_trait;
+    use ethabi::{Address, Event, Hash, ParamType, Token};
+
+    use super::{ChainBackend, TxOutcome};
+    use crate::events::{self, Log};
+    use crate::revert;
+    use crate::submitter::TxFailed;
+    use crate::vault::{CompletedQuery, ProposalStatus, QueryProposal};
+
+    /// Seconds between rounds.
+    const ROUND_SECONDS: u64 = 6;
+    /// `Vault.defaultExpirationPeriod` as set by the constructor.
+    pub const EXPIRATION_PERIOD: u64 = 7 * 24 * 60 * 60;
+    /// Error code of a reverted call in the EVM module.
+    const REVERTED_CODE: u32 = 8;
+    const UINT256: ParamType = ParamType::Uint(256);
+
+    #[derive(Debug, Default)]
+    struct Vault {
+        round: u64,
+        timestamp: u64,
+        proposals: BTreeMap<u64, QueryProposal>,
+        /// `proposalsByStatus[Approved]`, with the contract's swap-remove order.
+        approved: Vec<u64>,
+        completed: BTreeMap<u64, CompletedQuery>,
+        logs: Vec<Log>,
+        /// Calldata of every transaction that reached the chain.
+        transactions: Vec<Vec<u8>>,
+        /// Whether the next submission executes but reports an error to the submitter.
+        lose_next_submission: bool,
+    }
+
+    /// A scripted vault. Calls see its current state whatever round they ask for, and every
+    /// transaction, scripted or submitted, is included in a round of its own.
+    pub struct FakeChain {
+        app_id: [u8; 21],
+        vault: Mutex<Vault>,
+    }
+
+    impl FakeChain {
+        pub fn new(app_id: [u8; 21]) -> Self {
+            Self {
+                app_id,
+                vault: Mutex::new(Vault {
+                    round: 1,
+                    timestamp: 1_700_000_000,
+                    ..Vault::default()
+                }),
+            }
+        }
+
+        /// `proposeQuery`, returning the ID of the new proposal.
+        pub fn propose(&self, requester: Address, sql_query: &str, public_key: &str) -> u64 {
+            let mut vault = self.vault.lock().unwrap();
+            vault.next_round();
+            let id = vault.proposals.len() as u64 + 1;
+            let proposal = QueryProposal {
+                id,
+                requester,
+                sql_query: sql_query.to_owned(),
+                public_key: public_key.to_owned(),
+                timestamp: vault.timestamp,
+                expiration_time: vault.timestamp + EXPIRATION_PERIOD,
+                status: ProposalStatus::Pending,
+                governance_proposal_id: 0.into(),
+            };
+            vault.proposals.insert(id, proposal);
+            vault.emit(
+                events::proposal_submitted_event(),
+                &[uint(id), Token::Address(requester)],
+                &[Token::String(sql_query.to_owned())],
+            );
+            id
+        }
+
+        /// Governance approving a pending proposal.
+        pub fn approve(&self, proposal_id: u64) {
+            let mut vault = self.vault.lock().unwrap();
+            vault.next_round();
+            let proposal = vault
+                .proposals
+                .get_mut(&proposal_id)
+                .expect("proposal exists");
+            assert_eq!(proposal.status, ProposalStatus::Pending);
+            proposal.status = ProposalStatus::Approved;
+            vault.approved.push(proposal_id);
+            vault.emit(
+                events::proposal_approved_event(),
+                &[
+                    uint(proposal_id),
+                    Token::Address(Address::repeat_byte(0x90)),
+                ],
+                &[],
+            );
+        }
+
+        /// Let `rounds` rounds pass.
+        pub fn advance(&self, rounds: u64) {
+            let mut vault = self.vault.lock().unwrap();
+            vault.round += rounds;
+            vault.timestamp += rounds * ROUND_SECONDS;
+        }
+
+        /// Make the next submission execute but fail on the way back, as a dropped connection
+        /// to the node would.
+        pub fn lose_next_submission(&self) {
+            self.vault.lock().unwrap().lose_next_submission = true;
+        }
+
+        pub fn proposal(&self, proposal_id: u64) -> Option<QueryProposal> {
+            self.vault
+                .lock()
+                .unwrap()
+                .proposals
+                .get(&proposal_id)
+                .cloned()
+        }
+
+        pub fn completed_query(&self, proposal_id: u64) -> Option<CompletedQuery> {
+            self.vault
+                .lock()
+                .unwrap()
+                .completed
+                .get(&proposal_id)
+                .cloned()
+        }
+
+        /// Calldata of every transaction submitted so far.
+        pub fn transactions(&self) -> Vec<Vec<u8>> {
+            self.vault.lock().unwrap().transactions.clone()
+        }
+    }
+
+    impl Vault {
+        /// Start the round a transaction is included in.
+        fn next_round(&mut self) {
+            self.round += 1;
+            self.timestamp += ROUND_SECONDS;
+        }
+
+        fn emit(&mut self, event: Event, indexed: &[Token], data: &[Token]) {
+            let mut topics = vec![event.signature()];
+            topics.extend(
+                indexed
+                    .iter()
+                    .map(|token| Hash::from_slice(&ethabi::encode(std::slice::from_ref(token)))),
+            );
+            self.logs.push(Log {
+                block_number: self.round,
+                topics,
+                data: ethabi::encode(data),
+            });
+        }
+
+        fn proposal(&self, proposal_id: u64) -> Result<&QueryProposal> {
+            self.proposals
+                .get(&proposal_id)
+                .ok_or_else(|| reverted("Proposal does not exist"))
+        }
+
+        /// `consumeProposal(uint256 proposalId, string encryptedResult)`
+        fn consume_proposal(&mut self, args: &[u8]) -> Result<()> {
+            let [proposal_id, encrypted_result] = decode_args(&[UINT256, ParamType::String], args)?;
+            let proposal_id = as_u64(proposal_id)?;
+            let encrypted_result = encrypted_result.into_string().unwrap_or_default();
+
+            let proposal = self.proposal(proposal_id)?;
+            if proposal.status != ProposalStatus::Approved {
+                return Err(reverted("Proposal not approved"));
+            }
+            if self.timestamp >= proposal.expiration_time {
+                return Err(reverted("Proposal expired"));
+            }
+            if encrypted_result.is_empty() {
+                return Err(reverted("Encrypted result cannot be empty"));
+            }
+
+            let completed = CompletedQuery {
+                proposal_id,
+                original_query: proposal.sql_query.clone(),
+                public_key: proposal.public_key.clone(),
+                encrypted_result,
+                completed_timestamp: self.timestamp,
+            };
+            let requester = proposal.requester;
+            self.completed.insert(proposal_id, completed);
+            if let Some(proposal) = self.proposals.get_mut(&proposal_id) {
+                proposal.status = ProposalStatus::Completed;
+            }
+            if let Some(index) = self.approved.iter().position(|id| *id == proposal_id) {
+                self.approved.swap_remove(index);
+            }
+            self.emit(
+                events::query_completed_event(),
+                &[uint(proposal_id), Token::Address(requester)],
+                &[],
+            );
+
+            Ok(())
+        }
+
+        /// Execute a transaction, leaving the state untouched if it reverts.
+        fn execute(&mut self, data: &[u8]) -> Result<()> {
+            let (selector, args) = split_selector(data)?;
+            if selector
+                == selector_of(
+                    "consumeProposal",
+                    &[ParamType::Uint(256), ParamType::String],
+                )
+            {
+                self.consume_proposal(args)
+            } else {
+                Err(anyhow!(
+                    "unsupported transaction 0x{}",
+                    hex::encode(selector)
+                ))
+            }
+        }
+
+        fn call(&self, app_id: [u8; 21], data: &[u8]) -> Result<Vec<u8>> {
+            let (selector, args) = split_selector(data)?;
+            let output = if selector == selector_of("getApprovedProposals", &[UINT256, UINT256]) {
+                let [offset, limit] = decode_args(&[UINT256, UINT256], args)?;
+                let page = self
+                    .approved
+                    .iter()
+                    .skip(as_u64(offset)? as usize)
+                    .take(as_u64(limit)? as usize)
+                    .map(|id| self.proposals[id].to_token())
+                    .collect();
+                Token::Array(page)
+            } else if selector == selector_of("getProposal", &[UINT256]) {
+                let [proposal_id] = decode_args(&[UINT256], args)?;
+                self.proposal(as_u64(proposal_id)?)?.to_token()
+            } else if selector == selector_of("getCompletedQuery", &[UINT256]) {
+                let [proposal_id] = decode_args(&[UINT256], args)?;
+                self.completed
+                    .get(&as_u64(proposal_id)?)
+                    .ok_or_else(|| reverted("Completed query does not exist"))?
+                    .to_token()
+            } else if selector == selector_of("appId", &[]) {
+                Token::FixedBytes(app_id.to_vec())
+            } else {
+                // Calls that execute a transaction's logic without applying it.
+                let mut scratch = Vault {
+                    proposals: self.proposals.clone(),
+                    approved: self.approved.clone(),
+                    timestamp: self.timestamp,
+                    ..Vault::default()
+                };
+                scratch.execute(data)?;
+                return Ok(Vec::new());
+            };
+
+            Ok(ethabi::encode(&[output]))
+        }
+    }
+
+    #[async_trait]
+    impl ChainBackend for FakeChain {
+        /// The calldata of the call.
+        type Tx = Vec<u8>;
+
+        async fn current_round(&self) -> Result<u64> {
+            Ok(self.vault.lock().unwrap().round)
+        }
+
+        async fn simulate_call(&self, _round: u64, data: Vec<u8>) -> Result<Vec<u8>> {
+            self.vault
+                .lock()
+                .unwrap()
+                .call(self.app_id, &data)
+                .map_err(revert::decode_error)
+        }
+
+        async fn get_logs(&self, _round: u64, range: &RangeInclusive<u64>) -> Result<Vec<Log>> {
+            Ok(self
+                .vault
+                .lock()
+                .unwrap()
+                .logs
+                .iter()
+                .filter(|log| range.contains(&log.block_number))
+                .cloned()
+                .collect())
+        }
+
+        async fn prepare_call(&self, data: Vec<u8>) -> Result<Vec<u8>> {
+            let round = self.current_round().await?;
+            self.simulate_call(round, data.clone()).await?;
+            Ok(data)
+        }
+
+        async fn submit_tx(&self, data: Vec<u8>) -> Result<TxOutcome> {
+            let mut vault = self.vault.lock().unwrap();
+            vault.next_round();
+            vault.transactions.push(data.clone());
+
+            let outcome = match vault.execute(&data) {
+                Ok(()) => TxOutcome::Succeeded,
+                Err(err) => TxOutcome::Failed(TxFailed {
+                    module: "evm".into(),
+                    code: REVERTED_CODE,
+                    message: err.to_string(),
+                }),
+            };
+
+            if std::mem::take(&mut vault.lose_next_submission) {
+                return Err(anyhow!("connection to the node was lost"));
+            }
+            Ok(outcome)
+        }
+    }
+
+    /// The error the EVM module reports for `require(false, message)`.
+    fn reverted(message: &str) -> anyhow::Error {
+        anyhow!("reverted: {}", message)
+    }
+
+    fn uint(value: u64) -> Token {
+        Token::Uint(value.into())
+    }
+
+    fn as_u64(token: Token) -> Result<u64> {
+        let value = token
+            .into_uint()
+            .ok_or_else(|| anyhow!("expected a uint argument"))?;
+        u64::try_from(value).map_err(|_| anyhow!("argument {} does not fit in u64", value))
+    }
+
+    fn selector_of(name: &str, params: &[ParamType]) -> [u8; 4] {
+        ethabi::short_signature(name, params)
+    }
+
+    fn split_selector(data: &[u8]) -> Result<([u8; 4], &[u8])> {
+        if data.len() < 4 {
+            return Err(anyhow!("calldata without a function selector"));
+        }
+        let (selector, args) = data.split_at(4);
+        Ok((selector.try_into()?, args))
+    }
+
+    fn decode_args<const N: usize>(params: &[ParamType], args: &[u8]) -> Result<[Token; N]> {
+        ethabi::decode(params, args)?
+            .try_into()
+            .map_err(|_| anyhow!("expected {} arguments", N))
+    }
+}
@@ -0,0 +1,1273 @@
+//! Engine configuration.
+//!
+//! Settings are resolved in three layers: the defaults of the selected network profile, an
+//! optional TOML file named by `ENGINE_CONFIG`, and environment variables. ROFL secrets reach the
+//! container as environment variables through `compose.yaml`, so credentials are only read from
+//! the environment and never from the file. The result is validated once at startup and every
+//! problem is reported at the same time.
+//!
+//! Only the localnet profile may run without a consensus trust root; on the other networks the
+//! engine refuses to start without one, as it would otherwise trust whatever its node reports.
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+
+use anyhow::{Context, Result, anyhow};
+use ethabi::Address;
+use serde::{Deserialize, Serialize};
+
+/// Characters of the bech32 data part.
+const BECH32_CHARSET: &str = "qpzry9x8gf2tvdw0s3jn54khce6mua7l";
+
+/// Network the engine is deployed against.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Network {
+    Localnet,
+    Testnet,
+    Mainnet,
+}
+
+impl FromStr for Network {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        match value.to_lowercase().as_str() {
+            "localnet" => Ok(Network::Localnet),
+            "testnet" => Ok(Network::Testnet),
+            "mainnet" => Ok(Network::Mainnet),
+            _ => Err(anyhow!(
+                "unknown network {:?}, expected localnet, testnet or mainnet",
+                value
+            )),
+        }
+    }
+}
+
+/// Noise mechanism of differentially private results.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Mechanism {
+    Laplace,
+    Gaussian,
+}
+
+impl FromStr for Mechanism {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        match value.to_lowercase().as_str() {
+            "laplace" => Ok(Mechanism::Laplace),
+            "gaussian" => Ok(Mechanism::Gaussian),
+            _ => Err(anyhow!(
+                "unknown mechanism {:?}, expected laplace or gaussian",
+                value
+            )),
+        }
+    }
+}
+
+/// Built-in defaults of a network profile.
+struct Profile {
+    app_id: Option<&'static str>,
+    contract_address: Option<&'static str>,
+    trust_root: ProfileTrustRoot,
+}
+
+/// Trust root defaults of a profile. The runtime and chain are fixed per network, while the
+/// height and hash belong to a deployment.
+#[derive(Default)]
+struct ProfileTrustRoot {
+    height: Option<u64>,
+    hash: Option<&'static str>,
+    runtime_id: Option<&'static str>,
+    chain_context: Option<&'static str>,
+}
+
+impl Network {
+    fn profile(self) -> Profile {
+        match self {
+            Network::Localnet => Profile {
+                app_id: None,
+                contract_address: None,
+                trust_root: ProfileTrustRoot::default(),
+            },
+            // The Sapphire Testnet deployment described in rofl.yaml.
+            Network::Testnet => Profile {
+                app_id: Some("rofl1qzshync9pv2als5y0n33wrjycwc2pp0y0u8yw3k4"),
+                contract_address: Some("0xcDC557d454C09141d7bbb1E67c39BF500a348A5a"),
+                trust_root: ProfileTrustRoot {
+                    height: Some(26852547),
+                    hash: Some("1009fe4dd739c715294ca0bcc86b7449a2f5f7a147285d581af319d75ccc9f75"),
+                    runtime_id: Some(
+                        "000000000000000000000000000000000000000000000000a6d1e3ebf60dff6c",
+                    ),
+                    chain_context: Some(
+                        "0b91b8e4e44b2003a7c5e23ddadb5e14ef5345c0ebcb3ddcae07fa2f244cab76",
+                    ),
+                },
+            },
+            // Sapphire Mainnet; there is no deployment yet.
+            Network::Mainnet => Profile {
+                app_id: None,
+                contract_address: None,
+                trust_root: ProfileTrustRoot {
+                    height: None,
+                    hash: None,
+                    runtime_id: Some(
+                        "000000000000000000000000000000000000000000000000f80306c9858e7279",
+                    ),
+                    chain_context: Some(
+                        "bb3d748def55bdfb797a2ac53ee6ee141e54cd2ab2dc2375f4a0703a178e6e55",
+                    ),
+                },
+            },
+        }
+    }
+}
+
+/// A value that is kept out of logs.
+#[derive(Clone, PartialEq, Eq)]
+pub struct Secret(String);
+
+impl Secret {
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("<redacted>")
+    }
+}
+
+/// Gas settings for calls and transactions against the vault.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GasConfig {
+    /// Gas limit of read-only `evm.SimulateCall` queries.
+    pub simulate_gas_limit: u64,
+    /// Gas price of read-only `evm.SimulateCall` queries.
+    pub simulate_gas_price: u64,
+    /// Upper bound on the gas of a single transaction.
+    pub max_tx_gas: u64,
+    /// Headroom added to gas estimates, in percent.
+    pub margin_percent: u64,
+}
+
+impl Default for GasConfig {
+    fn default() -> Self {
+        Self {
+            simulate_gas_limit: 1_000_000,
+            simulate_gas_price: 100,
+            // The batch gas limit on Sapphire.
+            max_tx_gas: 15_000_000,
+            margin_percent: 20,
+        }
+    }
+}
+
+/// Resources a single query may use. A query that runs into one of them has a resource exceeded
+/// outcome instead of a result.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LimitsConfig {
+    /// Wall-clock time of loading the datasets and running the query.
+    pub timeout: Duration,
+    /// Memory DuckDB may use before it spills to disk, in MB.
+    pub memory_mb: u64,
+    /// Disk space DuckDB may spill to, in MB.
+    pub spill_mb: u64,
+    /// Directory DuckDB spills to; its own default when `None`.
+    pub spill_dir: Option<PathBuf>,
+    /// Total size of the dataset objects a query reads, in bytes.
+    pub max_scan_bytes: u64,
+    /// Rows a query may return.
+    pub max_output_rows: u64,
+}
+
+impl Default for LimitsConfig {
+    fn default() -> Self {
+        // The app gets 512 MB of memory and disk, shared with the engine and its state.
+        Self {
+            timeout: Duration::from_secs(60),
+            memory_mb: 256,
+            spill_mb: 256,
+            spill_dir: None,
+            max_scan_bytes: 128 << 20,
+            max_output_rows: 10_000,
+        }
+    }
+}
+
+/// Consensus block the runtime verifies the consensus layer from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TrustRootConfig {
+    pub height: u64,
+    /// Hex-encoded hash of the block at `height`.
+    pub hash: String,
+    /// Hex-encoded ID of the runtime (ParaTime).
+    pub runtime_id: String,
+    /// Hex-encoded chain context of the consensus layer.
+    pub chain_context: String,
+}
+
+/// Connection settings for the Akave bucket holding the datasets.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AkaveConfig {
+    pub endpoint: String,
+    pub bucket: String,
+    /// Bucket that results too large to store on chain are uploaded to.
+    pub result_bucket: String,
+    pub access_key: Secret,
+    pub secret_key: Secret,
+}
+
+/// What proposal queries may do beyond being read-only queries over the datasets.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PolicyConfig {
+    /// Columns, in any dataset, that may only be projected through aggregates.
+    pub pii_columns: Vec<String>,
+    /// Fewest input rows a result group needs to be released, unless the dataset sets its own.
+    pub min_group_size: u64,
+    /// Settings of individual datasets, by table name.
+    pub datasets: BTreeMap<String, DatasetPolicy>,
+    /// Differential privacy of results; off when `None`.
+    pub privacy: Option<PrivacyConfig>,
+}
+
+impl Default for PolicyConfig {
+    fn default() -> Self {
+        Self {
+            pii_columns: Vec::new(),
+            min_group_size: 1,
+            datasets: BTreeMap::new(),
+            privacy: None,
+        }
+    }
+}
+
+impl PolicyConfig {
+    fn dataset(&self, table: &str) -> Option<&DatasetPolicy> {
+        self.datasets
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case(table))
+            .map(|(_, dataset)| dataset)
+    }
+
+    /// Minimum group size of a result over the given tables: the largest one among them.
+    pub fn min_group_size<'a>(&self, tables: impl IntoIterator<Item = &'a str>) -> u64 {
+        tables
+            .into_iter()
+            .map(|table| {
+                self.dataset(table)
+                    .and_then(|dataset| dataset.min_group_size)
+                    .unwrap_or(self.min_group_size)
+            })
+            .max()
+            .unwrap_or(self.min_group_size)
+    }
+
+    /// Total epsilon a dataset may spend, if differential privacy is on.
+    pub fn epsilon_budget(&self, table: &str) -> Option<f64> {
+        let privacy = self.privacy.as_ref()?;
+        Some(
+            self.dataset(table)
+                .and_then(|dataset| dataset.epsilon_budget)
+                .unwrap_or(privacy.epsilon_budget),
+        )
+    }
+}
+
+/// Policy settings of a single dataset.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DatasetPolicy {
+    pub min_group_size: Option<u64>,
+    pub epsilon_budget: Option<f64>,
+}
+
+/// Differential privacy of `COUNT`, `SUM` and `AVG` results.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PrivacyConfig {
+    pub mechanism: Mechanism,
+    /// Epsilon a query spends on every dataset it reads.
+    pub epsilon: f64,
+    /// Delta of the Gaussian mechanism.
+    pub delta: f64,
+    /// Values are clamped to this magnitude before they are summed or averaged. Without it, `SUM`
+    /// and `AVG` are refused.
+    pub sum_bound: Option<f64>,
+    /// Total epsilon a dataset may spend, unless it sets its own.
+    pub epsilon_budget: f64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct EngineConfig {
+    pub network: Network,
+    /// Bech32 ID of the ROFL app the engine runs as.
+    pub app_id: String,
+    /// Address of the `Vault` contract.
+    pub contract_address: Address,
+    /// Consensus trust root; only `None` on localnet, where integrity is not verified.
+    pub trust_root: Option<TrustRootConfig>,
+    /// Endpoint engine logs are posted to, if any.
+    pub log_server_url: Option<String>,
+    /// Directory on persistent storage holding the engine's state.
+    pub data_dir: PathBuf,
+    /// Number of approved proposals requested per `getApprovedProposals` call.
+    pub approved_page_size: u64,
+    /// Largest encrypted result, in bytes, stored on chain; larger ones go through Akave.
+    pub inline_result_bytes: u64,
+    pub gas: GasConfig,
+    pub akave: AkaveConfig,
+    pub policy: PolicyConfig,
+    pub limits: LimitsConfig,
+}
+
+/// Layout of the optional TOML file.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct FileConfig {
+    network: Option<Network>,
+    app_id: Option<String>,
+    contract_address: Option<String>,
+    log_server_url: Option<String>,
+    data_dir: Option<PathBuf>,
+    approved_page_size: Option<u64>,
+    inline_result_bytes: Option<u64>,
+    trust_root: FileTrustRootConfig,
+    gas: FileGasConfig,
+    akave: FileAkaveConfig,
+    policy: FilePolicyConfig,
+    limits: FileLimitsConfig,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct FileTrustRootConfig {
+    height: Option<u64>,
+    hash: Option<String>,
+    runtime_id: Option<String>,
+    chain_context: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct FileGasConfig {
+    simulate_gas_limit: Option<u64>,
+    simulate_gas_price: Option<u64>,
+    max_tx_gas: Option<u64>,
+    margin_percent: Option<u64>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct FileAkaveConfig {
+    endpoint: Option<String>,
+    bucket: Option<String>,
+    result_bucket: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct FileLimitsConfig {
+    timeout_secs: Option<u64>,
+    memory_mb: Option<u64>,
+    spill_mb: Option<u64>,
+    max_scan_bytes: Option<u64>,
+    max_output_rows: Option<u64>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct FilePolicyConfig {
+    pii_columns: Option<Vec<String>>,
+    min_group_size: Option<u64>,
+    datasets: BTreeMap<String, DatasetPolicy>,
+    privacy: FilePrivacyConfig,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct FilePrivacyConfig {
+    mechanism: Option<Mechanism>,
+    epsilon: Option<f64>,
+    delta: Option<f64>,
+    sum_bound: Option<f64>,
+    epsilon_budget: Option<f64>,
+}
+
+/// Collects every problem with the configuration so they can be reported together.
+#[derive(Default)]
+struct Errors(Vec<String>);
+
+impl Errors {
+    fn push(&mut self, key: &str, message: impl fmt::Display) {
+        self.0.push(format!("{}: {}", key, message));
+    }
+
+    fn has(&self, key: &str) -> bool {
+        let prefix = format!("{}: ", key);
+        self.0.iter().any(|error| error.starts_with(&prefix))
+    }
+
+    /// Parse a real-valued setting, recording an error if it is malformed.
+    fn real(&mut self, key: &str, value: Option<String>, fallback: f64) -> f64 {
+        match value.map(|value| value.trim().parse::<f64>()) {
+            None => fallback,
+            Some(Ok(value)) => value,
+            Some(Err(err)) => {
+                self.push(key, err);
+                fallback
+            }
+        }
+    }
+
+    /// Record an error unless `value` is a positive number.
+    fn positive(&mut self, key: &str, value: f64) {
+        if !(value.is_finite() && value > 0.0) {
+            self.push(key, "must be a positive number");
+        }
+    }
+
+    /// Parse a numeric setting, recording an error if it is malformed.
+    fn number(&mut self, key: &str, value: Option<String>, fallback: u64) -> u64 {
+        match value.map(|value| value.trim().parse::<u64>()) {
+            None => fallback,
+            Some(Ok(value)) => value,
+            Some(Err(err)) => {
+                self.push(key, err);
+                fallback
+            }
+        }
+    }
+}
+
+impl EngineConfig {
+    /// Load the configuration from the process environment.
+    pub fn from_env() -> Result<Self> {
+        Self::load(|name| std::env::var(name).ok())
+    }
+
+    /// Load the configuration, looking up environment variables through `var`. Empty variables
+    /// count as unset, so optional ROFL secrets can be passed through unconditionally.
+    pub fn load(var: impl Fn(&str) -> Option<String>) -> Result<Self> {
+        let var = |name: &str| var(name).filter(|value| !value.trim().is_empty());
+
+        let file = match var("ENGINE_CONFIG") {
+            Some(path) => {
+                let contents = fs::read_to_string(&path)
+                    .with_context(|| format!("failed to read config file {}", path))?;
+                toml::from_str::<FileConfig>(&contents)
+                    .with_context(|| format!("invalid config file {}", path))?
+            }
+            None => FileConfig::default(),
+        };
+
+        let mut errors = Errors::default();
+
+        let network = match var("ENGINE_NETWORK") {
+            Some(network) => network.parse().unwrap_or_else(|err| {
+                errors.push("ENGINE_NETWORK", err);
+                Network::Testnet
+            }),
+            None => file.network.unwrap_or(Network::Testnet),
+        };
+        let profile = network.profile();
+
+        let app_id = var("ENGINE_APP_ID")
+            .or(file.app_id)
+            .or(profile.app_id.map(str::to_owned));
+        let app_id = match app_id {
+            Some(app_id) => {
+                if let Err(err) = validate_app_id(&app_id) {
+                    errors.push("ENGINE_APP_ID", err);
+                }
+                app_id
+            }
+            None => {
+                errors.push("ENGINE_APP_ID", "must be set for this network");
+                String::new()
+            }
+        };
+
+        let contract_address = var("ENGINE_CONTRACT_ADDRESS")
+            .or(file.contract_address)
+            .or(profile.contract_address.map(str::to_owned));
+        let contract_address = match contract_address.map(|address| parse_address(&address)) {
+            Some(Ok(address)) => address,
+            Some(Err(err)) => {
+                errors.push("ENGINE_CONTRACT_ADDRESS", err);
+                Address::zero()
+            }
+            None => {
+                errors.push("ENGINE_CONTRACT_ADDRESS", "must be set for this network");
+                Address::zero()
+            }
+        };
+
+        let trust_root = load_trust_root(
+            network,
+            &var,
+            file.trust_root,
+            profile.trust_root,
+            &mut errors,
+        );
+
+        let log_server_url = var("ENGINE_LOG_SERVER_URL").or(file.log_server_url);
+        if let Some(url) = &log_server_url
+            && let Err(err) = validate_url(url)
+        {
+            errors.push("ENGINE_LOG_SERVER_URL", err);
+        }
+
+        let data_dir = var("ENGINE_DATA_DIR")
+            .map(PathBuf::from)
+            .or(file.data_dir)
+            .unwrap_or_else(|| PathBuf::from("/data"));
+        if !data_dir.is_absolute() {
+            errors.push("ENGINE_DATA_DIR", "must be an absolute path");
+        }
+
+        let approved_page_size = errors.number(
+            "ENGINE_APPROVED_PAGE_SIZE",
+            var("ENGINE_APPROVED_PAGE_SIZE"),
+            file.approved_page_size.unwrap_or(10),
+        );
+        if approved_page_size == 0 {
+            errors.push("ENGINE_APPROVED_PAGE_SIZE", "must be positive");
+        }
+
+        let inline_result_bytes = errors.number(
+            "ENGINE_INLINE_RESULT_BYTES",
+            var("ENGINE_INLINE_RESULT_BYTES"),
+            file.inline_result_bytes.unwrap_or(4096),
+        );
+
+        let defaults = GasConfig::default();
+        let gas = GasConfig {
+            simulate_gas_limit: errors.number(
+                "ENGINE_SIMULATE_GAS_LIMIT",
+                var("ENGINE_SIMULATE_GAS_LIMIT"),
+                file.gas
+                    .simulate_gas_limit
+                    .unwrap_or(defaults.simulate_gas_limit),
+            ),
+            simulate_gas_price: errors.number(
+                "ENGINE_SIMULATE_GAS_PRICE",
+                var("ENGINE_SIMULATE_GAS_PRICE"),
+                file.gas
+                    .simulate_gas_price
+                    .unwrap_or(defaults.simulate_gas_price),
+            ),
+            max_tx_gas: errors.number(
+                "ENGINE_MAX_TX_GAS",
+                var("ENGINE_MAX_TX_GAS"),
+                file.gas.max_tx_gas.unwrap_or(defaults.max_tx_gas),
+            ),
+            margin_percent: errors.number(
+                "ENGINE_GAS_MARGIN_PERCENT",
+                var("ENGINE_GAS_MARGIN_PERCENT"),
+                file.gas.margin_percent.unwrap_or(defaults.margin_percent),
+            ),
+        };
+        if gas.simulate_gas_limit == 0 {
+            errors.push("ENGINE_SIMULATE_GAS_LIMIT", "must be positive");
+        }
+        if gas.max_tx_gas == 0 {
+            errors.push("ENGINE_MAX_TX_GAS", "must be positive");
+        }
+        if gas.margin_percent > 100 {
+            errors.push("ENGINE_GAS_MARGIN_PERCENT", "must be at most 100");
+        }
+
+        let mut required = |key: &str, value: Option<String>| {
+            value.unwrap_or_else(|| {
+                errors.push(key, "must be set");
+                String::new()
+            })
+        };
+        let bucket = var("AKAVE_BUCKET")
+            .or(file.akave.bucket)
+            .unwrap_or_else(|| "BaMaMe-Bucket".to_owned());
+        let akave = AkaveConfig {
+            endpoint: required(
+                "AKAVE_ENDPOINT",
+                var("AKAVE_ENDPOINT").or(file.akave.endpoint),
+            ),
+            bucket: bucket.clone(),
+            result_bucket: var("AKAVE_RESULT_BUCKET")
+                .or(file.akave.result_bucket)
+                .unwrap_or(bucket),
+            access_key: Secret(required("AKAVE_ACCESS_KEY", var("AKAVE_ACCESS_KEY"))),
+            secret_key: Secret(required("AKAVE_SECRET_KEY", var("AKAVE_SECRET_KEY"))),
+        };
+        if !akave.endpoint.is_empty()
+            && let Err(err) = validate_url(&akave.endpoint)
+        {
+            errors.push("AKAVE_ENDPOINT", err);
+        }
+
+        let policy = PolicyConfig {
+            pii_columns: var("ENGINE_PII_COLUMNS")
+                .map(|columns| {
+                    columns
+                        .split(',')
+                        .map(|column| column.trim().to_owned())
+                        .filter(|column| !column.is_empty())
+                        .collect()
+                })
+                .or(file.policy.pii_columns)
+                .unwrap_or_default(),
+            min_group_size: errors.number(
+                "ENGINE_MIN_GROUP_SIZE",
+                var("ENGINE_MIN_GROUP_SIZE"),
+                file.policy.min_group_size.unwrap_or(1),
+            ),
+            datasets: file.policy.datasets,
+            privacy: load_privacy(&var, file.policy.privacy, &mut errors),
+        };
+        if policy.min_group_size == 0 {
+            errors.push("ENGINE_MIN_GROUP_SIZE", "must be positive");
+        }
+        for (table, dataset) in &policy.datasets {
+            if dataset.min_group_size == Some(0) {
+                errors.push(
+                    &format!("policy.datasets.{}.min_group_size", table),
+                    "must be positive",
+                );
+            }
+            if let Some(budget) = dataset.epsilon_budget {
+                errors.positive(&format!("policy.datasets.{}.epsilon_budget", table), budget);
+            }
+        }
+
+        let defaults = LimitsConfig::default();
+        let limits = LimitsConfig {
+            timeout: Duration::from_secs(
+                errors.number(
+                    "ENGINE_QUERY_TIMEOUT_SECS",
+                    var("ENGINE_QUERY_TIMEOUT_SECS"),
+                    file.limits
+                        .timeout_secs
+                        .unwrap_or(defaults.timeout.as_secs()),
+                ),
+            ),
+            memory_mb: errors.number(
+                "ENGINE_QUERY_MEMORY_MB",
+                var("ENGINE_QUERY_MEMORY_MB"),
+                file.limits.memory_mb.unwrap_or(defaults.memory_mb),
+            ),
+            spill_mb: errors.number(
+                "ENGINE_QUERY_SPILL_MB",
+                var("ENGINE_QUERY_SPILL_MB"),
+                file.limits.spill_mb.unwrap_or(defaults.spill_mb),
+            ),
+            spill_dir: Some(data_dir.join("spill")),
+            max_scan_bytes: errors.number(
+                "ENGINE_QUERY_MAX_SCAN_BYTES",
+                var("ENGINE_QUERY_MAX_SCAN_BYTES"),
+                file.limits
+                    .max_scan_bytes
+                    .unwrap_or(defaults.max_scan_bytes),
+            ),
+            max_output_rows: errors.number(
+                "ENGINE_QUERY_MAX_ROWS",
+                var("ENGINE_QUERY_MAX_ROWS"),
+                file.limits
+                    .max_output_rows
+                    .unwrap_or(defaults.max_output_rows),
+            ),
+        };
+        for (key, value) in [
+            ("ENGINE_QUERY_TIMEOUT_SECS", limits.timeout.as_secs()),
+            ("ENGINE_QUERY_MEMORY_MB", limits.memory_mb),
+            ("ENGINE_QUERY_MAX_SCAN_BYTES", limits.max_scan_bytes),
+            ("ENGINE_QUERY_MAX_ROWS", limits.max_output_rows),
+        ] {
+            if value == 0 {
+                errors.push(key, "must be positive");
+            }
+        }
+
+        if !errors.0.is_empty() {
+            return Err(anyhow!(
+                "invalid engine configuration:\n  - {}",
+                errors.0.join("\n  - ")
+            ));
+        }
+
+        Ok(Self {
+            network,
+            app_id,
+            contract_address,
+            trust_root,
+            log_server_url,
+            data_dir,
+            approved_page_size,
+            inline_result_bytes,
+            gas,
+            akave,
+            policy,
+            limits,
+        })
+    }
+
+    /// Location of the proposal journal.
+    pub fn journal_path(&self) -> PathBuf {
+        self.data_dir.join("journal.jsonl")
+    }
+
+    /// File that logs the collector did not accept are appended to.
+    pub fn log_fallback_path(&self) -> PathBuf {
+        self.data_dir.join("logs-undelivered.jsonl")
+    }
+
+    /// File of the epsilon spent on each dataset by differentially private results.
+    pub fn privacy_budget_path(&self) -> PathBuf {
+        self.data_dir.join("privacy-budget.jsonl")
+    }
+}
+
+/// Resolve the differential privacy settings, which are on once a mechanism is chosen.
+fn load_privacy(
+    var: &impl Fn(&str) -> Option<String>,
+    file: FilePrivacyConfig,
+    errors: &mut Errors,
+) -> Option<PrivacyConfig> {
+    let mechanism = match var("ENGINE_DP_MECHANISM") {
+        Some(mechanism) => match mechanism.parse() {
+            Ok(mechanism) => mechanism,
+            Err(err) => {
+                errors.push("ENGINE_DP_MECHANISM", err);
+                return None;
+            }
+        },
+        None => file.mechanism?,
+    };
+
+    // The Gaussian calibration needs an epsilon below 1.
+    let default_epsilon = match mechanism {
+        Mechanism::Laplace => 1.0,
+        Mechanism::Gaussian => 0.5,
+    };
+    let privacy = PrivacyConfig {
+        mechanism,
+        epsilon: errors.real(
+            "ENGINE_DP_EPSILON",
+            var("ENGINE_DP_EPSILON"),
+            file.epsilon.unwrap_or(default_epsilon),
+        ),
+        delta: errors.real(
+            "ENGINE_DP_DELTA",
+            var("ENGINE_DP_DELTA"),
+            file.delta.unwrap_or(1e-6),
+        ),
+        sum_bound: match var("ENGINE_DP_SUM_BOUND") {
+            Some(bound) => Some(errors.real("ENGINE_DP_SUM_BOUND", Some(bound), 1.0)),
+            None => file.sum_bound,
+        },
+        epsilon_budget: errors.real(
+            "ENGINE_DP_BUDGET",
+            var("ENGINE_DP_BUDGET"),
+            file.epsilon_budget.unwrap_or(10.0),
+        ),
+    };
+    errors.positive("ENGINE_DP_EPSILON", privacy.epsilon);
+    if privacy.mechanism == Mechanism::Gaussian && privacy.epsilon >= 1.0 {
+        errors.push(
+            "ENGINE_DP_EPSILON",
+            "must be below 1 for the gaussian mechanism",
+        );
+    }
+    errors.positive("ENGINE_DP_DELTA", privacy.delta);
+    if privacy.delta >= 1.0 {
+        errors.push("ENGINE_DP_DELTA", "must be below 1");
+    }
+    if let Some(bound) = privacy.sum_bound {
+        errors.positive("ENGINE_DP_SUM_BOUND", bound);
+    }
+    errors.positive("ENGINE_DP_BUDGET", privacy.epsilon_budget);
+
+    Some(privacy)
+}
+
+/// Resolve the trust root from the environment, the file and the profile. It is either complete or
+/// absent, and may only be absent on localnet.
+fn load_trust_root(
+    network: Network,
+    var: &impl Fn(&str) -> Option<String>,
+    file: FileTrustRootConfig,
+    profile: ProfileTrustRoot,
+    errors: &mut Errors,
+) -> Option<TrustRootConfig> {
+    let height = match var("ENGINE_TRUST_ROOT_HEIGHT") {
+        Some(height) => match height.trim().parse::<u64>() {
+            Ok(height) => Some(height),
+            Err(err) => {
+                errors.push("ENGINE_TRUST_ROOT_HEIGHT", err);
+                return None;
+            }
+        },
+        None => file.height.or(profile.height),
+    };
+    let mut hex_field = |key: &str, file: Option<String>, profile: Option<&str>| {
+        let value = var(key).or(file).or(profile.map(str::to_owned))?;
+        match validate_hash(&value) {
+            Ok(()) => Some(value.to_lowercase()),
+            Err(err) => {
+                errors.push(key, err);
+                None
+            }
+        }
+    };
+    let hash = hex_field("ENGINE_TRUST_ROOT_HASH", file.hash, profile.hash);
+    let runtime_id = hex_field(
+        "ENGINE_TRUST_ROOT_RUNTIME_ID",
+        file.runtime_id,
+        profile.runtime_id,
+    );
+    let chain_context = hex_field(
+        "ENGINE_TRUST_ROOT_CHAIN_CONTEXT",
+        file.chain_context,
+        profile.chain_context,
+    );
+
+    match (height, hash, runtime_id, chain_context) {
+        (Some(height), Some(hash), Some(runtime_id), Some(chain_context)) => {
+            if height == 0 {
+                errors.push("ENGINE_TRUST_ROOT_HEIGHT", "must be positive");
+            }
+            Some(TrustRootConfig {
+                height,
+                hash,
+                runtime_id,
+                chain_context,
+            })
+        }
+        (None, None, None, None) if network == Network::Localnet => None,
+        (height, hash, runtime_id, chain_context) => {
+            let fields = [
+                ("ENGINE_TRUST_ROOT_HEIGHT", height.is_some()),
+                ("ENGINE_TRUST_ROOT_HASH", hash.is_some()),
+                ("ENGINE_TRUST_ROOT_RUNTIME_ID", runtime_id.is_some()),
+                ("ENGINE_TRUST_ROOT_CHAIN_CONTEXT", chain_context.is_some()),
+            ];
+            for (key, _) in fields.iter().filter(|(_, set)| !set) {
+                // Invalid values were reported already.
+                if !errors.has(key) {
+                    errors.push(key, "must be set unless ENGINE_NETWORK is localnet");
+                }
+            }
+            None
+        }
+    }
+}
+
+fn validate_hash(value: &str) -> Result<()> {
+    match hex::decode(value) {
+        Ok(bytes) if bytes.len() == 32 => Ok(()),
+        _ => Err(anyhow!("{:?} is not 32 hex-encoded bytes", value)),
+    }
+}
+
+fn validate_app_id(app_id: &str) -> Result<()> {
+    let data = app_id
+        .strip_prefix("rofl1")
+        .ok_or_else(|| anyhow!("{:?} is not a rofl1... app ID", app_id))?;
+    if data.len() != 40 || !data.chars().all(|c| BECH32_CHARSET.contains(c)) {
+        return Err(anyhow!("{:?} is not a valid bech32 app ID", app_id));
+    }
+
+    Ok(())
+}
+
+fn parse_address(address: &str) -> Result<Address> {
+    let hex = address
+        .strip_prefix("0x")
+        .ok_or_else(|| anyhow!("{:?} must start with 0x", address))?;
+    let bytes = hex::decode(hex).map_err(|_| anyhow!("{:?} is not hex", address))?;
+    if bytes.len() != 20 {
+        return Err(anyhow!("{:?} is not a 20 byte address", address));
+    }
+
+    let address = Address::from_slice(&bytes);
+    if address.is_zero() {
+        return Err(anyhow!("the zero address is not a vault"));
+    }
+
+    Ok(address)
+}
+
+fn validate_url(url: &str) -> Result<()> {
+    if !(url.starts_with("http://") || url.starts_with("https://")) {
+        return Err(anyhow!("{:?} is not an http(s) URL", url));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn load(vars: &[(&str, &str)]) -> Result<EngineConfig> {
+        let vars: HashMap<String, String> = vars
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect();
+        EngineConfig::load(|name| vars.get(name).cloned())
+    }
+
+    const SECRETS: [(&str, &str); 3] = [
+        ("AKAVE_ENDPOINT", "https://akave.example"),
+        ("AKAVE_ACCESS_KEY", "access"),
+        ("AKAVE_SECRET_KEY", "secret"),
+    ];
+
+    #[test]
+    fn uses_testnet_profile_by_default() {
+        let config = load(&SECRETS).unwrap();
+
+        assert_eq!(config.network, Network::Testnet);
+        assert_eq!(
+            config.app_id,
+            "rofl1qzshync9pv2als5y0n33wrjycwc2pp0y0u8yw3k4"
+        );
+        assert_eq!(
+            config.contract_address,
+            parse_address("0xcDC557d454C09141d7bbb1E67c39BF500a348A5a").unwrap()
+        );
+        let trust_root = config.trust_root.as_ref().unwrap();
+        assert_eq!(trust_root.height, 26852547);
+        assert_eq!(
+            trust_root.runtime_id,
+            "000000000000000000000000000000000000000000000000a6d1e3ebf60dff6c"
+        );
+        assert_eq!(config.log_server_url, None);
+        assert_eq!(config.journal_path(), PathBuf::from("/data/journal.jsonl"));
+        assert_eq!(
+            config.log_fallback_path(),
+            PathBuf::from("/data/logs-undelivered.jsonl")
+        );
+        assert_eq!(config.approved_page_size, 10);
+        assert_eq!(config.gas, GasConfig::default());
+        assert_eq!(config.akave.bucket, "BaMaMe-Bucket");
+        assert_eq!(config.akave.result_bucket, "BaMaMe-Bucket");
+        assert_eq!(config.inline_result_bytes, 4096);
+        assert_eq!(config.akave.secret_key.expose(), "secret");
+        assert_eq!(config.policy, PolicyConfig::default());
+        assert_eq!(
+            config.limits,
+            LimitsConfig {
+                spill_dir: Some(PathBuf::from("/data/spill")),
+                ..LimitsConfig::default()
+            }
+        );
+    }
+
+    #[test]
+    fn requires_deployment_settings_outside_testnet() {
+        let mut vars = SECRETS.to_vec();
+        vars.push(("ENGINE_NETWORK", "mainnet"));
+        let err = load(&vars).unwrap_err().to_string();
+        assert!(err.contains("ENGINE_APP_ID: must be set"), "{}", err);
+        assert!(
+            err.contains("ENGINE_CONTRACT_ADDRESS: must be set"),
+            "{}",
+            err
+        );
+        assert!(
+            err.contains("ENGINE_TRUST_ROOT_HEIGHT: must be set"),
+            "{}",
+            err
+        );
+        assert!(
+            err.contains("ENGINE_TRUST_ROOT_HASH: must be set"),
+            "{}",
+            err
+        );
+        assert!(!err.contains("ENGINE_TRUST_ROOT_RUNTIME_ID"), "{}", err);
+
+        vars.push((
+            "ENGINE_APP_ID",
+            "rofl1qrhjgmyge7vuxl3h0d02jrvnl43jhwdwjyq6c943",
+        ));
+        vars.push((
+            "ENGINE_CONTRACT_ADDRESS",
+            "0x1111111111111111111111111111111111111111",
+        ));
+        vars.push(("ENGINE_TRUST_ROOT_HEIGHT", "1000"));
+        vars.push((
+            "ENGINE_TRUST_ROOT_HASH",
+            "1009FE4DD739C715294CA0BCC86B7449A2F5F7A147285D581AF319D75CCC9F75",
+        ));
+        let config = load(&vars).unwrap();
+        assert_eq!(config.network, Network::Mainnet);
+        let trust_root = config.trust_root.as_ref().unwrap();
+        assert_eq!(trust_root.height, 1000);
+        assert_eq!(
+            trust_root.hash,
+            "1009fe4dd739c715294ca0bcc86b7449a2f5f7a147285d581af319d75ccc9f75"
+        );
+        assert_eq!(
+            trust_root.runtime_id,
+            "000000000000000000000000000000000000000000000000f80306c9858e7279"
+        );
+        assert_eq!(
+            config.app_id,
+            "rofl1qrhjgmyge7vuxl3h0d02jrvnl43jhwdwjyq6c943"
+        );
+    }
+
+    #[test]
+    fn reports_every_problem() {
+        let err = load(&[
+            ("ENGINE_NETWORK", "devnet"),
+            ("ENGINE_APP_ID", "rofl1nope"),
+            ("ENGINE_CONTRACT_ADDRESS", "0x1234"),
+            ("ENGINE_LOG_SERVER_URL", "ftp://logs"),
+            ("ENGINE_DATA_DIR", "data"),
+            ("ENGINE_MAX_TX_GAS", "lots"),
+            ("ENGINE_APPROVED_PAGE_SIZE", "0"),
+            ("ENGINE_MIN_GROUP_SIZE", "0"),
+            ("ENGINE_DP_MECHANISM", "cauchy"),
+            ("ENGINE_QUERY_MAX_ROWS", "0"),
+        ])
+        .unwrap_err()
+        .to_string();
+
+        for key in [
+            "ENGINE_NETWORK",
+            "ENGINE_APP_ID",
+            "ENGINE_CONTRACT_ADDRESS",
+            "ENGINE_LOG_SERVER_URL",
+            "ENGINE_DATA_DIR",
+            "ENGINE_MAX_TX_GAS",
+            "ENGINE_APPROVED_PAGE_SIZE",
+            "ENGINE_MIN_GROUP_SIZE",
+            "ENGINE_DP_MECHANISM",
+            "ENGINE_QUERY_MAX_ROWS",
+            "AKAVE_ENDPOINT",
+            "AKAVE_ACCESS_KEY",
+            "AKAVE_SECRET_KEY",
+        ] {
+            assert!(
+                err.contains(&format!("{}: ", key)),
+                "{} missing in {}",
+                key,
+                err
+            );
+        }
+    }
+
+    #[test]
+    fn layers_file_and_environment() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("engine.toml");
+        fs::write(
+            &path,
+            r#"
+network = "localnet"
+app_id = "rofl1qrhjgmyge7vuxl3h0d02jrvnl43jhwdwjyq6c943"
+contract_address = "0x2222222222222222222222222222222222222222"
+data_dir = "/var/lib/engine"
+
+[gas]
+max_tx_gas = 5000000
+
+[akave]
+endpoint = "http://localhost:9000"
+bucket = "datasets"
+result_bucket = "results"
+
+[policy]
+pii_columns = ["name"]
+min_group_size = 3
+
+[policy.datasets.names_and_cities]
+min_group_size = 10
+epsilon_budget = 2.5
+
+[policy.privacy]
+mechanism = "gaussian"
+epsilon = 0.5
+
+[limits]
+timeout_secs = 30
+max_output_rows = 500
+"#,
+        )
+        .unwrap();
+
+        let config = load(&[
+            ("ENGINE_CONFIG", path.to_str().unwrap()),
+            (
+                "ENGINE_CONTRACT_ADDRESS",
+                "0x3333333333333333333333333333333333333333",
+            ),
+            ("ENGINE_LOG_SERVER_URL", ""),
+            ("ENGINE_PII_COLUMNS", "name, email,"),
+            ("ENGINE_DP_SUM_BOUND", "100"),
+            ("ENGINE_QUERY_MAX_ROWS", "1000"),
+            ("AKAVE_ACCESS_KEY", "access"),
+            ("AKAVE_SECRET_KEY", "secret"),
+        ])
+        .unwrap();
+
+        assert_eq!(config.network, Network::Localnet);
+        // Localnet runs without consensus verification.
+        assert_eq!(config.trust_root, None);
+        assert_eq!(
+            config.app_id,
+            "rofl1qrhjgmyge7vuxl3h0d02jrvnl43jhwdwjyq6c943"
+        );
+        // The environment wins over the file.
+        assert_eq!(config.contract_address, Address::repeat_byte(0x33));
+        assert_eq!(config.log_server_url, None);
+        assert_eq!(config.data_dir, PathBuf::from("/var/lib/engine"));
+        assert_eq!(config.gas.max_tx_gas, 5_000_000);
+        assert_eq!(config.gas.simulate_gas_limit, 1_000_000);
+        assert_eq!(config.akave.endpoint, "http://localhost:9000");
+        assert_eq!(config.akave.bucket, "datasets");
+        assert_eq!(config.akave.result_bucket, "results");
+        assert_eq!(config.policy.pii_columns, vec!["name", "email"]);
+        assert_eq!(config.policy.min_group_size(["visits"]), 3);
+        assert_eq!(
+            config.policy.min_group_size(["visits", "Names_And_Cities"]),
+            10
+        );
+        assert_eq!(config.policy.min_group_size([]), 3);
+        assert_eq!(
+            config.policy.privacy,
+            Some(PrivacyConfig {
+                mechanism: Mechanism::Gaussian,
+                epsilon: 0.5,
+                delta: 1e-6,
+                sum_bound: Some(100.0),
+                epsilon_budget: 10.0,
+            })
+        );
+        assert_eq!(config.policy.epsilon_budget("NAMES_AND_CITIES"), Some(2.5));
+        assert_eq!(config.policy.epsilon_budget("visits"), Some(10.0));
+        assert_eq!(
+            config.limits,
+            LimitsConfig {
+                timeout: Duration::from_secs(30),
+                spill_dir: Some(PathBuf::from("/var/lib/engine/spill")),
+                max_output_rows: 1000,
+                ..LimitsConfig::default()
+            }
+        );
+    }
+
+    #[test]
+    fn only_skips_trust_root_on_localnet() {
+        let mut vars = SECRETS.to_vec();
+        vars.extend([
+            ("ENGINE_NETWORK", "localnet"),
+            (
+                "ENGINE_APP_ID",
+                "rofl1qrhjgmyge7vuxl3h0d02jrvnl43jhwdwjyq6c943",
+            ),
+            (
+                "ENGINE_CONTRACT_ADDRESS",
+                "0x1111111111111111111111111111111111111111",
+            ),
+        ]);
+        assert_eq!(load(&vars).unwrap().trust_root, None);
+
+        // A partial trust root is never ignored.
+        vars.push(("ENGINE_TRUST_ROOT_HEIGHT", "5"));
+        let err = load(&vars).unwrap_err().to_string();
+        assert!(
+            err.contains("ENGINE_TRUST_ROOT_HASH: must be set"),
+            "{}",
+            err
+        );
+        assert!(
+            err.contains("ENGINE_TRUST_ROOT_CHAIN_CONTEXT: must be set"),
+            "{}",
+            err
+        );
+
+        // Testnet values can be overridden, but not with malformed ones.
+        let err = load(&[
+            SECRETS[0],
+            SECRETS[1],
+            SECRETS[2],
+            ("ENGINE_TRUST_ROOT_HASH", "95d1501f"),
+        ])
+        .unwrap_err()
+        .to_string();
+        assert!(
+            err.contains("ENGINE_TRUST_ROOT_HASH: \"95d1501f\" is not 32 hex-encoded bytes"),
+            "{}",
+            err
+        );
+        assert_eq!(err.matches("ENGINE_TRUST_ROOT_HASH").count(), 1, "{}", err);
+    }
+
+    #[test]
+    fn validates_privacy_settings() {
+        assert_eq!(load(&SECRETS).unwrap().policy.privacy, None);
+
+        let mut vars = SECRETS.to_vec();
+        vars.extend([
+            ("ENGINE_DP_MECHANISM", "Laplace"),
+            ("ENGINE_DP_EPSILON", "0"),
+            ("ENGINE_DP_DELTA", "1.5"),
+            ("ENGINE_DP_SUM_BOUND", "-1"),
+            ("ENGINE_DP_BUDGET", "lots"),
+        ]);
+        let err = load(&vars).unwrap_err().to_string();
+        for key in [
+            "ENGINE_DP_EPSILON",
+            "ENGINE_DP_DELTA",
+            "ENGINE_DP_SUM_BOUND",
+            "ENGINE_DP_BUDGET",
+        ] {
+            assert!(
+                err.contains(&format!("{}: ", key)),
+                "{} missing in {}",
+                key,
+                err
+            );
+        }
+
+        // Gaussian noise defaults to an epsilon it is calibrated for, and refuses larger ones.
+        let mut vars = SECRETS.to_vec();
+        vars.push(("ENGINE_DP_MECHANISM", "gaussian"));
+        let privacy = load(&vars).unwrap().policy.privacy.unwrap();
+        assert_eq!(privacy.epsilon, 0.5);
+        vars.push(("ENGINE_DP_EPSILON", "1"));
+        let err = load(&vars).unwrap_err().to_string();
+        assert!(
+            err.contains("ENGINE_DP_EPSILON: must be below 1 for the gaussian mechanism"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn rejects_unknown_file_keys() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("engine.toml");
+        fs::write(&path, "contract = \"0x00\"\n").unwrap();
+
+        let err = load(&[("ENGINE_CONFIG", path.to_str().unwrap())]).unwrap_err();
+        assert!(format!("{:#}", err).contains("unknown field"), "{:#}", err);
+    }
+
+    #[test]
+    fn keeps_secrets_out_of_debug_output() {
+        let config = load(&SECRETS).unwrap();
+        let debug = format!("{:?}", config);
+        assert!(!debug.contains("\"secret\""), "{}", debug);
+        assert!(debug.contains("<redacted>"));
+    }
+}
@@ -0,0 +1,109 @@
+//! How encrypted results reach the requester.
+//!
+//! `consumeProposal` stores its result string on chain, which gets expensive beyond a few KB.
+//! Small results are stored there as the base64 envelope itself. Larger ones are uploaded to
+//! Akave and the vault stores a JSON pointer to the object instead, with its size and SHA-256 so
+//! the requester can check what they download. The two are told apart by the first character: a
+//! base64 envelope never starts with `{`.
+use anyhow::{Result, anyhow};
+use ethabi::Address;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+/// Where the encrypted result of a proposal was uploaded.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "delivery", rename = "akave")]
+pub struct ResultPointer {
+    pub bucket: String,
+    pub key: String,
+    /// Size of the object in bytes.
+    pub size: u64,
+    /// Hex-encoded SHA-256 of the object.
+    pub sha256: String,
+}
+
+impl ResultPointer {
+    /// The pointer to `envelope` uploaded to `bucket` under `key`.
+    pub fn new(bucket: &str, key: &str, envelope: &[u8]) -> Self {
+        Self {
+            bucket: bucket.to_owned(),
+            key: key.to_owned(),
+            size: envelope.len() as u64,
+            sha256: hex::encode(Sha256::digest(envelope)),
+        }
+    }
+
+    /// Read the pointer from an on-chain result, or `None` if the result is an inline envelope.
+    pub fn parse(result: &str) -> Result<Option<Self>> {
+        if !result.trim_start().starts_with('{') {
+            return Ok(None);
+        }
+        serde_json::from_str(result)
+            .map(Some)
+            .map_err(|err| anyhow!("invalid result pointer: {}", err))
+    }
+
+    /// Check that a downloaded object is the one the pointer refers to.
+    pub fn verify(&self, object: &[u8]) -> Result<()> {
+        if object.len() as u64 != self.size {
+            return Err(anyhow!(
+                "{} has {} bytes, the pointer says {}",
+                self.key,
+                object.len(),
+                self.size
+            ));
+        }
+        let sha256 = hex::encode(Sha256::digest(object));
+        if !sha256.eq_ignore_ascii_case(&self.sha256) {
+            return Err(anyhow!(
+                "{} has SHA-256 {}, the pointer says {}",
+                self.key,
+                sha256,
+                self.sha256
+            ));
+        }
+        Ok(())
+    }
+
+    pub fn to_json(&self) -> Result<String> {
+        Ok(serde_json::to_string(self)?)
+    }
+}
+
+/// Object key of the result of a proposal of the given vault.
+pub fn result_key(vault: &Address, proposal_id: u64) -> String {
+    format!("results/0x{}/{}", hex::encode(vault), proposal_id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn points_at_verified_objects() {
+        let key = result_key(&Address::repeat_byte(0xab), 7);
+        assert_eq!(key, "results/0xabababababababababababababababababababab/7");
+
+        let pointer = ResultPointer::new("results", &key, b"envelope");
+        let json = pointer.to_json().unwrap();
+        assert_eq!(
+            json,
+            format!(
+                r#"{{"delivery":"akave","bucket":"results","key":"{}","size":8,"sha256":"{}"}}"#,
+                key,
+                hex::encode(Sha256::digest(b"envelope"))
+            )
+        );
+        assert_eq!(ResultPointer::parse(&json).unwrap(), Some(pointer.clone()));
+
+        pointer.verify(b"envelope").unwrap();
+        assert!(pointer.verify(b"envelopf").is_err());
+        assert!(pointer.verify(b"envelope!").is_err());
+    }
+
+    #[test]
+    fn tells_pointers_from_envelopes() {
+        assert_eq!(ResultPointer::parse("AQIDBAUGBwgJ+/8=").unwrap(), None);
+        assert!(ResultPointer::parse(r#"{"delivery":"ipfs","cid":"x"}"#).is_err());
+    }
+}
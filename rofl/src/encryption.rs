@@ -0,0 +1,362 @@
+//! Encryption of query results to the requester's public key.
+//!
+//! Results are sealed with an ECIES-style construction: an ephemeral key agreement with the
+//! requester's key (secp256k1 or X25519), HKDF-SHA256 to derive a key and ChaCha20-Poly1305 to
+//! encrypt. The envelope stored on-chain is the base64 encoding of
+//!
+//! ```text
+//! version (1) | scheme (1) | ephemeral public key | nonce (12) | ciphertext and tag
+//! ```
+//!
+//! where the ephemeral key is 33 bytes (compressed SEC1) for secp256k1 and 32 bytes for X25519.
+//! The header up to and including the ephemeral key is authenticated as associated data.
+use anyhow::{Result, anyhow};
+use base64::Engine as _;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use hkdf::Hkdf;
+use k256::elliptic_curve::sec1::ToEncodedPoint;
+use rand::RngCore;
+use rand::rngs::OsRng;
+use sha2::Sha256;
+
+/// Envelope format version.
+pub const VERSION: u8 = 1;
+
+const NONCE_LEN: usize = 12;
+
+/// Key agreement scheme used for an envelope.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Scheme {
+    Secp256k1 = 1,
+    X25519 = 2,
+}
+
+impl Scheme {
+    /// Length of the ephemeral public key stored in the envelope.
+    fn ephemeral_key_len(self) -> usize {
+        match self {
+            Scheme::Secp256k1 => 33,
+            Scheme::X25519 => 32,
+        }
+    }
+
+    fn info(self) -> &'static [u8] {
+        match self {
+            Scheme::Secp256k1 => b"vault-result/v1/secp256k1",
+            Scheme::X25519 => b"vault-result/v1/x25519",
+        }
+    }
+}
+
+impl TryFrom<u8> for Scheme {
+    type Error = anyhow::Error;
+
+    fn try_from(value: u8) -> Result<Self> {
+        match value {
+            1 => Ok(Scheme::Secp256k1),
+            2 => Ok(Scheme::X25519),
+            _ => Err(anyhow!("unknown encryption scheme {}", value)),
+        }
+    }
+}
+
+/// A requester's public key, as submitted with a proposal.
+#[derive(Debug, Clone, PartialEq)]
+pub enum RecipientKey {
+    Secp256k1(k256::PublicKey),
+    X25519(x25519_dalek::PublicKey),
+}
+
+impl RecipientKey {
+    /// Parse a public key given as hex (with or without `0x`) or base64. The key type follows
+    /// from the decoded length: 32 bytes is X25519, 33 or 65 bytes is a SEC1 encoded secp256k1
+    /// key and 64 bytes is an uncompressed secp256k1 key without the `0x04` prefix, as produced
+    /// by Ethereum tooling.
+    pub fn parse(public_key: &str) -> Result<Self> {
+        let bytes = decode_key(public_key)?;
+        match bytes.len() {
+            32 => {
+                let bytes: [u8; 32] = bytes.try_into().unwrap();
+                Ok(RecipientKey::X25519(bytes.into()))
+            }
+            33 | 65 => Ok(RecipientKey::Secp256k1(k256::PublicKey::from_sec1_bytes(
+                &bytes,
+            )?)),
+            64 => {
+                let mut sec1 = vec![0x04];
+                sec1.extend_from_slice(&bytes);
+                Ok(RecipientKey::Secp256k1(k256::PublicKey::from_sec1_bytes(
+                    &sec1,
+                )?))
+            }
+            len => Err(anyhow!("unsupported public key length {}", len)),
+        }
+    }
+
+    pub fn scheme(&self) -> Scheme {
+        match self {
+            RecipientKey::Secp256k1(_) => Scheme::Secp256k1,
+            RecipientKey::X25519(_) => Scheme::X25519,
+        }
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        match self {
+            RecipientKey::Secp256k1(key) => key.to_encoded_point(true).as_bytes().to_vec(),
+            RecipientKey::X25519(key) => key.as_bytes().to_vec(),
+        }
+    }
+}
+
+/// Encrypt `plaintext` to `public_key` and return the base64 envelope.
+pub fn encrypt(public_key: &str, plaintext: &[u8]) -> Result<String> {
+    let recipient = RecipientKey::parse(public_key)?;
+    let scheme = recipient.scheme();
+
+    let (ephemeral, shared) = match &recipient {
+        RecipientKey::Secp256k1(key) => {
+            let secret = k256::ecdh::EphemeralSecret::random(&mut OsRng);
+            let ephemeral = secret
+                .public_key()
+                .to_encoded_point(true)
+                .as_bytes()
+                .to_vec();
+            let shared = secret.diffie_hellman(key).raw_secret_bytes().to_vec();
+            (ephemeral, shared)
+        }
+        RecipientKey::X25519(key) => {
+            let secret = x25519_dalek::EphemeralSecret::random_from_rng(OsRng);
+            let ephemeral = x25519_dalek::PublicKey::from(&secret).as_bytes().to_vec();
+            let shared = secret.diffie_hellman(key);
+            if !shared.was_contributory() {
+                return Err(anyhow!("public key is a low order point"));
+            }
+            (ephemeral, shared.as_bytes().to_vec())
+        }
+    };
+
+    let mut envelope = vec![VERSION, scheme as u8];
+    envelope.extend_from_slice(&ephemeral);
+
+    let cipher = cipher(scheme, &shared, &ephemeral, &recipient.to_bytes())?;
+    let mut nonce = [0u8; NONCE_LEN];
+    OsRng.fill_bytes(&mut nonce);
+    let ciphertext = cipher
+        .encrypt(
+            Nonce::from_slice(&nonce),
+            Payload {
+                msg: plaintext,
+                aad: &envelope,
+            },
+        )
+        .map_err(|_| anyhow!("failed to encrypt result"))?;
+
+    envelope.extend_from_slice(&nonce);
+    envelope.extend_from_slice(&ciphertext);
+
+    Ok(BASE64.encode(envelope))
+}
+
+/// Decrypt an envelope produced by [`encrypt`] with the requester's 32 byte secret key.
+pub fn decrypt(secret_key: &[u8], envelope: &str) -> Result<Vec<u8>> {
+    let envelope = BASE64.decode(envelope.trim())?;
+    match envelope.first() {
+        Some(&VERSION) => {}
+        Some(version) => return Err(anyhow!("unsupported envelope version {}", version)),
+        None => return Err(anyhow!("empty envelope")),
+    }
+    let scheme = envelope
+        .get(1)
+        .ok_or_else(|| anyhow!("truncated envelope"))?;
+    let scheme = Scheme::try_from(*scheme)?;
+
+    let header_len = 2 + scheme.ephemeral_key_len();
+    if envelope.len() < header_len + NONCE_LEN {
+        return Err(anyhow!("truncated envelope"));
+    }
+    let (header, rest) = envelope.split_at(header_len);
+    let ephemeral = &header[2..];
+    let (nonce, ciphertext) = rest.split_at(NONCE_LEN);
+
+    let (recipient, shared) = match scheme {
+        Scheme::Secp256k1 => {
+            let secret = k256::SecretKey::from_slice(secret_key)?;
+            let ephemeral = k256::PublicKey::from_sec1_bytes(ephemeral)?;
+            let shared =
+                k256::ecdh::diffie_hellman(secret.to_nonzero_scalar(), ephemeral.as_affine());
+            (
+                RecipientKey::Secp256k1(secret.public_key()),
+                shared.raw_secret_bytes().to_vec(),
+            )
+        }
+        Scheme::X25519 => {
+            let secret: [u8; 32] = secret_key
+                .try_into()
+                .map_err(|_| anyhow!("X25519 secret key must be 32 bytes"))?;
+            let secret = x25519_dalek::StaticSecret::from(secret);
+            let ephemeral: [u8; 32] = ephemeral.try_into()?;
+            let shared = secret.diffie_hellman(&ephemeral.into());
+            (
+                RecipientKey::X25519((&secret).into()),
+                shared.as_bytes().to_vec(),
+            )
+        }
+    };
+
+    let cipher = cipher(scheme, &shared, ephemeral, &recipient.to_bytes())?;
+    cipher
+        .decrypt(
+            Nonce::from_slice(nonce),
+            Payload {
+                msg: ciphertext,
+                aad: header,
+            },
+        )
+        .map_err(|_| anyhow!("failed to decrypt result: wrong key or corrupted envelope"))
+}
+
+/// Derive the AEAD key from the shared secret. Both public keys are mixed into the salt so the
+/// key is bound to this exchange.
+fn cipher(
+    scheme: Scheme,
+    shared: &[u8],
+    ephemeral: &[u8],
+    recipient: &[u8],
+) -> Result<ChaCha20Poly1305> {
+    let salt = [ephemeral, recipient].concat();
+    let mut key = [0u8; 32];
+    Hkdf::<Sha256>::new(Some(&salt), shared)
+        .expand(scheme.info(), &mut key)
+        .map_err(|_| anyhow!("failed to derive encryption key"))?;
+
+    Ok(ChaCha20Poly1305::new(Key::from_slice(&key)))
+}
+
+/// Decode a key given as `0x`-prefixed hex, bare hex or base64.
+pub fn decode_key(key: &str) -> Result<Vec<u8>> {
+    let key = key.trim();
+    if let Some(hex) = key.strip_prefix("0x") {
+        return Ok(hex::decode(hex)?);
+    }
+    if key.len().is_multiple_of(2) && key.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return Ok(hex::decode(key)?);
+    }
+
+    BASE64
+        .decode(key)
+        .map_err(|_| anyhow!("public key is neither hex nor base64"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn secp256k1_key() -> (k256::SecretKey, k256::PublicKey) {
+        let secret = k256::SecretKey::random(&mut OsRng);
+        let public = secret.public_key();
+        (secret, public)
+    }
+
+    #[test]
+    fn detects_key_type_from_format() {
+        let (_, public) = secp256k1_key();
+        let compressed = public.to_encoded_point(true);
+        let uncompressed = public.to_encoded_point(false);
+
+        let keys = [
+            format!("0x{}", hex::encode(compressed.as_bytes())),
+            hex::encode(uncompressed.as_bytes()),
+            format!("0x{}", hex::encode(&uncompressed.as_bytes()[1..])),
+            BASE64.encode(compressed.as_bytes()),
+        ];
+        for key in keys {
+            assert_eq!(
+                RecipientKey::parse(&key).unwrap(),
+                RecipientKey::Secp256k1(public)
+            );
+        }
+
+        let x25519 = [7u8; 32];
+        assert_eq!(
+            RecipientKey::parse(&BASE64.encode(x25519)).unwrap(),
+            RecipientKey::X25519(x25519.into())
+        );
+        assert_eq!(
+            RecipientKey::parse(&format!("0x{}", hex::encode(x25519)))
+                .unwrap()
+                .scheme(),
+            Scheme::X25519
+        );
+
+        assert!(RecipientKey::parse("0x1234").is_err());
+        assert!(RecipientKey::parse("not a key!").is_err());
+    }
+
+    #[test]
+    fn round_trips_secp256k1() {
+        let (secret, public) = secp256k1_key();
+        let key = hex::encode(public.to_encoded_point(false).as_bytes());
+
+        let envelope = encrypt(&key, b"{\"rows\":[[1]]}").unwrap();
+        let raw = BASE64.decode(&envelope).unwrap();
+        assert_eq!(raw[..2], [VERSION, Scheme::Secp256k1 as u8]);
+
+        let plaintext = decrypt(&secret.to_bytes(), &envelope).unwrap();
+        assert_eq!(plaintext, b"{\"rows\":[[1]]}");
+    }
+
+    #[test]
+    fn round_trips_x25519() {
+        let secret = x25519_dalek::StaticSecret::random_from_rng(OsRng);
+        let public = x25519_dalek::PublicKey::from(&secret);
+
+        let envelope = encrypt(&BASE64.encode(public.as_bytes()), b"result").unwrap();
+        let raw = BASE64.decode(&envelope).unwrap();
+        assert_eq!(raw[..2], [VERSION, Scheme::X25519 as u8]);
+
+        assert_eq!(decrypt(secret.as_bytes(), &envelope).unwrap(), b"result");
+    }
+
+    #[test]
+    fn envelopes_are_randomized() {
+        let (_, public) = secp256k1_key();
+        let key = hex::encode(public.to_encoded_point(true).as_bytes());
+
+        assert_ne!(
+            encrypt(&key, b"result").unwrap(),
+            encrypt(&key, b"result").unwrap()
+        );
+    }
+
+    #[test]
+    fn rejects_wrong_key_and_tampering() {
+        let (secret, public) = secp256k1_key();
+        let (other, _) = secp256k1_key();
+        let key = hex::encode(public.to_encoded_point(true).as_bytes());
+        let envelope = encrypt(&key, b"result").unwrap();
+
+        assert!(decrypt(&other.to_bytes(), &envelope).is_err());
+
+        let mut raw = BASE64.decode(&envelope).unwrap();
+        let last = raw.len() - 1;
+        raw[last] ^= 1;
+        assert!(decrypt(&secret.to_bytes(), &BASE64.encode(&raw)).is_err());
+
+        // The header is authenticated, so changing the scheme or version fails too.
+        raw[last] ^= 1;
+        raw[1] = Scheme::X25519 as u8;
+        assert!(decrypt(&secret.to_bytes(), &BASE64.encode(&raw)).is_err());
+        raw[1] = Scheme::Secp256k1 as u8;
+        raw[0] = 2;
+        assert!(decrypt(&secret.to_bytes(), &BASE64.encode(&raw)).is_err());
+        raw[0] = VERSION;
+        assert_eq!(
+            decrypt(&secret.to_bytes(), &BASE64.encode(&raw)).unwrap(),
+            b"result"
+        );
+
+        assert!(decrypt(&secret.to_bytes(), &BASE64.encode([VERSION])).is_err());
+    }
+}
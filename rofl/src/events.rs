@@ -0,0 +1,515 @@
+//! Decoding of the `Vault` contract events and the indexer state the engine derives from them.
+use std::collections::BTreeSet;
+use std::ops::RangeInclusive;
+
+use anyhow::{Result, anyhow};
+use ethabi::{Address, Event, EventParam, Hash, ParamType, RawLog, Token, Uint};
+
+/// Maximum number of rounds covered by a single `evm.GetLogs` query.
+const MAX_LOG_RANGE: u64 = 100;
+/// Number of rounds after which the indexer falls back to a full rescan of the approved
+/// proposals, in case a log was missed.
+const RESYNC_INTERVAL: u64 = 600;
+
+/// `ProposalSubmitted(uint256 indexed proposalId, address indexed requester, string sqlQuery)`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProposalSubmitted {
+    pub proposal_id: u64,
+    pub requester: Address,
+    pub sql_query: String,
+}
+
+/// `ProposalApproved(uint256 indexed proposalId, address indexed approver)`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProposalApproved {
+    pub proposal_id: u64,
+    pub approver: Address,
+}
+
+/// `ProposalRejected(uint256 indexed proposalId, address indexed rejecter)`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProposalRejected {
+    pub proposal_id: u64,
+    pub rejecter: Address,
+}
+
+/// `QueryCompleted(uint256 indexed proposalId, address indexed requester)`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QueryCompleted {
+    pub proposal_id: u64,
+    pub requester: Address,
+}
+
+/// `ProposalExpired(uint256 indexed proposalId)`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProposalExpired {
+    pub proposal_id: u64,
+}
+
+/// `GovernanceProposalCreated(uint256 indexed vaultProposalId, uint256 indexed governanceProposalId)`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GovernanceProposalCreated {
+    pub vault_proposal_id: u64,
+    pub governance_proposal_id: Uint,
+}
+
+/// A decoded event emitted by the `Vault` contract.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum VaultEvent {
+    ProposalSubmitted(ProposalSubmitted),
+    ProposalApproved(ProposalApproved),
+    ProposalRejected(ProposalRejected),
+    QueryCompleted(QueryCompleted),
+    ProposalExpired(ProposalExpired),
+    GovernanceProposalCreated(GovernanceProposalCreated),
+}
+
+/// A raw log as returned by `evm.GetLogs`.
+#[derive(Debug, Clone)]
+pub struct Log {
+    pub block_number: u64,
+    pub topics: Vec<Hash>,
+    pub data: Vec<u8>,
+}
+
+fn event(name: &str, inputs: &[(&str, ParamType, bool)]) -> Event {
+    Event {
+        name: name.to_owned(),
+        inputs: inputs
+            .iter()
+            .map(|(name, kind, indexed)| EventParam {
+                name: (*name).to_owned(),
+                kind: kind.clone(),
+                indexed: *indexed,
+            })
+            .collect(),
+        anonymous: false,
+    }
+}
+
+pub fn proposal_submitted_event() -> Event {
+    event(
+        "ProposalSubmitted",
+        &[
+            ("proposalId", ParamType::Uint(256), true),
+            ("requester", ParamType::Address, true),
+            ("sqlQuery", ParamType::String, false),
+        ],
+    )
+}
+
+pub fn proposal_approved_event() -> Event {
+    event(
+        "ProposalApproved",
+        &[
+            ("proposalId", ParamType::Uint(256), true),
+            ("approver", ParamType::Address, true),
+        ],
+    )
+}
+
+pub fn proposal_rejected_event() -> Event {
+    event(
+        "ProposalRejected",
+        &[
+            ("proposalId", ParamType::Uint(256), true),
+            ("rejecter", ParamType::Address, true),
+        ],
+    )
+}
+
+pub fn query_completed_event() -> Event {
+    event(
+        "QueryCompleted",
+        &[
+            ("proposalId", ParamType::Uint(256), true),
+            ("requester", ParamType::Address, true),
+        ],
+    )
+}
+
+pub fn proposal_expired_event() -> Event {
+    event(
+        "ProposalExpired",
+        &[("proposalId", ParamType::Uint(256), true)],
+    )
+}
+
+pub fn governance_proposal_created_event() -> Event {
+    event(
+        "GovernanceProposalCreated",
+        &[
+            ("vaultProposalId", ParamType::Uint(256), true),
+            ("governanceProposalId", ParamType::Uint(256), true),
+        ],
+    )
+}
+
+/// Topic hashes of every event the indexer decodes, for use as a `topics[0]` log filter.
+pub fn topics() -> Vec<Hash> {
+    [
+        proposal_submitted_event(),
+        proposal_approved_event(),
+        proposal_rejected_event(),
+        query_completed_event(),
+        proposal_expired_event(),
+        governance_proposal_created_event(),
+    ]
+    .iter()
+    .map(Event::signature)
+    .collect()
+}
+
+/// Builds a typed event from the decoded log params, in declaration order.
+type Decoder = fn(Vec<Token>) -> Result<VaultEvent>;
+
+/// Decode a vault log. Returns `None` for events the indexer does not track (e.g. `Paused`).
+pub fn decode_log(log: &Log) -> Result<Option<VaultEvent>> {
+    let Some(topic) = log.topics.first() else {
+        return Ok(None);
+    };
+
+    let decoders: [(Event, Decoder); 6] = [
+        (proposal_submitted_event(), |values| {
+            let [proposal_id, requester, sql_query] = into_values(values)?;
+            Ok(VaultEvent::ProposalSubmitted(ProposalSubmitted {
+                proposal_id: into_u64(proposal_id)?,
+                requester: into_address(requester)?,
+                sql_query: sql_query
+                    .into_string()
+                    .ok_or_else(|| anyhow!("expected a string value"))?,
+            }))
+        }),
+        (proposal_approved_event(), |values| {
+            let [proposal_id, approver] = into_values(values)?;
+            Ok(VaultEvent::ProposalApproved(ProposalApproved {
+                proposal_id: into_u64(proposal_id)?,
+                approver: into_address(approver)?,
+            }))
+        }),
+        (proposal_rejected_event(), |values| {
+            let [proposal_id, rejecter] = into_values(values)?;
+            Ok(VaultEvent::ProposalRejected(ProposalRejected {
+                proposal_id: into_u64(proposal_id)?,
+                rejecter: into_address(rejecter)?,
+            }))
+        }),
+        (query_completed_event(), |values| {
+            let [proposal_id, requester] = into_values(values)?;
+            Ok(VaultEvent::QueryCompleted(QueryCompleted {
+                proposal_id: into_u64(proposal_id)?,
+                requester: into_address(requester)?,
+            }))
+        }),
+        (proposal_expired_event(), |values| {
+            let [proposal_id] = into_values(values)?;
+            Ok(VaultEvent::ProposalExpired(ProposalExpired {
+                proposal_id: into_u64(proposal_id)?,
+            }))
+        }),
+        (governance_proposal_created_event(), |values| {
+            let [vault_proposal_id, governance_proposal_id] = into_values(values)?;
+            Ok(VaultEvent::GovernanceProposalCreated(
+                GovernanceProposalCreated {
+                    vault_proposal_id: into_u64(vault_proposal_id)?,
+                    governance_proposal_id: governance_proposal_id
+                        .into_uint()
+                        .ok_or_else(|| anyhow!("expected a uint value"))?,
+                },
+            ))
+        }),
+    ];
+
+    for (event, decode) in decoders {
+        if event.signature() != *topic {
+            continue;
+        }
+
+        let parsed = event.parse_log(RawLog {
+            topics: log.topics.clone(),
+            data: log.data.clone(),
+        })?;
+        let values = parsed.params.into_iter().map(|p| p.value).collect();
+        return decode(values).map(Some);
+    }
+
+    Ok(None)
+}
+
+fn into_values<const N: usize>(values: Vec<Token>) -> Result<[Token; N]> {
+    values
+        .try_into()
+        .map_err(|values: Vec<Token>| anyhow!("expected {} event params, got {}", N, values.len()))
+}
+
+fn into_u64(token: Token) -> Result<u64> {
+    let value = token
+        .into_uint()
+        .ok_or_else(|| anyhow!("expected a uint value"))?;
+    u64::try_from(value).map_err(|_| anyhow!("uint value {} does not fit in u64", value))
+}
+
+fn into_address(token: Token) -> Result<Address> {
+    token
+        .into_address()
+        .ok_or_else(|| anyhow!("expected an address value"))
+}
+
+/// Tracks which rounds have been indexed and which approved proposals still await execution.
+#[derive(Debug, Default)]
+pub struct EventIndexer {
+    /// Last round whose logs have been applied.
+    cursor: Option<u64>,
+    /// Round of the last full rescan of the approved proposals.
+    synced_at: u64,
+    /// Approved proposals that have not been consumed yet.
+    pending: BTreeSet<u64>,
+}
+
+impl EventIndexer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Range of rounds whose logs should be fetched next, or `None` if the indexer needs a full
+    /// rescan of the approved proposals first.
+    pub fn next_range(&self, round: u64) -> Option<RangeInclusive<u64>> {
+        let cursor = self.cursor?;
+        if round.saturating_sub(self.synced_at) >= RESYNC_INTERVAL {
+            return None;
+        }
+
+        let from = cursor + 1;
+        if from > round {
+            return Some(from..=cursor);
+        }
+        Some(from..=round.min(cursor + MAX_LOG_RANGE))
+    }
+
+    /// Replace the pending set with the result of a full rescan done at `round`.
+    pub fn resync(&mut self, round: u64, approved: impl IntoIterator<Item = u64>) {
+        self.pending = approved.into_iter().collect();
+        self.cursor = Some(round);
+        self.synced_at = round;
+    }
+
+    /// Apply the events of an indexed range. The events must be in chain order.
+    pub fn apply(&mut self, range: &RangeInclusive<u64>, events: &[VaultEvent]) {
+        for event in events {
+            match event {
+                VaultEvent::ProposalApproved(e) => {
+                    self.pending.insert(e.proposal_id);
+                }
+                VaultEvent::QueryCompleted(QueryCompleted { proposal_id, .. })
+                | VaultEvent::ProposalExpired(ProposalExpired { proposal_id })
+                | VaultEvent::ProposalRejected(ProposalRejected { proposal_id, .. }) => {
+                    self.pending.remove(proposal_id);
+                }
+                VaultEvent::ProposalSubmitted(_) | VaultEvent::GovernanceProposalCreated(_) => {}
+            }
+        }
+
+        if !range.is_empty() {
+            self.cursor = Some(*range.end());
+        }
+    }
+
+    /// Forget the cursor, forcing a full rescan on the next round.
+    pub fn reset(&mut self) {
+        self.cursor = None;
+    }
+
+    /// Approved proposals that still await execution.
+    pub fn pending(&self) -> Vec<u64> {
+        self.pending.iter().copied().collect()
+    }
+
+    /// Stop tracking a proposal, e.g. after it was consumed or found to be no longer approved.
+    pub fn remove(&mut self, proposal_id: u64) {
+        self.pending.remove(&proposal_id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ethabi::Contract;
+
+    fn vault_abi() -> Contract {
+        let artifact: serde_json::Value =
+            serde_json::from_str(include_str!("../../rofl-bun/abis/Vault.json")).unwrap();
+        serde_json::from_value(artifact["abi"].clone()).unwrap()
+    }
+
+    fn topic_uint(value: u64) -> Hash {
+        Hash::from_slice(&ethabi::encode(&[Token::Uint(value.into())]))
+    }
+
+    fn topic_address(address: Address) -> Hash {
+        Hash::from_slice(&ethabi::encode(&[Token::Address(address)]))
+    }
+
+    #[test]
+    fn events_match_abi() {
+        let abi = vault_abi();
+        for ours in [
+            proposal_submitted_event(),
+            proposal_approved_event(),
+            proposal_rejected_event(),
+            query_completed_event(),
+            proposal_expired_event(),
+            governance_proposal_created_event(),
+        ] {
+            let theirs = abi.event(&ours.name).unwrap();
+            assert_eq!(&ours, theirs, "{}", ours.name);
+        }
+    }
+
+    #[test]
+    fn decodes_proposal_submitted() {
+        let requester = Address::repeat_byte(0xaa);
+        let log = Log {
+            block_number: 10,
+            topics: vec![
+                proposal_submitted_event().signature(),
+                topic_uint(3),
+                topic_address(requester),
+            ],
+            data: ethabi::encode(&[Token::String("SELECT 1".into())]),
+        };
+
+        assert_eq!(
+            decode_log(&log).unwrap(),
+            Some(VaultEvent::ProposalSubmitted(ProposalSubmitted {
+                proposal_id: 3,
+                requester,
+                sql_query: "SELECT 1".into(),
+            }))
+        );
+    }
+
+    #[test]
+    fn decodes_indexed_only_events() {
+        let approver = Address::repeat_byte(0xbb);
+        let log = Log {
+            block_number: 11,
+            topics: vec![
+                proposal_approved_event().signature(),
+                topic_uint(3),
+                topic_address(approver),
+            ],
+            data: vec![],
+        };
+        assert_eq!(
+            decode_log(&log).unwrap(),
+            Some(VaultEvent::ProposalApproved(ProposalApproved {
+                proposal_id: 3,
+                approver,
+            }))
+        );
+
+        let log = Log {
+            block_number: 12,
+            topics: vec![proposal_expired_event().signature(), topic_uint(4)],
+            data: vec![],
+        };
+        assert_eq!(
+            decode_log(&log).unwrap(),
+            Some(VaultEvent::ProposalExpired(ProposalExpired {
+                proposal_id: 4
+            }))
+        );
+
+        let log = Log {
+            block_number: 13,
+            topics: vec![
+                governance_proposal_created_event().signature(),
+                topic_uint(4),
+                Hash::repeat_byte(0xff),
+            ],
+            data: vec![],
+        };
+        assert_eq!(
+            decode_log(&log).unwrap(),
+            Some(VaultEvent::GovernanceProposalCreated(
+                GovernanceProposalCreated {
+                    vault_proposal_id: 4,
+                    governance_proposal_id: Uint::MAX,
+                }
+            ))
+        );
+    }
+
+    #[test]
+    fn ignores_untracked_events() {
+        let abi = vault_abi();
+        let log = Log {
+            block_number: 14,
+            topics: vec![abi.event("Paused").unwrap().signature()],
+            data: ethabi::encode(&[Token::Address(Address::zero())]),
+        };
+        assert_eq!(decode_log(&log).unwrap(), None);
+    }
+
+    #[test]
+    fn rejects_malformed_logs() {
+        let log = Log {
+            block_number: 15,
+            topics: vec![proposal_approved_event().signature(), topic_uint(3)],
+            data: vec![],
+        };
+        assert!(decode_log(&log).is_err());
+    }
+
+    #[test]
+    fn indexer_tracks_pending_proposals() {
+        let mut indexer = EventIndexer::new();
+        assert_eq!(indexer.next_range(100), None);
+
+        indexer.resync(100, [1, 2]);
+        assert_eq!(indexer.pending(), vec![1, 2]);
+
+        let range = indexer.next_range(103).unwrap();
+        assert_eq!(range, 101..=103);
+        indexer.apply(
+            &range,
+            &[
+                VaultEvent::ProposalApproved(ProposalApproved {
+                    proposal_id: 3,
+                    approver: Address::zero(),
+                }),
+                VaultEvent::QueryCompleted(QueryCompleted {
+                    proposal_id: 1,
+                    requester: Address::zero(),
+                }),
+                VaultEvent::ProposalExpired(ProposalExpired { proposal_id: 2 }),
+            ],
+        );
+        assert_eq!(indexer.pending(), vec![3]);
+
+        // Nothing new to index within the same round.
+        assert!(indexer.next_range(103).unwrap().is_empty());
+
+        indexer.remove(3);
+        assert!(indexer.pending().is_empty());
+    }
+
+    #[test]
+    fn indexer_bounds_ranges_and_resyncs() {
+        let mut indexer = EventIndexer::new();
+        indexer.resync(0, []);
+
+        let range = indexer.next_range(500).unwrap();
+        assert_eq!(range, 1..=MAX_LOG_RANGE);
+        indexer.apply(&range, &[]);
+        assert_eq!(
+            indexer.next_range(500).unwrap(),
+            MAX_LOG_RANGE + 1..=2 * MAX_LOG_RANGE
+        );
+
+        assert_eq!(indexer.next_range(RESYNC_INTERVAL), None);
+
+        indexer.reset();
+        assert_eq!(indexer.next_range(1), None);
+    }
+}
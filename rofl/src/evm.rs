@@ -0,0 +1,453 @@
+//! An in-process EVM running the compiled `Vault` and its governance contracts, so tests drive
+//! the engine against the contract code itself rather than the scripted vault in `chain::fake`.
+//!
+//! The bytecode comes from the hardhat-deploy artifacts of the Sapphire Testnet deployment. The
+//! tests below check that those artifacts were compiled from the current contract sources, so a
+//! contract change fails the engine's tests until the artifacts are regenerated.
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use anyhow::{Context as _, Result, anyhow};
+use async_trait::async_trait;
+use ethabi::{ParamType, Token};
+use revm::context::result::{ExecutionResult, Output};
+use revm::context::{Context, TxEnv};
+use revm::database::{CacheDB, EmptyDB};
+use revm::handler::{MainnetContext, MainnetEvm};
+use revm::primitives::{Address, Bytes, TxKind, U256};
+use revm::{Database, ExecuteCommitEvm, ExecuteEvm, MainBuilder, MainContext};
+
+use crate::chain::{ChainBackend, TxOutcome};
+use crate::events::Log;
+use crate::revert;
+use crate::submitter::TxFailed;
+use crate::vault::{self, CompletedQuery};
+
+/// Seconds between blocks.
+const BLOCK_SECONDS: u64 = 6;
+/// Minimum delay of the timelock, as in the `deploy-vault` task.
+const TIMELOCK_DELAY: u64 = 60;
+/// Error code of a reverted call in the EVM module.
+const REVERTED_CODE: u32 = 8;
+
+/// Directory of the hardhat-deploy artifacts the bytecode is taken from.
+fn artifacts_dir() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("../scaffold-eth/packages/hardhat/deployments/sapphire-testnet")
+}
+
+fn artifact(contract: &str) -> Result<serde_json::Value> {
+    let path = artifacts_dir().join(format!("{}.json", contract));
+    let artifact = std::fs::read_to_string(&path)
+        .with_context(|| format!("failed to read {}", path.display()))?;
+    Ok(serde_json::from_str(&artifact)?)
+}
+
+/// Creation bytecode of a contract.
+fn bytecode(contract: &str) -> Result<Vec<u8>> {
+    let artifact = artifact(contract)?;
+    let bytecode = artifact["bytecode"]
+        .as_str()
+        .ok_or_else(|| anyhow!("artifact of {} has no bytecode", contract))?;
+    Ok(hex::decode(bytecode.trim_start_matches("0x"))?)
+}
+
+fn to_evm(address: ethabi::Address) -> Address {
+    Address::from(address.0)
+}
+
+/// A chain holding the vault and its governance, deployed the way the `deploy-vault` task does.
+/// Every transaction is mined in a block of its own.
+pub struct EvmChain {
+    state: Mutex<State>,
+    /// Deployer and owner of every contract.
+    pub owner: ethabi::Address,
+    /// Account the engine calls the vault from.
+    pub engine: ethabi::Address,
+    pub vault: ethabi::Address,
+    pub governor: ethabi::Address,
+    /// Plain account that approves proposals in place of the governor.
+    pub approver: ethabi::Address,
+}
+
+/// Accounts and blocks of the chain. The EVM itself is not `Send`, so one is built around the
+/// database for every execution.
+struct State {
+    db: CacheDB<EmptyDB>,
+    block_number: u64,
+    timestamp: u64,
+    /// Logs emitted by every contract, with the address of the contract.
+    logs: Vec<(ethabi::Address, Log)>,
+}
+
+impl EvmChain {
+    /// Deploy the governance contracts and a vault bound to `app_id`.
+    pub fn deploy(app_id: [u8; 21]) -> Result<Self> {
+        let owner = ethabi::Address::repeat_byte(0x01);
+        let mut state = State {
+            db: CacheDB::default(),
+            block_number: 1,
+            timestamp: 1_700_000_000,
+            logs: Vec::new(),
+        };
+
+        let token = state.create(owner, "VaultToken", &[Token::Address(owner)])?;
+        let timelock = state.create(
+            owner,
+            "VaultTimelock",
+            &[
+                Token::Uint(TIMELOCK_DELAY.into()),
+                Token::Array(vec![Token::Address(owner)]),
+                Token::Array(vec![Token::Address(owner)]),
+                Token::Address(owner),
+            ],
+        )?;
+        let governor = state.create(
+            owner,
+            "VaultGovernor",
+            &[
+                Token::Address(token),
+                Token::Address(timelock),
+                Token::Address(owner),
+            ],
+        )?;
+        let vault = state.create(
+            owner,
+            "Vault",
+            &[Token::FixedBytes(app_id.to_vec()), Token::Address(governor)],
+        )?;
+
+        // The vault opens governance proposals itself, so it has to be allowed to propose.
+        let data = [
+            ethabi::short_signature("addAuthorizedVoter", &[ParamType::Address]).to_vec(),
+            ethabi::encode(&[Token::Address(vault)]),
+        ]
+        .concat();
+        state.send(owner, governor, data)?;
+
+        Ok(Self {
+            state: Mutex::new(state),
+            owner,
+            engine: ethabi::Address::repeat_byte(0xe0),
+            vault,
+            governor,
+            approver: ethabi::Address::repeat_byte(0xa0),
+        })
+    }
+
+    /// Submit a query to the vault as `requester`, returning the ID of the new proposal.
+    pub fn propose_query(
+        &self,
+        requester: ethabi::Address,
+        sql_query: &str,
+        public_key: &str,
+    ) -> Result<u64> {
+        let data = vault::encode_propose_query(sql_query, public_key);
+        let output = self
+            .state
+            .lock()
+            .unwrap()
+            .send(requester, self.vault, data)?;
+        vault::decode_propose_query(&output)
+    }
+
+    /// Open the governance proposal for a query and approve it.
+    ///
+    /// `VaultGovernor` executes proposals through its timelock, which the vault does not accept
+    /// as the approver. The owner hands the governor role to a plain account for the approving
+    /// call instead, and back to `VaultGovernor` afterwards as the vault opens governance
+    /// proposals through it.
+    pub fn approve(&self, proposal_id: u64) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        state.send(
+            self.owner,
+            self.vault,
+            vault::encode_create_governance_proposal(proposal_id),
+        )?;
+        state.send(
+            self.owner,
+            self.vault,
+            vault::encode_set_governor(self.approver),
+        )?;
+        state.send(
+            self.approver,
+            self.vault,
+            vault::encode_approve_proposal(proposal_id),
+        )?;
+        state.send(
+            self.owner,
+            self.vault,
+            vault::encode_set_governor(self.governor),
+        )?;
+        Ok(())
+    }
+
+    /// Mine `blocks` empty blocks.
+    pub fn advance(&self, blocks: u64) {
+        self.state.lock().unwrap().advance(blocks);
+    }
+
+    /// The result the vault stored for a proposal.
+    pub fn completed_query(&self, proposal_id: u64) -> Result<CompletedQuery> {
+        let data = vault::encode_get_completed_query(proposal_id);
+        let output = self
+            .state
+            .lock()
+            .unwrap()
+            .call(self.engine, self.vault, data)?;
+        vault::decode_get_completed_query(&output)
+    }
+}
+
+impl State {
+    fn advance(&mut self, blocks: u64) {
+        self.block_number += blocks;
+        self.timestamp += blocks * BLOCK_SECONDS;
+    }
+
+    /// Run `execute` on an EVM at the current block.
+    fn with_evm<T>(
+        &mut self,
+        execute: impl FnOnce(&mut MainnetEvm<MainnetContext<&mut CacheDB<EmptyDB>>>) -> T,
+    ) -> T {
+        let (number, timestamp) = (self.block_number, self.timestamp);
+        let mut evm = Context::mainnet()
+            .with_db(&mut self.db)
+            .modify_block_chained(|block| {
+                block.number = U256::from(number);
+                block.timestamp = U256::from(timestamp);
+            })
+            .build_mainnet();
+        execute(&mut evm)
+    }
+
+    fn tx(&mut self, from: ethabi::Address, kind: TxKind, data: Vec<u8>) -> Result<TxEnv> {
+        let caller = to_evm(from);
+        let nonce = self
+            .db
+            .basic(caller)?
+            .map(|account| account.nonce)
+            .unwrap_or_default();
+        TxEnv::builder()
+            .caller(caller)
+            .kind(kind)
+            .data(Bytes::from(data))
+            .nonce(nonce)
+            .build()
+            .map_err(|err| anyhow!("invalid transaction: {:?}", err))
+    }
+
+    /// Deploy a contract from its artifact, returning its address.
+    fn create(
+        &mut self,
+        from: ethabi::Address,
+        contract: &str,
+        args: &[Token],
+    ) -> Result<ethabi::Address> {
+        let data = [bytecode(contract)?, ethabi::encode(args)].concat();
+        let tx = self.tx(from, TxKind::Create, data)?;
+        self.advance(1);
+        match self.with_evm(|evm| evm.transact_commit(tx))? {
+            ExecutionResult::Success {
+                output: Output::Create(_, Some(address)),
+                ..
+            } => Ok(ethabi::Address::from(address.into_array())),
+            result => Err(anyhow!("failed to deploy {}: {:?}", contract, result)),
+        }
+    }
+
+    /// Mine a transaction calling `to` and return its output, or the reason it failed.
+    fn send(
+        &mut self,
+        from: ethabi::Address,
+        to: ethabi::Address,
+        data: Vec<u8>,
+    ) -> Result<Vec<u8>> {
+        let tx = self.tx(from, TxKind::Call(to_evm(to)), data)?;
+        self.advance(1);
+        let result = self.with_evm(|evm| evm.transact_commit(tx))?;
+        self.record_logs(&result);
+        output(result)
+    }
+
+    /// Run a call without committing its effects.
+    fn call(
+        &mut self,
+        from: ethabi::Address,
+        to: ethabi::Address,
+        data: Vec<u8>,
+    ) -> Result<Vec<u8>> {
+        let tx = self.tx(from, TxKind::Call(to_evm(to)), data)?;
+        let result = self.with_evm(|evm| evm.transact(tx))?;
+        output(result.result)
+    }
+
+    fn record_logs(&mut self, result: &ExecutionResult) {
+        let ExecutionResult::Success { logs, .. } = result else {
+            return;
+        };
+        let block_number = self.block_number;
+        self.logs.extend(logs.iter().map(|log| {
+            (
+                ethabi::Address::from(log.address.into_array()),
+                Log {
+                    block_number,
+                    topics: log
+                        .data
+                        .topics()
+                        .iter()
+                        .map(|topic| ethabi::Hash::from(topic.0))
+                        .collect(),
+                    data: log.data.data.to_vec(),
+                },
+            )
+        }));
+    }
+}
+
+/// The output of a successful execution, or an error reporting the failure as the EVM module of
+/// the runtime does.
+fn output(result: ExecutionResult) -> Result<Vec<u8>> {
+    match result {
+        ExecutionResult::Success { output, .. } => Ok(output.data().to_vec()),
+        ExecutionResult::Revert { output, .. } => Err(revert::decode_error(anyhow!(
+            revert::reverted_message(&output)
+        ))),
+        ExecutionResult::Halt { reason, .. } => Err(revert::decode_error(anyhow!(
+            "execution failed: {:?}",
+            reason
+        ))),
+    }
+}
+
+#[async_trait]
+impl ChainBackend for EvmChain {
+    /// The calldata of the call.
+    type Tx = Vec<u8>;
+
+    async fn current_round(&self) -> Result<u64> {
+        Ok(self.state.lock().unwrap().block_number)
+    }
+
+    async fn simulate_call(&self, _round: u64, data: Vec<u8>) -> Result<Vec<u8>> {
+        self.state
+            .lock()
+            .unwrap()
+            .call(self.engine, self.vault, data)
+    }
+
+    async fn get_logs(&self, _round: u64, range: &RangeInclusive<u64>) -> Result<Vec<Log>> {
+        Ok(self
+            .state
+            .lock()
+            .unwrap()
+            .logs
+            .iter()
+            .filter(|(address, log)| *address == self.vault && range.contains(&log.block_number))
+            .map(|(_, log)| log.clone())
+            .collect())
+    }
+
+    async fn prepare_call(&self, data: Vec<u8>) -> Result<Vec<u8>> {
+        let round = self.current_round().await?;
+        self.simulate_call(round, data.clone()).await?;
+        Ok(data)
+    }
+
+    async fn submit_tx(&self, data: Vec<u8>) -> Result<TxOutcome> {
+        match self
+            .state
+            .lock()
+            .unwrap()
+            .send(self.engine, self.vault, data)
+        {
+            Ok(_) => Ok(TxOutcome::Succeeded),
+            Err(err) => Ok(TxOutcome::Failed(TxFailed {
+                module: "evm".into(),
+                code: REVERTED_CODE,
+                message: err.to_string(),
+            })),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vault::ProposalStatus;
+
+    /// Source of a contract as it was compiled into its deployment artifact.
+    fn compiled_source(contract: &str) -> String {
+        let artifact = artifact(contract).unwrap();
+        let input = artifacts_dir().join(format!(
+            "solcInputs/{}.json",
+            artifact["solcInputHash"].as_str().unwrap()
+        ));
+        let input: serde_json::Value =
+            serde_json::from_str(&std::fs::read_to_string(input).unwrap()).unwrap();
+        input["sources"][format!("contracts/{}.sol", contract)]["content"]
+            .as_str()
+            .unwrap()
+            .to_owned()
+    }
+
+    #[test]
+    fn artifacts_match_contract_sources() {
+        let contracts = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("../scaffold-eth/packages/hardhat/contracts");
+        for contract in ["Vault", "VaultGovernor", "VaultTimelock", "VaultToken"] {
+            let source = std::fs::read_to_string(contracts.join(format!("{}.sol", contract)));
+            assert_eq!(
+                compiled_source(contract),
+                source.unwrap(),
+                "{}.sol changed since its artifact was compiled, redeploy it to regenerate {}",
+                contract,
+                artifacts_dir().display()
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn runs_the_vault_contract() {
+        let chain = EvmChain::deploy([7; 21]).unwrap();
+        let round = chain.current_round().await.unwrap();
+
+        let app_id = chain
+            .simulate_call(round, vault::encode_app_id())
+            .await
+            .unwrap();
+        assert_eq!(vault::decode_app_id(&app_id).unwrap(), [7; 21]);
+
+        let requester = ethabi::Address::repeat_byte(0x11);
+        let proposal_id = chain.propose_query(requester, "SELECT 1", "0x02").unwrap();
+        assert_eq!(proposal_id, 1);
+        chain.approve(proposal_id).unwrap();
+
+        let proposal = vault::decode_get_proposal(
+            &chain
+                .simulate_call(round, vault::encode_get_proposal(proposal_id))
+                .await
+                .unwrap(),
+        )
+        .unwrap();
+        assert_eq!(proposal.status, ProposalStatus::Approved);
+        assert_eq!(proposal.requester, requester);
+        // The governor is back in place to open the governance proposals of later queries.
+        let next = chain.propose_query(requester, "SELECT 2", "0x02").unwrap();
+        chain.approve(next).unwrap();
+
+        // Reverts carry the contract's reason.
+        let err = chain
+            .prepare_call(vault::encode_consume_proposal(proposal_id, ""))
+            .await
+            .unwrap_err();
+        assert_eq!(
+            revert::ContractError::from_error(&err),
+            Some(revert::ContractError::Revert(
+                "Encrypted result cannot be empty".into()
+            ))
+        );
+        assert!(chain.completed_query(proposal_id).is_err());
+    }
+}
@@ -0,0 +1,386 @@
+//! Crash-safe record of how far each proposal got through the engine.
+//!
+//! The journal is an append-only file of JSON lines, one per stage transition, kept on the
+//! persistent storage of the ROFL app. Every append is flushed to disk before the engine acts on
+//! it, so after a restart the engine knows which proposals were already executed, which results
+//! were already encrypted and, most importantly, for which proposals a transaction was sent.
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result, anyhow};
+use serde::{Deserialize, Serialize};
+
+/// Stage a proposal has reached. Stages only move forward; `Confirmed` and `Failed` are final.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Stage {
+    Claimed,
+    Executing,
+    ResultEncrypted,
+    TxSubmitted,
+    Confirmed,
+    Failed,
+}
+
+impl Stage {
+    pub fn is_final(self) -> bool {
+        matches!(self, Stage::Confirmed | Stage::Failed)
+    }
+}
+
+/// What the journal knows about a proposal.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    pub stage: Stage,
+    /// The encrypted result, kept from `ResultEncrypted` on so it is not computed twice. It is
+    /// dropped once the proposal is final, as it is never sent again.
+    pub encrypted_result: Option<String>,
+    /// Why the proposal failed.
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Record {
+    proposal_id: u64,
+    stage: Stage,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    encrypted_result: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+pub struct Journal {
+    path: PathBuf,
+    file: File,
+    entries: BTreeMap<u64, Entry>,
+}
+
+impl Journal {
+    /// Open the journal at `path`, creating it if needed, and replay it. A torn final line left
+    /// by a crash during an append is discarded.
+    pub fn open(path: impl Into<PathBuf>) -> Result<Self> {
+        let path = path.into();
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)
+                .with_context(|| format!("failed to create {}", dir.display()))?;
+        }
+
+        let mut entries = BTreeMap::new();
+        if path.exists() {
+            let reader = BufReader::new(File::open(&path)?);
+            let lines = reader.lines().collect::<std::io::Result<Vec<_>>>()?;
+            let last = lines.len().saturating_sub(1);
+            for (index, line) in lines.iter().enumerate() {
+                if line.trim().is_empty() {
+                    continue;
+                }
+                match serde_json::from_str::<Record>(line) {
+                    Ok(record) => apply(&mut entries, record),
+                    Err(_) if index == last => {
+                        tracing::warn!("Discarding torn journal record in {}", path.display());
+                    }
+                    Err(err) => {
+                        return Err(anyhow!(
+                            "corrupt journal record on line {} of {}: {}",
+                            index + 1,
+                            path.display(),
+                            err
+                        ));
+                    }
+                }
+            }
+        }
+
+        // Rewrite the journal with one record per proposal. This also drops a torn final line,
+        // which would otherwise corrupt the next append.
+        let file = compact(&path, &entries)?;
+
+        Ok(Self {
+            path,
+            file,
+            entries,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn get(&self, proposal_id: u64) -> Option<&Entry> {
+        self.entries.get(&proposal_id)
+    }
+
+    pub fn stage(&self, proposal_id: u64) -> Option<Stage> {
+        self.get(proposal_id).map(|entry| entry.stage)
+    }
+
+    /// Move a proposal to `stage`. Fails without writing anything if that would not move it
+    /// forward. A proposal still at `Claimed` or `Executing` may go back to either of them, as an
+    /// execution interrupted by a restart has to be redone from the start.
+    pub fn advance(&mut self, proposal_id: u64, stage: Stage) -> Result<()> {
+        self.append(Record {
+            proposal_id,
+            stage,
+            encrypted_result: None,
+            error: None,
+        })
+    }
+
+    /// Record the encrypted result of a proposal, moving it to `ResultEncrypted`.
+    pub fn store_result(&mut self, proposal_id: u64, encrypted_result: String) -> Result<()> {
+        self.append(Record {
+            proposal_id,
+            stage: Stage::ResultEncrypted,
+            encrypted_result: Some(encrypted_result),
+            error: None,
+        })
+    }
+
+    /// Mark a proposal as failed for good.
+    pub fn fail(&mut self, proposal_id: u64, error: String) -> Result<()> {
+        self.append(Record {
+            proposal_id,
+            stage: Stage::Failed,
+            encrypted_result: None,
+            error: Some(error),
+        })
+    }
+
+    fn append(&mut self, record: Record) -> Result<()> {
+        let current = self.stage(record.proposal_id);
+        if !can_advance(current, record.stage) {
+            return Err(anyhow!(
+                "proposal {} cannot move from {:?} to {:?}",
+                record.proposal_id,
+                current,
+                record.stage
+            ));
+        }
+
+        let mut line = serde_json::to_string(&record)?;
+        line.push('\n');
+        self.file.write_all(line.as_bytes())?;
+        self.file.sync_data()?;
+
+        apply(&mut self.entries, record);
+        Ok(())
+    }
+}
+
+fn can_advance(from: Option<Stage>, to: Stage) -> bool {
+    match from {
+        None => true,
+        Some(from) if from.is_final() => false,
+        Some(_) if to == Stage::Failed => true,
+        Some(from) => from < to || (from <= Stage::Executing && to <= Stage::Executing),
+    }
+}
+
+fn apply(entries: &mut BTreeMap<u64, Entry>, record: Record) {
+    let entry = entries.entry(record.proposal_id).or_insert(Entry {
+        stage: record.stage,
+        encrypted_result: None,
+        error: None,
+    });
+    entry.stage = record.stage;
+    if record.stage.is_final() {
+        entry.encrypted_result = None;
+    } else if record.encrypted_result.is_some() {
+        entry.encrypted_result = record.encrypted_result;
+    }
+    if record.error.is_some() {
+        entry.error = record.error;
+    }
+}
+
+/// Atomically replace the journal with one record per proposal and return it opened for
+/// appending.
+fn compact(path: &Path, entries: &BTreeMap<u64, Entry>) -> Result<File> {
+    let tmp = path.with_extension("tmp");
+    let mut file = File::create(&tmp)?;
+    for (&proposal_id, entry) in entries {
+        let record = Record {
+            proposal_id,
+            stage: entry.stage,
+            encrypted_result: entry.encrypted_result.clone(),
+            error: entry.error.clone(),
+        };
+        let mut line = serde_json::to_string(&record)?;
+        line.push('\n');
+        file.write_all(line.as_bytes())?;
+    }
+    file.sync_all()?;
+    fs::rename(&tmp, path)?;
+
+    // Persist the rename itself.
+    if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        File::open(dir)?.sync_all()?;
+    }
+
+    Ok(OpenOptions::new().append(true).open(path)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn journal_path(dir: &tempfile::TempDir) -> PathBuf {
+        dir.path().join("state").join("journal.jsonl")
+    }
+
+    #[test]
+    fn survives_restart() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = journal_path(&dir);
+
+        let mut journal = Journal::open(&path).unwrap();
+        journal.advance(1, Stage::Claimed).unwrap();
+        journal.advance(1, Stage::Executing).unwrap();
+        journal.store_result(1, "envelope".into()).unwrap();
+        journal.advance(1, Stage::TxSubmitted).unwrap();
+        journal.advance(2, Stage::Claimed).unwrap();
+        journal.fail(2, "bad query".into()).unwrap();
+        journal.advance(3, Stage::Executing).unwrap();
+        drop(journal);
+
+        let journal = Journal::open(&path).unwrap();
+        assert_eq!(
+            journal.get(1),
+            Some(&Entry {
+                stage: Stage::TxSubmitted,
+                encrypted_result: Some("envelope".into()),
+                error: None,
+            })
+        );
+        assert_eq!(journal.stage(2), Some(Stage::Failed));
+        assert_eq!(journal.get(2).unwrap().error.as_deref(), Some("bad query"));
+        assert_eq!(journal.stage(3), Some(Stage::Executing));
+        assert_eq!(journal.stage(4), None);
+
+        // Reopening compacts the journal to one record per proposal.
+        let contents = fs::read_to_string(&path).unwrap();
+        assert_eq!(contents.lines().count(), 3);
+    }
+
+    #[test]
+    fn only_moves_forward() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut journal = Journal::open(journal_path(&dir)).unwrap();
+
+        journal.advance(1, Stage::Claimed).unwrap();
+        journal.advance(1, Stage::Executing).unwrap();
+        // An interrupted execution can be restarted.
+        journal.advance(1, Stage::Executing).unwrap();
+        journal.advance(1, Stage::Claimed).unwrap();
+        journal.advance(1, Stage::Executing).unwrap();
+        journal.store_result(1, "envelope".into()).unwrap();
+        assert!(journal.advance(1, Stage::Executing).is_err());
+
+        // A transaction is only ever recorded as sent once.
+        journal.advance(1, Stage::TxSubmitted).unwrap();
+        assert!(journal.advance(1, Stage::TxSubmitted).is_err());
+        journal.advance(1, Stage::Confirmed).unwrap();
+        assert!(journal.fail(1, "late".into()).is_err());
+        assert_eq!(journal.stage(1), Some(Stage::Confirmed));
+        assert_eq!(journal.get(1).unwrap().error, None);
+    }
+
+    #[test]
+    fn drops_results_of_final_proposals_when_compacting() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = journal_path(&dir);
+
+        let mut journal = Journal::open(&path).unwrap();
+        journal.advance(1, Stage::Claimed).unwrap();
+        journal.store_result(1, "first envelope".into()).unwrap();
+        journal.advance(1, Stage::TxSubmitted).unwrap();
+        journal.advance(1, Stage::Confirmed).unwrap();
+        journal.advance(2, Stage::Claimed).unwrap();
+        journal.store_result(2, "second envelope".into()).unwrap();
+        journal.fail(2, "reverted".into()).unwrap();
+        journal.advance(3, Stage::Claimed).unwrap();
+        journal.store_result(3, "third envelope".into()).unwrap();
+        drop(journal);
+
+        let journal = Journal::open(&path).unwrap();
+        assert_eq!(journal.get(1).unwrap().encrypted_result, None);
+        assert_eq!(journal.get(2).unwrap().encrypted_result, None);
+        assert_eq!(journal.get(2).unwrap().error.as_deref(), Some("reverted"));
+        assert_eq!(
+            journal.get(3).unwrap().encrypted_result.as_deref(),
+            Some("third envelope")
+        );
+
+        let contents = fs::read_to_string(&path).unwrap();
+        assert!(!contents.contains("first envelope"));
+        assert!(!contents.contains("second envelope"));
+        assert!(contents.contains("third envelope"));
+    }
+
+    #[test]
+    fn discards_torn_final_record() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = journal_path(&dir);
+
+        let mut journal = Journal::open(&path).unwrap();
+        journal.advance(1, Stage::Claimed).unwrap();
+        drop(journal);
+
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(br#"{"proposal_id":1,"stage":"exec"#)
+            .unwrap();
+        drop(file);
+
+        let mut journal = Journal::open(&path).unwrap();
+        assert_eq!(journal.stage(1), Some(Stage::Claimed));
+        journal.advance(1, Stage::Executing).unwrap();
+        drop(journal);
+
+        assert_eq!(
+            Journal::open(&path).unwrap().stage(1),
+            Some(Stage::Executing)
+        );
+    }
+
+    #[test]
+    fn rejects_corruption_before_the_end() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = journal_path(&dir);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(
+            &path,
+            "garbage\n{\"proposal_id\":1,\"stage\":\"claimed\"}\n",
+        )
+        .unwrap();
+
+        assert!(Journal::open(&path).is_err());
+    }
+
+    #[test]
+    fn uses_stage_names_from_the_spec() {
+        let names: Vec<String> = [
+            Stage::Claimed,
+            Stage::Executing,
+            Stage::ResultEncrypted,
+            Stage::TxSubmitted,
+            Stage::Confirmed,
+            Stage::Failed,
+        ]
+        .iter()
+        .map(|stage| serde_json::to_string(stage).unwrap())
+        .collect();
+        assert_eq!(
+            names,
+            [
+                "\"claimed\"",
+                "\"executing\"",
+                "\"result-encrypted\"",
+                "\"tx-submitted\"",
+                "\"confirmed\"",
+                "\"failed\""
+            ]
+        );
+    }
+}
@@ -0,0 +1,27 @@
+//! The query engine behind the `engine` ROFL app, shared with the offline `decrypt-result` and
+//! `log-collector` tools.
+//!
+//! The app itself lives in `main.rs`; this crate holds the proposal pipeline and everything it
+//! builds on, from the vault bindings to query execution and result delivery.
+mod akave;
+mod anonymity;
+pub mod audit_log;
+pub mod budget;
+pub mod chain;
+pub mod config;
+pub mod delivery;
+pub mod encryption;
+pub mod events;
+#[cfg(test)]
+mod evm;
+pub mod journal;
+mod limits;
+pub mod logs;
+pub mod oasis;
+pub mod pipeline;
+mod policy;
+mod privacy;
+mod query;
+mod revert;
+mod submitter;
+pub mod vault;
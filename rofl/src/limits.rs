@@ -0,0 +1,104 @@
+//! Resource limits of query execution.
+//!
+//! A query that runs into a limit does not fail: its requester gets a resource exceeded outcome
+//! in place of the result. The outcome only names the limit, not how far the query got, so the
+//! same query over the same data under the same limits always gets the same outcome.
+use std::fmt;
+
+use serde::Serialize;
+
+use crate::config::LimitsConfig;
+
+/// A resource whose limit a query ran into.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Resource {
+    /// Wall-clock time, in milliseconds.
+    Time,
+    /// Memory and spill space, in MB.
+    Memory,
+    /// Size of the dataset objects read, in bytes.
+    BytesScanned,
+    /// Rows of the result.
+    OutputRows,
+}
+
+/// Outcome of a query that exceeded one of its limits, handed back in place of its result.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(tag = "outcome", rename = "resource_exceeded")]
+pub struct ResourceExceeded {
+    pub resource: Resource,
+    pub limit: u64,
+}
+
+impl ResourceExceeded {
+    /// The outcome of running into `resource` under `limits`.
+    pub fn new(resource: Resource, limits: &LimitsConfig) -> Self {
+        let limit = match resource {
+            Resource::Time => limits.timeout.as_millis().try_into().unwrap_or(u64::MAX),
+            Resource::Memory => limits.memory_mb,
+            Resource::BytesScanned => limits.max_scan_bytes,
+            Resource::OutputRows => limits.max_output_rows,
+        };
+        Self { resource, limit }
+    }
+
+    /// Serialize the outcome as the JSON document handed back to the requester.
+    pub fn to_json(self) -> anyhow::Result<String> {
+        Ok(serde_json::to_string(&self)?)
+    }
+}
+
+impl fmt::Display for ResourceExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.resource {
+            Resource::Time => write!(f, "query ran longer than {} ms", self.limit),
+            Resource::Memory => write!(f, "query needed more than {} MB of memory", self.limit),
+            Resource::BytesScanned => write!(f, "query reads more than {} bytes", self.limit),
+            Resource::OutputRows => write!(f, "query returned more than {} rows", self.limit),
+        }
+    }
+}
+
+impl std::error::Error for ResourceExceeded {}
+
+/// Check the total size of the dataset objects a query reads against its limit.
+pub fn check_scan(bytes: u64, limits: &LimitsConfig) -> Result<(), ResourceExceeded> {
+    if bytes > limits.max_scan_bytes {
+        return Err(ResourceExceeded::new(Resource::BytesScanned, limits));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn reports_only_the_limit() {
+        let limits = LimitsConfig {
+            max_scan_bytes: 100,
+            ..LimitsConfig::default()
+        };
+        assert!(check_scan(100, &limits).is_ok());
+
+        let exceeded = check_scan(101, &limits).unwrap_err();
+        assert_eq!(exceeded.to_string(), "query reads more than 100 bytes");
+        assert_eq!(
+            exceeded.to_json().unwrap(),
+            r#"{"outcome":"resource_exceeded","resource":"bytes_scanned","limit":100}"#
+        );
+        assert_eq!(
+            ResourceExceeded::new(Resource::Time, &limits).to_string(),
+            "query ran longer than 60000 ms"
+        );
+
+        // Timeouts below a second are not rounded down to nothing.
+        let limits = LimitsConfig {
+            timeout: Duration::from_millis(250),
+            ..LimitsConfig::default()
+        };
+        assert_eq!(ResourceExceeded::new(Resource::Time, &limits).limit, 250);
+    }
+}
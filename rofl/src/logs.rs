@@ -0,0 +1,600 @@
+//! Shipping of engine logs to a remote collector.
+//!
+//! Logs are emitted with `tracing`. Besides being printed, every event is turned into a
+//! [`Record`] by [`ShipperLayer`] and handed to a bounded in-memory queue, so logging never waits
+//! on the network. A [`Shipper`] drains the queue on its own thread and posts the records to the
+//! collector in batches, retrying with backoff. Batches the collector does not take are appended
+//! to a local fallback file instead of being lost.
+//!
+//! Records are signed with the app's signer right before they are shipped, see
+//! [`crate::audit_log`]. Until the signer is available, records wait in the queue.
+//!
+//! Events pick up the `proposal_id` and `round` fields of the spans they are emitted in, so code
+//! running for a proposal only has to be instrumented once.
+use std::fmt;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
+
+use anyhow::{Result, anyhow};
+use rand::RngCore;
+use serde::Serialize;
+use tokio::sync::mpsc;
+use tokio::time::Instant;
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id, Record as SpanRecord};
+use tracing::{Event, Level, Subscriber};
+use tracing_subscriber::filter::Targets;
+use tracing_subscriber::layer::{Context, Layer, SubscriberExt};
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::util::SubscriberInitExt;
+
+use crate::audit_log::{Batch, Payload, Record, RecordSigner, SignedRecord};
+
+/// Number of records the queue holds before new ones are dropped.
+const QUEUE_CAPACITY: usize = 1024;
+/// Number of records posted at once.
+const BATCH_SIZE: usize = 64;
+/// Longest a record waits in a partial batch.
+const FLUSH_INTERVAL: Duration = Duration::from_secs(2);
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+/// Attempts made to post a batch before it goes to the fallback file.
+const SHIP_ATTEMPTS: u32 = 3;
+/// Delay before the first retry; it doubles with every further attempt.
+const BASE_BACKOFF: Duration = Duration::from_millis(500);
+/// Longest a batch waits for the signer before it goes to the fallback file unsigned.
+const SIGNER_WAIT: Duration = Duration::from_secs(30);
+
+/// Slot for the signer records are signed with. It is filled once the app's environment, and
+/// with it the signer, is available.
+pub type SignerSlot = Arc<OnceLock<Box<dyn RecordSigner>>>;
+
+/// Install the global subscriber. Events of the engine at `info` and above are printed and, if a
+/// collector is configured, queued for the returned shipper. Events of dependencies such as the
+/// HTTP client are left out, as shipping them would log the shipper's own requests.
+pub fn init(url: Option<String>, fallback_path: PathBuf, signer: SignerSlot) -> Option<Shipper> {
+    let targets = Targets::new()
+        .with_target("engine", Level::INFO)
+        .with_target("akave_adapter", Level::INFO);
+    let (layer, shipper) = match url {
+        Some(url) => {
+            let (layer, shipper) = channel(url, fallback_path, signer);
+            (Some(layer), Some(shipper))
+        }
+        None => (None, None),
+    };
+
+    tracing_subscriber::registry()
+        .with(tracing_subscriber::fmt::layer().with_ansi(false))
+        .with(layer)
+        .with(targets)
+        .init();
+    shipper
+}
+
+/// Create the layer feeding the queue and the shipper draining it.
+pub fn channel(url: String, fallback_path: PathBuf, signer: SignerSlot) -> (ShipperLayer, Shipper) {
+    let (sender, receiver) = mpsc::channel(QUEUE_CAPACITY);
+    let dropped = Arc::new(AtomicU64::new(0));
+    let layer = ShipperLayer {
+        sender,
+        dropped: dropped.clone(),
+    };
+    let mut session = [0u8; 16];
+    rand::rngs::OsRng.fill_bytes(&mut session);
+    let shipper = Shipper {
+        url,
+        fallback_path,
+        receiver,
+        dropped,
+        signer,
+        session: hex::encode(session),
+        next_seq: 0,
+        backoff: BASE_BACKOFF,
+        signer_wait: SIGNER_WAIT,
+    };
+    (layer, shipper)
+}
+
+/// `tracing` layer that queues every event it sees as a [`Record`].
+pub struct ShipperLayer {
+    sender: mpsc::Sender<Record>,
+    /// Records dropped because the queue was full, reported with the next batch.
+    dropped: Arc<AtomicU64>,
+}
+
+/// `proposal_id` and `round` recorded on a span.
+#[derive(Debug, Default, Clone, Copy)]
+struct SpanFields {
+    proposal_id: Option<u64>,
+    round: Option<u64>,
+}
+
+impl<S> Layer<S> for ShipperLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        let mut visitor = FieldVisitor::default();
+        attrs.record(&mut visitor);
+        if let Some(span) = ctx.span(id) {
+            span.extensions_mut().insert(visitor.span_fields());
+        }
+    }
+
+    fn on_record(&self, id: &Id, values: &SpanRecord<'_>, ctx: Context<'_, S>) {
+        let mut visitor = FieldVisitor::default();
+        values.record(&mut visitor);
+        if let Some(span) = ctx.span(id) {
+            let mut extensions = span.extensions_mut();
+            match extensions.get_mut::<SpanFields>() {
+                Some(fields) => {
+                    fields.proposal_id = visitor.proposal_id.or(fields.proposal_id);
+                    fields.round = visitor.round.or(fields.round);
+                }
+                None => extensions.insert(visitor.span_fields()),
+            }
+        }
+    }
+
+    fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+        let mut visitor = FieldVisitor::default();
+        event.record(&mut visitor);
+
+        // Fill in what the event does not carry itself from the innermost span that has it.
+        if let Some(scope) = ctx.event_scope(event) {
+            for span in scope {
+                if let Some(fields) = span.extensions().get::<SpanFields>() {
+                    visitor.proposal_id = visitor.proposal_id.or(fields.proposal_id);
+                    visitor.round = visitor.round.or(fields.round);
+                }
+            }
+        }
+
+        let metadata = event.metadata();
+        let record = Record {
+            timestamp: chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, true),
+            level: metadata.level().to_string(),
+            target: metadata.target().to_owned(),
+            message: visitor.message,
+            proposal_id: visitor.proposal_id,
+            round: visitor.round,
+        };
+        if self.sender.try_send(record).is_err() {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Collects the message of an event, with any other fields appended as `key=value`.
+#[derive(Default)]
+struct FieldVisitor {
+    message: String,
+    proposal_id: Option<u64>,
+    round: Option<u64>,
+}
+
+impl FieldVisitor {
+    fn span_fields(&self) -> SpanFields {
+        SpanFields {
+            proposal_id: self.proposal_id,
+            round: self.round,
+        }
+    }
+}
+
+impl Visit for FieldVisitor {
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        match field.name() {
+            "proposal_id" => self.proposal_id = Some(value),
+            "round" => self.round = Some(value),
+            _ => self.record_debug(field, &value),
+        }
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        match u64::try_from(value) {
+            Ok(value) => self.record_u64(field, value),
+            Err(_) => self.record_debug(field, &value),
+        }
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        if field.name() == "message" {
+            self.message.insert_str(0, value);
+        } else {
+            self.record_debug(field, &value);
+        }
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        if field.name() == "message" {
+            self.message.insert_str(0, &format!("{:?}", value));
+        } else {
+            self.message
+                .push_str(&format!(" {}={:?}", field.name(), value));
+        }
+    }
+}
+
+/// Drains the queue into the collector.
+pub struct Shipper {
+    url: String,
+    fallback_path: PathBuf,
+    receiver: mpsc::Receiver<Record>,
+    dropped: Arc<AtomicU64>,
+    signer: SignerSlot,
+    /// Random ID of this run, so the collector can tell new records from replayed ones.
+    session: String,
+    next_seq: u64,
+    backoff: Duration,
+    signer_wait: Duration,
+}
+
+impl Shipper {
+    /// Run the shipper on a thread of its own, so a slow collector never holds up the runtime.
+    pub fn spawn(self) {
+        std::thread::spawn(move || {
+            match tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+            {
+                Ok(runtime) => runtime.block_on(self.run()),
+                Err(err) => eprintln!("Failed to start the log shipper: {:?}", err),
+            }
+        });
+    }
+
+    /// Ship records until every [`ShipperLayer`] is gone, then flush what is left.
+    pub async fn run(mut self) {
+        let client = match reqwest::Client::builder().timeout(REQUEST_TIMEOUT).build() {
+            Ok(client) => client,
+            Err(err) => {
+                // The shipper cannot log through `tracing` without feeding itself.
+                eprintln!("Failed to create the log shipper's HTTP client: {:?}", err);
+                return;
+            }
+        };
+
+        let mut batch = Vec::with_capacity(BATCH_SIZE);
+        let mut deadline = None;
+        loop {
+            let next = match deadline {
+                Some(deadline) => tokio::time::timeout_at(deadline, self.receiver.recv()).await,
+                None => Ok(self.receiver.recv().await),
+            };
+            let closed = match next {
+                Ok(Some(record)) => {
+                    batch.push(record);
+                    deadline.get_or_insert_with(|| Instant::now() + FLUSH_INTERVAL);
+                    if batch.len() < BATCH_SIZE {
+                        continue;
+                    }
+                    false
+                }
+                Ok(None) => true,
+                // The oldest record in the batch has waited long enough.
+                Err(_) => false,
+            };
+
+            self.flush(&client, std::mem::take(&mut batch)).await;
+            deadline = None;
+            if closed {
+                return;
+            }
+        }
+    }
+
+    async fn flush(&mut self, client: &reqwest::Client, mut records: Vec<Record>) {
+        let dropped = self.dropped.swap(0, Ordering::Relaxed);
+        if dropped > 0 {
+            records.push(Record {
+                timestamp: chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, true),
+                level: tracing::Level::WARN.to_string(),
+                target: module_path!().to_owned(),
+                message: format!("dropped {} log record(s), the queue was full", dropped),
+                proposal_id: None,
+                round: None,
+            });
+        }
+        if records.is_empty() {
+            return;
+        }
+
+        let payloads: Vec<Payload> = records
+            .into_iter()
+            .map(|record| {
+                let seq = self.next_seq;
+                self.next_seq += 1;
+                Payload {
+                    session: self.session.clone(),
+                    seq,
+                    record,
+                }
+            })
+            .collect();
+
+        let batch = match self.wait_for_signer().await {
+            Some(signer) => payloads
+                .iter()
+                .map(|payload| SignedRecord::sign(payload, signer))
+                .collect::<Result<Vec<_>>>()
+                .map(|records| Batch { records }),
+            None => Err(anyhow!("the signer is not available")),
+        };
+        let batch = match batch {
+            Ok(batch) => batch,
+            Err(err) => {
+                eprintln!(
+                    "Failed to sign {} log record(s), writing them to {} unsigned: {:?}",
+                    payloads.len(),
+                    self.fallback_path.display(),
+                    err
+                );
+                self.write_fallback(&payloads);
+                return;
+            }
+        };
+
+        let mut attempt = 1;
+        let err = loop {
+            match self.post(client, &batch).await {
+                Ok(()) => return,
+                Err(_) if attempt < SHIP_ATTEMPTS => {
+                    tokio::time::sleep(self.backoff.saturating_mul(1 << (attempt - 1))).await;
+                    attempt += 1;
+                }
+                Err(err) => break err,
+            }
+        };
+
+        eprintln!(
+            "Failed to ship {} log record(s), writing them to {}: {:?}",
+            batch.records.len(),
+            self.fallback_path.display(),
+            err
+        );
+        self.write_fallback(&batch.records);
+    }
+
+    async fn wait_for_signer(&self) -> Option<&dyn RecordSigner> {
+        let deadline = Instant::now() + self.signer_wait;
+        loop {
+            if let Some(signer) = self.signer.get() {
+                return Some(signer.as_ref());
+            }
+            if Instant::now() >= deadline {
+                return None;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+    }
+
+    async fn post(&self, client: &reqwest::Client, batch: &Batch) -> Result<()> {
+        let resp = client
+            .post(&self.url)
+            .json(batch)
+            .send()
+            .await
+            .map_err(|e| anyhow!("HTTP POST failed: {}", e))?;
+
+        if !resp.status().is_success() {
+            let status = resp.status();
+            let text = resp.text().await.unwrap_or_default();
+            return Err(anyhow!(
+                "Logging server returned error {}: {}",
+                status,
+                text
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Append records to the fallback file as JSON lines.
+    fn write_fallback<T: Serialize>(&self, records: &[T]) {
+        if let Err(err) = append_lines(&self.fallback_path, records) {
+            eprintln!("Failed to write the log fallback file: {:?}", err);
+        }
+    }
+}
+
+fn append_lines<T: Serialize>(path: &Path, records: &[T]) -> Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+
+    let mut lines = String::new();
+    for record in records {
+        lines.push_str(&serde_json::to_string(record)?);
+        lines.push('\n');
+    }
+    file.write_all(lines.as_bytes())?;
+    file.sync_data()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use k256::ecdsa::SigningKey;
+
+    fn record(message: &str) -> Record {
+        Record {
+            timestamp: "2025-06-01T00:00:00.000Z".into(),
+            level: "INFO".into(),
+            target: "engine".into(),
+            message: message.into(),
+            proposal_id: None,
+            round: None,
+        }
+    }
+
+    /// A shipper to `url` that signs with a fresh key, which is returned as well.
+    fn signed_channel(url: String, fallback: PathBuf) -> (ShipperLayer, Shipper, SigningKey) {
+        let key = SigningKey::random(&mut rand::rngs::OsRng);
+        let signer = SignerSlot::default();
+        assert!(signer.set(Box::new(key.clone())).is_ok());
+        let (layer, mut shipper) = channel(url, fallback, signer);
+        shipper.backoff = Duration::ZERO;
+        (layer, shipper, key)
+    }
+
+    #[test]
+    fn records_carry_span_fields() {
+        let dir = tempfile::tempdir().unwrap();
+        let (layer, mut shipper, _) =
+            signed_channel("http://unused".into(), dir.path().join("logs.jsonl"));
+        let subscriber = tracing_subscriber::registry().with(layer);
+
+        tracing::subscriber::with_default(subscriber, || {
+            let block = tracing::info_span!("block", round = 12u64);
+            let _block = block.enter();
+            tracing::info!("scanning");
+            let proposal = tracing::info_span!("proposal", proposal_id = 7u64);
+            let _proposal = proposal.enter();
+            tracing::warn!(attempt = 2, "retrying {}", "consumeProposal");
+        });
+
+        let scanning = shipper.receiver.try_recv().unwrap();
+        assert_eq!(scanning.message, "scanning");
+        assert_eq!(scanning.level, "INFO");
+        assert_eq!((scanning.proposal_id, scanning.round), (None, Some(12)));
+
+        let retrying = shipper.receiver.try_recv().unwrap();
+        assert_eq!(retrying.message, "retrying consumeProposal attempt=2");
+        assert_eq!(retrying.level, "WARN");
+        assert_eq!((retrying.proposal_id, retrying.round), (Some(7), Some(12)));
+        assert!(chrono::DateTime::parse_from_rfc3339(&retrying.timestamp).is_ok());
+    }
+
+    #[test]
+    fn counts_records_dropped_by_a_full_queue() {
+        let dir = tempfile::tempdir().unwrap();
+        let (layer, shipper, _) =
+            signed_channel("http://unused".into(), dir.path().join("logs.jsonl"));
+        let subscriber = tracing_subscriber::registry().with(layer);
+
+        tracing::subscriber::with_default(subscriber, || {
+            for i in 0..QUEUE_CAPACITY + 3 {
+                tracing::info!("record {}", i);
+            }
+        });
+
+        assert_eq!(shipper.dropped.load(Ordering::Relaxed), 3);
+    }
+
+    #[tokio::test]
+    async fn ships_signed_batches_and_falls_back_to_a_file() {
+        let mut server = mockito::Server::new_async().await;
+        let dir = tempfile::tempdir().unwrap();
+        let fallback = dir.path().join("logs").join("undelivered.jsonl");
+
+        let (layer, shipper, key) =
+            signed_channel(format!("{}/logs", server.url()), fallback.clone());
+        let session = shipper.session.clone();
+        let sender = layer.sender.clone();
+        drop(layer);
+
+        // The first batch fails once and then goes through.
+        let failing = server
+            .mock("POST", "/logs")
+            .with_status(503)
+            .expect(1)
+            .create_async()
+            .await;
+        let verifying_key = *key.verifying_key();
+        let accepted = server
+            .mock("POST", "/logs")
+            .match_request(move |request| {
+                let batch: Batch = serde_json::from_slice(request.body().unwrap()).unwrap();
+                let payloads: Vec<Payload> = batch
+                    .records
+                    .iter()
+                    .map(|record| record.verify(&verifying_key).unwrap())
+                    .collect();
+                payloads
+                    == [
+                        Payload {
+                            session: session.clone(),
+                            seq: 0,
+                            record: record("first"),
+                        },
+                        Payload {
+                            session: session.clone(),
+                            seq: 1,
+                            record: record("second"),
+                        },
+                    ]
+            })
+            .expect(1)
+            .create_async()
+            .await;
+
+        sender.send(record("first")).await.unwrap();
+        sender.send(record("second")).await.unwrap();
+        drop(sender);
+        shipper.run().await;
+
+        failing.assert_async().await;
+        accepted.assert_async().await;
+        assert!(!fallback.exists());
+
+        // A collector that keeps failing gets nothing, the file gets everything, signed.
+        server.reset();
+        let down = server
+            .mock("POST", "/logs")
+            .with_status(500)
+            .expect(SHIP_ATTEMPTS as usize)
+            .create_async()
+            .await;
+
+        let (layer, shipper, key) =
+            signed_channel(format!("{}/logs", server.url()), fallback.clone());
+        layer.dropped.store(5, Ordering::Relaxed);
+        layer.sender.send(record("lost?")).await.unwrap();
+        drop(layer);
+        shipper.run().await;
+
+        down.assert_async().await;
+        let payloads: Vec<Payload> = fs::read_to_string(&fallback)
+            .unwrap()
+            .lines()
+            .map(|line| {
+                let record: SignedRecord = serde_json::from_str(line).unwrap();
+                record.verify(key.verifying_key()).unwrap()
+            })
+            .collect();
+        assert_eq!(payloads.len(), 2);
+        assert_eq!(payloads[0].record, record("lost?"));
+        assert!(
+            payloads[1]
+                .record
+                .message
+                .contains("dropped 5 log record(s)")
+        );
+    }
+
+    #[tokio::test]
+    async fn keeps_records_without_a_signer() {
+        let dir = tempfile::tempdir().unwrap();
+        let fallback = dir.path().join("undelivered.jsonl");
+        let (layer, mut shipper) = channel(
+            "http://unused".into(),
+            fallback.clone(),
+            SignerSlot::default(),
+        );
+        shipper.signer_wait = Duration::ZERO;
+        layer.sender.send(record("early")).await.unwrap();
+        drop(layer);
+        shipper.run().await;
+
+        let payload: Payload =
+            serde_json::from_str(fs::read_to_string(&fallback).unwrap().trim()).unwrap();
+        assert_eq!((payload.seq, payload.record), (0, record("early")));
+    }
+}
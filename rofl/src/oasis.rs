@@ -0,0 +1,208 @@
+//! [`ChainBackend`] for the vault deployed on Sapphire, reached through the ROFL environment.
+use std::collections::BTreeMap;
+use std::ops::RangeInclusive;
+use std::sync::Arc;
+
+use anyhow::{Result, anyhow};
+use async_trait::async_trait;
+use module_evm::types::{H160, H256};
+use oasis_runtime_sdk::crypto::signature::secp256k1;
+use oasis_runtime_sdk::modules::core::types::EstimateGasQuery;
+use oasis_runtime_sdk::modules::rofl::app::prelude::*;
+use oasis_runtime_sdk::types;
+use oasis_runtime_sdk::types::address::SignatureAddressSpec;
+use oasis_runtime_sdk::types::token;
+use oasis_runtime_sdk::types::transaction::{CallResult, CallerAddress};
+
+use crate::chain::{ChainBackend, TxOutcome};
+use crate::config::EngineConfig;
+use crate::events::{self, Log};
+use crate::revert;
+use crate::submitter::{self, TxFailed};
+
+/// Arguments of the `evm.GetLogs` query.
+#[derive(Debug, cbor::Encode)]
+struct GetLogsQuery {
+    from_block: u64,
+    to_block: u64,
+    address: Vec<H160>,
+    topics: Vec<Vec<H256>>,
+}
+
+/// A log entry returned by the `evm.GetLogs` query.
+#[derive(Debug, Default, cbor::Decode)]
+struct EvmLog {
+    block_number: u64,
+    topics: Vec<H256>,
+    data: Vec<u8>,
+}
+
+pub struct OasisChain<A: App> {
+    /// The app, which builds transactions with its fee proxy set.
+    app: Arc<A>,
+    env: Environment<A>,
+    config: &'static EngineConfig,
+}
+
+impl<A: App> OasisChain<A> {
+    pub fn new(app: Arc<A>, env: Environment<A>, config: &'static EngineConfig) -> Self {
+        Self { app, env, config }
+    }
+
+    fn contract_address(&self) -> H160 {
+        H160(self.config.contract_address.0)
+    }
+
+    /// EVM address of the app's signer, which the engine calls the vault as.
+    fn caller(&self) -> Result<H160> {
+        let sdk_pub_key =
+            secp256k1::PublicKey::from_bytes(self.env.signer().public_key().as_bytes())
+                .map_err(|_| anyhow!("signer is not a secp256k1 key"))?;
+
+        module_evm::derive_caller::from_sigspec(&SignatureAddressSpec::Secp256k1Eth(sdk_pub_key))
+            .map_err(|_| anyhow!("failed to derive caller address"))
+    }
+}
+
+#[async_trait]
+impl<A: App> ChainBackend for OasisChain<A> {
+    type Tx = types::transaction::Transaction;
+
+    async fn current_round(&self) -> Result<u64> {
+        self.env.client().latest_round().await
+    }
+
+    async fn simulate_call(&self, round: u64, data: Vec<u8>) -> Result<Vec<u8>> {
+        let caller = self.caller()?;
+
+        let gas_price = module_evm::types::U256::from(self.config.gas.simulate_gas_price);
+
+        let response: Vec<u8> = self
+            .env
+            .client()
+            .query(
+                round,
+                "evm.SimulateCall",
+                module_evm::types::SimulateCallQuery {
+                    address: Some(self.contract_address()),
+                    gas_limit: self.config.gas.simulate_gas_limit,
+                    gas_price,
+                    value: 0.into(),
+                    caller,
+                    data,
+                },
+            )
+            .await
+            .map_err(revert::decode_error)?;
+
+        Ok(response)
+    }
+
+    async fn get_logs(&self, round: u64, range: &RangeInclusive<u64>) -> Result<Vec<Log>> {
+        let logs: Vec<EvmLog> = self
+            .env
+            .client()
+            .query(
+                round,
+                "evm.GetLogs",
+                GetLogsQuery {
+                    from_block: *range.start(),
+                    to_block: *range.end(),
+                    address: vec![self.contract_address()],
+                    topics: vec![
+                        events::topics()
+                            .into_iter()
+                            .map(|topic| H256::from_slice(topic.as_bytes()))
+                            .collect(),
+                    ],
+                },
+            )
+            .await?;
+
+        Ok(logs
+            .into_iter()
+            .map(|log| Log {
+                block_number: log.block_number,
+                topics: log
+                    .topics
+                    .iter()
+                    .map(|topic| ethabi::Hash::from_slice(topic.as_bytes()))
+                    .collect(),
+                data: log.data,
+            })
+            .collect())
+    }
+
+    /// Build a vault call transaction with its gas estimated by simulating it and its fee sized
+    /// to that gas at the current minimum gas price.
+    async fn prepare_call(&self, data: Vec<u8>) -> Result<Self::Tx> {
+        let round = self.current_round().await?;
+        let calldata_len = data.len();
+
+        let mut tx = self.app.new_transaction(
+            "evm.Call",
+            module_evm::types::Call {
+                address: self.contract_address(),
+                value: 0.into(),
+                data,
+            },
+        );
+        tx.set_fee_gas(self.config.gas.max_tx_gas);
+
+        let estimate: u64 = self
+            .env
+            .client()
+            .query(
+                round,
+                "core.EstimateGas",
+                EstimateGasQuery {
+                    caller: Some(CallerAddress::EthAddress(self.caller()?.into())),
+                    tx: tx.clone(),
+                    // Surface reverts instead of estimating the gas of a failing call.
+                    propagate_failures: true,
+                },
+            )
+            .await
+            .map_err(revert::decode_error)?;
+        let gas = submitter::gas_limit(estimate, calldata_len, &self.config.gas)?;
+
+        let gas_prices: BTreeMap<token::Denomination, u128> = self
+            .env
+            .client()
+            .query(round, "core.MinGasPrice", ())
+            .await?;
+        let gas_price = gas_prices
+            .get(&token::Denomination::NATIVE)
+            .copied()
+            .unwrap_or_default();
+
+        tx.set_fee_gas(gas);
+        tx.set_fee_amount(token::BaseUnits::new(
+            submitter::fee_amount(gas, gas_price),
+            token::Denomination::NATIVE,
+        ));
+        tracing::info!("Prepared a vault call with {} gas", gas);
+
+        Ok(tx)
+    }
+
+    async fn submit_tx(&self, tx: Self::Tx) -> Result<TxOutcome> {
+        match self
+            .env
+            .client()
+            .sign_and_submit_tx(self.env.signer(), tx)
+            .await?
+        {
+            CallResult::Failed {
+                module,
+                code,
+                message,
+            } => Ok(TxOutcome::Failed(TxFailed {
+                module,
+                code,
+                message,
+            })),
+            _ => Ok(TxOutcome::Succeeded),
+        }
+    }
+}
@@ -0,0 +1,1184 @@
+//! The proposal pipeline: finding approved proposals, executing their queries and handing the
+//! encrypted results back to the vault through `consumeProposal`.
+use std::fs;
+use std::ops::RangeInclusive;
+use std::path::Path;
+
+use akave_adapter::Object;
+use anyhow::{Result, anyhow};
+use tempfile::TempDir;
+use tokio::sync::{Mutex, OnceCell};
+use tracing::{Instrument, error, info, info_span, warn};
+
+use crate::akave::AkaveAdapter;
+use crate::anonymity;
+use crate::budget::PrivacyBudget;
+use crate::chain::{ChainBackend, TxOutcome};
+use crate::config::EngineConfig;
+use crate::delivery;
+use crate::encryption;
+use crate::events::{self, EventIndexer, VaultEvent};
+use crate::journal::{Entry, Journal, Stage};
+use crate::limits::{self, ResourceExceeded};
+use crate::policy;
+use crate::privacy::PrivacyReport;
+use crate::query::{self, Dataset, QueryResult};
+use crate::submitter;
+use crate::vault::{self, ProposalStatus, QueryProposal};
+
+pub struct Pipeline {
+    config: &'static EngineConfig,
+    indexer: Mutex<EventIndexer>,
+    /// Connected on first use.
+    akave: OnceCell<AkaveAdapter>,
+    /// Progress of every proposal the engine picked up, kept across restarts.
+    journal: Mutex<Journal>,
+    /// Epsilon spent on each dataset by differentially private results.
+    budget: Mutex<PrivacyBudget>,
+}
+
+impl Pipeline {
+    pub fn new(config: &'static EngineConfig, journal: Journal, budget: PrivacyBudget) -> Self {
+        Self {
+            config,
+            indexer: Mutex::new(EventIndexer::new()),
+            akave: OnceCell::new(),
+            journal: Mutex::new(journal),
+            budget: Mutex::new(budget),
+        }
+    }
+
+    /// Bring the pending set up to date with `round` and process every pending proposal.
+    pub async fn scan_task(&self, chain: &impl ChainBackend, round: u64) -> Result<()> {
+        let mut indexer = self.indexer.lock().await;
+
+        match indexer.next_range(round) {
+            None => {
+                // No usable cursor (startup, a failed log query or a periodic resync), so rebuild
+                // the pending set from the contract's approved array.
+                let approved = self.get_all_approved_proposals(chain, round).await?;
+                info!(
+                    "Resynced at round {}: {} approved proposal(s)",
+                    round,
+                    approved.len()
+                );
+                indexer.resync(round, approved.iter().map(|p| p.id));
+            }
+            Some(range) if range.is_empty() => {}
+            Some(range) => match self.scan_emits(chain, round, &range).await {
+                Ok(events) => {
+                    self.record_outcomes(&events).await?;
+                    indexer.apply(&range, &events);
+                }
+                Err(err) => {
+                    warn!("Failed to index vault events: {:?}", err);
+                    indexer.reset();
+                }
+            },
+        }
+
+        for proposal_id in indexer.pending() {
+            let proposal = match self.get_proposal(chain, round, proposal_id).await {
+                Ok(proposal) => proposal,
+                Err(err) => {
+                    warn!("Failed to fetch proposal {}: {:?}", proposal_id, err);
+                    continue;
+                }
+            };
+            if proposal.status != ProposalStatus::Approved {
+                match proposal.status {
+                    ProposalStatus::Completed => self.mark_confirmed(proposal_id).await?,
+                    status => self.mark_abandoned(proposal_id, status).await?,
+                }
+                indexer.remove(proposal_id);
+                continue;
+            }
+
+            match self
+                .process_proposal(chain, &proposal)
+                .instrument(info_span!("proposal", proposal_id))
+                .await
+            {
+                Ok(()) => indexer.remove(proposal_id),
+                Err(err) => error!("Failed to process proposal {}: {:?}", proposal_id, err),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Fetch and decode the vault events emitted in the given range of rounds.
+    async fn scan_emits(
+        &self,
+        chain: &impl ChainBackend,
+        round: u64,
+        range: &RangeInclusive<u64>,
+    ) -> Result<Vec<VaultEvent>> {
+        let mut decoded = Vec::new();
+        for log in chain.get_logs(round, range).await? {
+            if let Some(event) = events::decode_log(&log)? {
+                info!("Round {:>6}: {:?}", log.block_number, event);
+                decoded.push(event);
+            }
+        }
+
+        Ok(decoded)
+    }
+
+    /// Bring the journal in line with proposals that the events show to be consumed, rejected
+    /// or expired. They are dropped from the pending set without being fetched again.
+    async fn record_outcomes(&self, events: &[VaultEvent]) -> Result<()> {
+        for event in events {
+            match event {
+                VaultEvent::QueryCompleted(e) => self.mark_confirmed(e.proposal_id).await?,
+                VaultEvent::ProposalRejected(e) => {
+                    self.mark_abandoned(e.proposal_id, ProposalStatus::Rejected)
+                        .await?
+                }
+                VaultEvent::ProposalExpired(e) => {
+                    self.mark_abandoned(e.proposal_id, ProposalStatus::Expired)
+                        .await?
+                }
+                _ => {}
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Collect every approved proposal. This is done before processing any of them: consuming a
+    /// proposal swap-removes it from the contract's approved array, which would shift later pages.
+    async fn get_all_approved_proposals(
+        &self,
+        chain: &impl ChainBackend,
+        round: u64,
+    ) -> Result<Vec<QueryProposal>> {
+        let mut approved = Vec::new();
+        let mut offset = 0u64;
+        loop {
+            let page = self
+                .get_approved_proposals(chain, round, offset, self.config.approved_page_size)
+                .await?;
+            let fetched = page.len() as u64;
+            approved.extend(page);
+
+            if fetched < self.config.approved_page_size {
+                break;
+            }
+            offset += fetched;
+        }
+
+        Ok(approved)
+    }
+
+    /// Run a single approved proposal through the execution pipeline, ending with the result
+    /// being handed back to the vault via `consumeProposal`. Work recorded in the journal by an
+    /// earlier attempt, possibly before a restart, is not repeated.
+    async fn process_proposal(
+        &self,
+        chain: &impl ChainBackend,
+        proposal: &QueryProposal,
+    ) -> Result<()> {
+        let entry = self.journal.lock().await.get(proposal.id).cloned();
+        let encrypted_result = match entry {
+            Some(entry) if entry.stage.is_final() => {
+                info!(
+                    "Proposal {} is already {:?}, skipping",
+                    proposal.id, entry.stage
+                );
+                return Ok(());
+            }
+            Some(Entry {
+                stage: Stage::TxSubmitted,
+                encrypted_result: Some(encrypted_result),
+                ..
+            }) => {
+                // The proposal is still approved, so the transaction sent by an earlier attempt
+                // has not landed. Sending it again is safe as the vault consumes a proposal once.
+                warn!(
+                    "Proposal {} is still approved after its consumeProposal was sent, sending it again",
+                    proposal.id
+                );
+                encrypted_result
+            }
+            Some(Entry {
+                stage: Stage::ResultEncrypted,
+                encrypted_result: Some(encrypted_result),
+                ..
+            }) => {
+                info!("Resuming proposal {} with its stored result", proposal.id);
+                encrypted_result
+            }
+            _ => self.execute_proposal(proposal).await?,
+        };
+
+        let result = self.deliver_result(proposal.id, encrypted_result).await?;
+        self.consume_proposal(chain, proposal.id, result).await
+    }
+
+    /// The result string handed to the vault: the encrypted result itself when it is small
+    /// enough to store on chain, otherwise a pointer to it uploaded to Akave. An upload that fails
+    /// is retried with the stored result on a later round.
+    async fn deliver_result(&self, proposal_id: u64, encrypted_result: String) -> Result<String> {
+        if encrypted_result.len() as u64 <= self.config.inline_result_bytes {
+            return Ok(encrypted_result);
+        }
+
+        let key = delivery::result_key(&self.config.contract_address, proposal_id);
+        let pointer = self
+            .akave()
+            .await
+            .upload_result(&self.config.akave.result_bucket, &key, &encrypted_result)
+            .await?;
+        info!(
+            "Uploaded the {} byte result of proposal {} to {}/{}",
+            pointer.size, proposal_id, pointer.bucket, pointer.key
+        );
+
+        pointer.to_json()
+    }
+
+    /// Execute the query of a proposal and encrypt its result, recording each step.
+    async fn execute_proposal(&self, proposal: &QueryProposal) -> Result<String> {
+        info!(
+            "Processing proposal {} from {:?}: {}",
+            proposal.id, proposal.requester, proposal.sql_query
+        );
+        self.journal
+            .lock()
+            .await
+            .advance(proposal.id, Stage::Claimed)?;
+
+        let objects = self.list_datasets().await?;
+        let result = match self.plan_query(proposal, objects).await {
+            Ok(objects) => {
+                // The downloaded datasets are removed with the directory once the query ran.
+                let dir = self.dataset_dir()?;
+                let datasets = self.fetch_datasets(&objects, dir.path()).await?;
+                self.journal
+                    .lock()
+                    .await
+                    .advance(proposal.id, Stage::Executing)?;
+                self.execute_query(proposal, datasets).await
+            }
+            Err(err) => Err(err),
+        };
+        let privacy = result
+            .as_ref()
+            .ok()
+            .and_then(|result| result.privacy.clone());
+        let encrypted_result =
+            report_resource_exceeded(proposal.id, result.and_then(|result| result.to_json()))
+                .and_then(|result| encryption::encrypt(&proposal.public_key, result.as_bytes()));
+
+        // The epsilon of a result is only charged once it is sealed, and before it is stored.
+        // A stored result whose upload or submission fails is delivered on a later round instead
+        // of being computed again.
+        let encrypted_result = match (encrypted_result, privacy) {
+            (Ok(encrypted_result), Some(report)) => self
+                .charge_budget(proposal.id, &report)
+                .await
+                .map(|()| encrypted_result),
+            (encrypted_result, _) => encrypted_result,
+        };
+
+        let mut journal = self.journal.lock().await;
+        match encrypted_result {
+            Ok(encrypted_result) => {
+                journal.store_result(proposal.id, encrypted_result.clone())?;
+                Ok(encrypted_result)
+            }
+            Err(err) => {
+                // A query or public key that fails once fails every time.
+                journal.fail(proposal.id, format!("{:#}", err))?;
+                Err(err)
+            }
+        }
+    }
+
+    /// Check the SQL of a proposal against the policy and pick the dataset objects it reads,
+    /// within the scan limit and, under differential privacy, the budget of each.
+    async fn plan_query(
+        &self,
+        proposal: &QueryProposal,
+        objects: Vec<Object>,
+    ) -> Result<Vec<Object>> {
+        let sql = &proposal.sql_query;
+
+        let tables: Vec<String> = objects
+            .iter()
+            .map(|object| query::table_name(&object.key))
+            .collect();
+        if let Err(rejection) = policy::check(sql, &tables, &self.config.policy) {
+            warn!(
+                "Proposal {} rejected by the SQL policy: {}",
+                proposal.id,
+                serde_json::to_string(&rejection)?
+            );
+            return Err(rejection.into());
+        }
+
+        let read = anonymity::prepare(sql)?.tables;
+        let objects: Vec<Object> = objects
+            .into_iter()
+            .filter(|object| read.contains(&query::table_name(&object.key).to_lowercase()))
+            .collect();
+        limits::check_scan(
+            objects.iter().map(|object| object.size).sum(),
+            &self.config.limits,
+        )?;
+
+        // The epsilon is only charged once the result is sealed, but a query that cannot be
+        // charged is not worth fetching and running.
+        if let Some(privacy) = &self.config.policy.privacy {
+            let datasets: Vec<String> = objects
+                .iter()
+                .map(|object| query::table_name(&object.key).to_lowercase())
+                .collect();
+            let policy = &self.config.policy;
+            self.budget
+                .lock()
+                .await
+                .check(&datasets, privacy.epsilon, |dataset| {
+                    policy.epsilon_budget(dataset).unwrap_or_default()
+                })?;
+        }
+
+        Ok(objects)
+    }
+
+    /// Execute the SQL query of an approved proposal over the datasets it reads.
+    async fn execute_query(
+        &self,
+        proposal: &QueryProposal,
+        datasets: Vec<Dataset>,
+    ) -> Result<QueryResult> {
+        let sql = proposal.sql_query.clone();
+
+        // DuckDB is blocking, so keep it off the async runtime.
+        let policy = &self.config.policy;
+        let limits = &self.config.limits;
+        let result =
+            tokio::task::spawn_blocking(move || query::run(&datasets, &sql, policy, limits))
+                .await??;
+        info!(
+            "Proposal {} returned {} row(s), {} group(s) suppressed",
+            proposal.id,
+            result.rows.len(),
+            result.suppressed_groups
+        );
+
+        Ok(result)
+    }
+
+    /// Charge the epsilon of a differentially private result to the datasets it read.
+    async fn charge_budget(&self, proposal_id: u64, report: &PrivacyReport) -> Result<()> {
+        let policy = &self.config.policy;
+        self.budget.lock().await.charge(
+            proposal_id,
+            &report.datasets,
+            report.epsilon_spent,
+            report.delta,
+            |dataset| policy.epsilon_budget(dataset).unwrap_or_default(),
+        )?;
+        info!(
+            "Proposal {} spent epsilon {} on {}",
+            proposal_id,
+            report.epsilon_spent,
+            report.datasets.join(", ")
+        );
+
+        Ok(())
+    }
+
+    /// The Akave adapter, connected on first use.
+    async fn akave(&self) -> &AkaveAdapter {
+        self.akave
+            .get_or_init(|| AkaveAdapter::connect(&self.config.akave))
+            .await
+    }
+
+    /// List the dataset objects that proposal queries run over.
+    async fn list_datasets(&self) -> Result<Vec<Object>> {
+        let akave = self.akave().await;
+        akave.list_datasets(akave.bucket()).await
+    }
+
+    /// A fresh directory for the datasets of one proposal, next to the files DuckDB spills to.
+    fn dataset_dir(&self) -> Result<TempDir> {
+        let spill_dir = self
+            .config
+            .limits
+            .spill_dir
+            .clone()
+            .unwrap_or_else(std::env::temp_dir);
+        fs::create_dir_all(&spill_dir)?;
+        Ok(tempfile::Builder::new()
+            .prefix("datasets-")
+            .tempdir_in(spill_dir)?)
+    }
+
+    /// Download the dataset objects a proposal query reads into `dir`.
+    async fn fetch_datasets(&self, objects: &[Object], dir: &Path) -> Result<Vec<Dataset>> {
+        let akave = self.akave().await;
+        let datasets = akave.fetch_datasets(akave.bucket(), objects, dir).await?;
+        info!(
+            "Fetched {} dataset(s) from bucket {}",
+            datasets.len(),
+            akave.bucket()
+        );
+
+        Ok(datasets)
+    }
+
+    async fn get_approved_proposals(
+        &self,
+        chain: &impl ChainBackend,
+        round: u64,
+        offset: u64,
+        limit: u64,
+    ) -> Result<Vec<QueryProposal>> {
+        let data = vault::encode_get_approved_proposals(offset, limit);
+        let response = chain.simulate_call(round, data).await?;
+        vault::decode_get_approved_proposals(&response)
+    }
+
+    async fn get_proposal(
+        &self,
+        chain: &impl ChainBackend,
+        round: u64,
+        proposal_id: u64,
+    ) -> Result<QueryProposal> {
+        let data = vault::encode_get_proposal(proposal_id);
+        let response = chain.simulate_call(round, data).await?;
+        vault::decode_get_proposal(&response)
+    }
+
+    /// Hand the encrypted result of a proposal to the vault.
+    ///
+    /// Preparing the transaction (gas estimation and fee lookup) is retried on transient
+    /// failures. Once it has been sent, which the journal records across restarts, a failed
+    /// submission is resolved by watching the proposal's status; it is only sent again on a
+    /// later round if the proposal is still approved by then.
+    async fn consume_proposal(
+        &self,
+        chain: &impl ChainBackend,
+        proposal_id: u64,
+        encrypted_result: String,
+    ) -> Result<()> {
+        let resending = self.journal.lock().await.stage(proposal_id) == Some(Stage::TxSubmitted);
+        let data = vault::encode_consume_proposal(proposal_id, &encrypted_result);
+        let tx = match submitter::retry("prepare consumeProposal", || {
+            chain.prepare_call(data.clone())
+        })
+        .await
+        {
+            Ok(tx) => tx,
+            Err(err) if submitter::is_transient(&err) => return Err(err),
+            Err(err) => {
+                return self
+                    .fail_submission(chain, proposal_id, resending, err)
+                    .await;
+            }
+        };
+
+        if !resending {
+            self.journal
+                .lock()
+                .await
+                .advance(proposal_id, Stage::TxSubmitted)?;
+        }
+
+        info!(
+            "Submitting consumeProposal for proposal {} ({} bytes of calldata)",
+            proposal_id,
+            data.len()
+        );
+
+        match chain.submit_tx(tx).await {
+            Ok(TxOutcome::Failed(err)) => {
+                self.fail_submission(chain, proposal_id, resending, err.into())
+                    .await
+            }
+            Ok(TxOutcome::Succeeded) => {
+                info!("Consumed proposal {}", proposal_id);
+                self.mark_confirmed(proposal_id).await
+            }
+            Err(err) => {
+                warn!(
+                    "Submitting consumeProposal for proposal {} failed, checking whether it landed: {:?}",
+                    proposal_id, err
+                );
+                submitter::retry("confirm consumeProposal", || {
+                    self.confirm_consumed(chain, proposal_id)
+                })
+                .await
+                .map_err(|confirm_err| {
+                    confirm_err.context(format!("consumeProposal submission failed: {}", err))
+                })
+            }
+        }
+    }
+
+    /// Mark a proposal as failed for good after its `consumeProposal` failed. When the
+    /// transaction was sent again, the one sent earlier may have consumed the proposal in the
+    /// meantime, which confirms it instead.
+    async fn fail_submission(
+        &self,
+        chain: &impl ChainBackend,
+        proposal_id: u64,
+        resending: bool,
+        err: anyhow::Error,
+    ) -> Result<()> {
+        if resending && self.confirm_consumed(chain, proposal_id).await.is_ok() {
+            return Ok(());
+        }
+
+        self.journal
+            .lock()
+            .await
+            .fail(proposal_id, format!("{:#}", err))?;
+        Err(err)
+    }
+
+    /// Succeed once the vault reports the proposal as completed.
+    async fn confirm_consumed(&self, chain: &impl ChainBackend, proposal_id: u64) -> Result<()> {
+        let round = chain.current_round().await?;
+        let proposal = self.get_proposal(chain, round, proposal_id).await?;
+
+        match proposal.status {
+            ProposalStatus::Completed => {
+                info!("Consumed proposal {}", proposal_id);
+                self.mark_confirmed(proposal_id).await
+            }
+            status => Err(anyhow!(
+                "proposal {} is {:?}, not completed",
+                proposal_id,
+                status
+            )),
+        }
+    }
+
+    /// Record that the transaction sent for a proposal took effect.
+    async fn mark_confirmed(&self, proposal_id: u64) -> Result<()> {
+        let mut journal = self.journal.lock().await;
+        if journal.stage(proposal_id) == Some(Stage::TxSubmitted) {
+            journal.advance(proposal_id, Stage::Confirmed)?;
+        }
+
+        Ok(())
+    }
+
+    /// Record that a proposal the engine picked up can no longer be consumed.
+    async fn mark_abandoned(&self, proposal_id: u64, status: ProposalStatus) -> Result<()> {
+        let mut journal = self.journal.lock().await;
+        if journal
+            .stage(proposal_id)
+            .is_some_and(|stage| !stage.is_final())
+        {
+            journal.fail(proposal_id, format!("proposal is {:?}", status))?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Hand the requester a resource exceeded outcome in place of the result of a query that ran
+/// into one of its limits.
+fn report_resource_exceeded(proposal_id: u64, result: Result<String>) -> Result<String> {
+    match result {
+        Err(err) => match err.downcast_ref::<ResourceExceeded>() {
+            Some(exceeded) => {
+                warn!("Proposal {} exceeded its limits: {}", proposal_id, exceeded);
+                exceeded.to_json()
+            }
+            None => Err(err),
+        },
+        result => result,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chain::fake::{EXPIRATION_PERIOD, FakeChain};
+    use crate::delivery::ResultPointer;
+    use crate::evm::EvmChain;
+    use crate::vault::CompletedQuery;
+    use ethabi::Address;
+    use std::collections::HashMap;
+    use std::path::Path;
+    use std::sync::{Arc, Mutex as StdMutex};
+
+    const APP_ID: [u8; 21] = [7; 21];
+    const DATASET: &str = "names-and-cities.parquet";
+    const QUERY: &str = "SELECT COUNT(*) AS n FROM names_and_cities";
+
+    struct Setup<C> {
+        chain: C,
+        pipeline: Pipeline,
+        secret_key: k256::SecretKey,
+        public_key: String,
+        akave: mockito::ServerGuard,
+        /// Download of the sample dataset.
+        dataset: mockito::Mock,
+        _dir: tempfile::TempDir,
+    }
+
+    /// A pipeline on a fresh fake vault.
+    async fn setup() -> Setup<FakeChain> {
+        setup_with(FakeChain::new(APP_ID)).await
+    }
+
+    /// A pipeline on `chain`, reading datasets from a mock Akave bucket that holds the sample
+    /// dataset.
+    async fn setup_with<C>(chain: C) -> Setup<C> {
+        setup_with_vars(chain, &[]).await
+    }
+
+    /// Like `setup_with`, with extra configuration variables.
+    async fn setup_with_vars<C>(chain: C, extra: &[(&'static str, &str)]) -> Setup<C> {
+        let mut akave = mockito::Server::new_async().await;
+        akave
+            .mock("GET", "/datasets/?list-type=2")
+            .with_status(200)
+            .with_header("content-type", "application/xml")
+            .with_body(format!(
+                r#"<?xml version="1.0" encoding="UTF-8"?>
+<ListBucketResult xmlns="http://s3.amazonaws.com/doc/2006-03-01/">
+  <Name>datasets</Name>
+  <KeyCount>1</KeyCount>
+  <MaxKeys>1000</MaxKeys>
+  <IsTruncated>false</IsTruncated>
+  <Contents>
+    <Key>{}</Key>
+    <LastModified>2025-06-01T00:00:00.000Z</LastModified>
+    <ETag>"abc"</ETag>
+    <Size>4</Size>
+    <StorageClass>STANDARD</StorageClass>
+  </Contents>
+</ListBucketResult>"#,
+                DATASET
+            ))
+            .create_async()
+            .await;
+        let dataset = akave
+            .mock(
+                "GET",
+                format!("/datasets/{}?x-id=GetObject", DATASET).as_str(),
+            )
+            .with_status(200)
+            .with_body(include_bytes!("../../rofl-bun/names-and-cities.parquet"))
+            .create_async()
+            .await;
+
+        let dir = tempfile::tempdir().unwrap();
+        let vars: HashMap<&str, String> = [
+            ("ENGINE_NETWORK", "localnet".to_owned()),
+            (
+                "ENGINE_APP_ID",
+                "rofl1qzshync9pv2als5y0n33wrjycwc2pp0y0u8yw3k4".to_owned(),
+            ),
+            (
+                "ENGINE_CONTRACT_ADDRESS",
+                "0xcDC557d454C09141d7bbb1E67c39BF500a348A5a".to_owned(),
+            ),
+            ("ENGINE_DATA_DIR", dir.path().display().to_string()),
+            ("AKAVE_ENDPOINT", akave.url()),
+            ("AKAVE_BUCKET", "datasets".to_owned()),
+            ("AKAVE_ACCESS_KEY", "access".to_owned()),
+            ("AKAVE_SECRET_KEY", "secret".to_owned()),
+        ]
+        .into_iter()
+        .chain(
+            extra
+                .iter()
+                .map(|(name, value)| (*name, (*value).to_owned())),
+        )
+        .collect();
+        let config = EngineConfig::load(|name| vars.get(name).cloned()).unwrap();
+        let config: &'static EngineConfig = Box::leak(Box::new(config));
+
+        let secret_key = k256::SecretKey::random(&mut rand::rngs::OsRng);
+        let public_key = hex::encode(secret_key.public_key().to_sec1_bytes());
+
+        Setup {
+            chain,
+            pipeline: Pipeline::new(
+                config,
+                open_journal(dir.path()),
+                PrivacyBudget::open(dir.path().join("privacy-budget.jsonl")).unwrap(),
+            ),
+            secret_key,
+            public_key,
+            akave,
+            dataset,
+            _dir: dir,
+        }
+    }
+
+    fn open_journal(dir: &Path) -> Journal {
+        Journal::open(dir.join("journal.jsonl")).unwrap()
+    }
+
+    impl<C: ChainBackend> Setup<C> {
+        async fn scan(&self) {
+            let round = self.chain.current_round().await.unwrap();
+            self.pipeline.scan_task(&self.chain, round).await.unwrap();
+        }
+
+        async fn stage(&self, proposal_id: u64) -> Option<Stage> {
+            self.pipeline.journal.lock().await.stage(proposal_id)
+        }
+
+        /// Decrypt a stored result with the requester's key.
+        fn decrypt(&self, completed: &CompletedQuery) -> serde_json::Value {
+            assert_eq!(completed.original_query, QUERY);
+            assert_eq!(completed.public_key, self.public_key);
+            let plaintext =
+                encryption::decrypt(&self.secret_key.to_bytes(), &completed.encrypted_result)
+                    .unwrap();
+            serde_json::from_slice(&plaintext).unwrap()
+        }
+    }
+
+    impl Setup<FakeChain> {
+        /// Submit a proposal and have governance approve it.
+        fn approved_proposal(&self) -> u64 {
+            let proposal_id =
+                self.chain
+                    .propose(Address::repeat_byte(0x11), QUERY, &self.public_key);
+            self.chain.approve(proposal_id);
+            proposal_id
+        }
+
+        async fn next_round(&self) {
+            self.chain.advance(1);
+            self.scan().await;
+        }
+
+        /// Journal a proposal as an earlier process leaves it when the `consumeProposal`
+        /// transaction it sent never lands.
+        async fn submitted_before_restart(&self, proposal_id: u64) {
+            let result = serde_json::json!({ "columns": ["n"], "rows": [[4]] });
+            let encrypted_result =
+                encryption::encrypt(&self.public_key, result.to_string().as_bytes()).unwrap();
+            let mut journal = self.pipeline.journal.lock().await;
+            journal.advance(proposal_id, Stage::Claimed).unwrap();
+            journal.store_result(proposal_id, encrypted_result).unwrap();
+            journal.advance(proposal_id, Stage::TxSubmitted).unwrap();
+        }
+
+        /// Decrypt the result the vault stored for a proposal.
+        fn result(&self, proposal_id: u64) -> serde_json::Value {
+            self.decrypt(&self.chain.completed_query(proposal_id).unwrap())
+        }
+    }
+
+    #[tokio::test]
+    async fn consumes_approved_proposals() {
+        let setup = setup().await;
+        let proposal_id = setup.approved_proposal();
+
+        // The first round resyncs from the approved array and processes the proposal.
+        setup.next_round().await;
+
+        assert_eq!(
+            setup.chain.proposal(proposal_id).unwrap().status,
+            ProposalStatus::Completed
+        );
+        assert_eq!(setup.stage(proposal_id).await, Some(Stage::Confirmed));
+        let result = setup.result(proposal_id);
+        assert_eq!(result["columns"], serde_json::json!(["n"]));
+        assert_eq!(result["rows"].as_array().unwrap().len(), 1);
+
+        // Nothing is sent again on later rounds.
+        setup.next_round().await;
+        setup.next_round().await;
+        assert_eq!(setup.chain.transactions().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn picks_up_proposals_from_events() {
+        let setup = setup().await;
+        setup.next_round().await;
+
+        let proposal_id = setup.approved_proposal();
+        setup.next_round().await;
+
+        assert_eq!(setup.stage(proposal_id).await, Some(Stage::Confirmed));
+        assert_eq!(
+            setup.result(proposal_id)["columns"],
+            serde_json::json!(["n"])
+        );
+        assert_eq!(setup.chain.transactions().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn confirms_lost_submissions_without_resending() {
+        let setup = setup().await;
+        let proposal_id = setup.approved_proposal();
+        setup.chain.lose_next_submission();
+
+        setup.next_round().await;
+        setup.next_round().await;
+
+        assert_eq!(setup.stage(proposal_id).await, Some(Stage::Confirmed));
+        assert_eq!(setup.chain.transactions().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn resends_submissions_that_never_land() {
+        let setup = setup().await;
+        let proposal_id = setup.approved_proposal();
+        setup.submitted_before_restart(proposal_id).await;
+
+        setup.next_round().await;
+
+        assert_eq!(setup.stage(proposal_id).await, Some(Stage::Confirmed));
+        assert_eq!(setup.result(proposal_id)["rows"], serde_json::json!([[4]]));
+        assert_eq!(setup.chain.transactions().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn fails_submissions_that_never_land_before_expiry() {
+        let setup = setup().await;
+        let proposal_id = setup.approved_proposal();
+        setup.submitted_before_restart(proposal_id).await;
+        setup.chain.advance(EXPIRATION_PERIOD);
+
+        setup.next_round().await;
+
+        let entry = setup
+            .pipeline
+            .journal
+            .lock()
+            .await
+            .get(proposal_id)
+            .cloned()
+            .unwrap();
+        assert_eq!(entry.stage, Stage::Failed);
+        assert!(
+            entry.error.as_deref().unwrap().contains("Proposal expired"),
+            "{:?}",
+            entry.error
+        );
+        assert!(setup.chain.transactions().is_empty());
+    }
+
+    #[tokio::test]
+    async fn restarts_executions_interrupted_by_a_crash() {
+        let setup = setup().await;
+        let proposal_id = setup.approved_proposal();
+        // An earlier process was killed while the query was running.
+        {
+            let mut journal = setup.pipeline.journal.lock().await;
+            journal.advance(proposal_id, Stage::Claimed).unwrap();
+            journal.advance(proposal_id, Stage::Executing).unwrap();
+        }
+
+        let round = setup.chain.current_round().await.unwrap();
+        let proposal = setup
+            .pipeline
+            .get_proposal(&setup.chain, round, proposal_id)
+            .await
+            .unwrap();
+        setup
+            .pipeline
+            .process_proposal(&setup.chain, &proposal)
+            .await
+            .unwrap();
+
+        assert_eq!(setup.stage(proposal_id).await, Some(Stage::Confirmed));
+        assert_eq!(
+            setup.result(proposal_id)["columns"],
+            serde_json::json!(["n"])
+        );
+        assert_eq!(setup.chain.transactions().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn fails_proposals_the_vault_refuses() {
+        let setup = setup().await;
+        let proposal_id = setup.approved_proposal();
+        // The vault still lists the proposal as approved, but refuses to consume it.
+        setup.chain.advance(EXPIRATION_PERIOD);
+
+        setup.next_round().await;
+
+        let entry = setup
+            .pipeline
+            .journal
+            .lock()
+            .await
+            .get(proposal_id)
+            .cloned()
+            .unwrap();
+        assert_eq!(entry.stage, Stage::Failed);
+        assert!(
+            entry.error.as_deref().unwrap().contains("Proposal expired"),
+            "{:?}",
+            entry.error
+        );
+        assert!(setup.chain.transactions().is_empty());
+        assert!(setup.chain.completed_query(proposal_id).is_none());
+    }
+
+    #[tokio::test]
+    async fn refuses_queries_outside_the_policy() {
+        let setup = setup().await;
+        let proposal_id = setup.chain.propose(
+            Address::repeat_byte(0x11),
+            "COPY names_and_cities TO '/tmp/out.csv'",
+            &setup.public_key,
+        );
+        setup.chain.approve(proposal_id);
+
+        setup.next_round().await;
+
+        let entry = setup
+            .pipeline
+            .journal
+            .lock()
+            .await
+            .get(proposal_id)
+            .cloned()
+            .unwrap();
+        assert_eq!(entry.stage, Stage::Failed);
+        assert_eq!(
+            entry.error.as_deref(),
+            Some("query rejected by policy: COPY statements are not allowed")
+        );
+        assert!(setup.chain.transactions().is_empty());
+    }
+
+    #[tokio::test]
+    async fn refuses_proposals_over_the_privacy_budget() {
+        let setup = setup_with_vars(
+            FakeChain::new(APP_ID),
+            &[
+                ("ENGINE_DP_MECHANISM", "laplace"),
+                ("ENGINE_DP_EPSILON", "0.5"),
+                ("ENGINE_DP_BUDGET", "1"),
+            ],
+        )
+        .await;
+        let proposals: Vec<u64> = (0..3).map(|_| setup.approved_proposal()).collect();
+
+        setup.next_round().await;
+
+        for proposal_id in &proposals[..2] {
+            assert_eq!(setup.stage(*proposal_id).await, Some(Stage::Confirmed));
+            assert_eq!(
+                setup.result(*proposal_id)["privacy"],
+                serde_json::json!({
+                    "mechanism": "laplace",
+                    "epsilon_spent": 0.5,
+                    "datasets": ["names_and_cities"],
+                })
+            );
+        }
+        let entry = setup
+            .pipeline
+            .journal
+            .lock()
+            .await
+            .get(proposals[2])
+            .cloned()
+            .unwrap();
+        assert_eq!(entry.stage, Stage::Failed);
+        assert!(
+            entry
+                .error
+                .as_deref()
+                .unwrap()
+                .starts_with("privacy budget of names_and_cities exceeded"),
+            "{:?}",
+            entry.error
+        );
+        assert_eq!(
+            setup.pipeline.budget.lock().await.spent("names_and_cities"),
+            1.0
+        );
+    }
+
+    #[tokio::test]
+    async fn refuses_spent_budgets_before_fetching_datasets() {
+        let setup = setup_with_vars(
+            FakeChain::new(APP_ID),
+            &[
+                ("ENGINE_DP_MECHANISM", "laplace"),
+                ("ENGINE_DP_EPSILON", "0.5"),
+                ("ENGINE_DP_BUDGET", "1"),
+            ],
+        )
+        .await;
+        setup
+            .pipeline
+            .budget
+            .lock()
+            .await
+            .charge(100, &["names_and_cities".into()], 0.75, None, |_| 1.0)
+            .unwrap();
+        let proposal_id = setup.approved_proposal();
+
+        setup.next_round().await;
+
+        let entry = setup
+            .pipeline
+            .journal
+            .lock()
+            .await
+            .get(proposal_id)
+            .cloned()
+            .unwrap();
+        assert_eq!(entry.stage, Stage::Failed);
+        assert!(
+            entry
+                .error
+                .as_deref()
+                .unwrap()
+                .starts_with("privacy budget of names_and_cities exceeded"),
+            "{:?}",
+            entry.error
+        );
+        assert!(!setup.dataset.matched_async().await);
+        assert!(setup.chain.transactions().is_empty());
+    }
+
+    #[tokio::test]
+    async fn spends_no_budget_on_results_that_cannot_be_sealed() {
+        let setup = setup_with_vars(
+            FakeChain::new(APP_ID),
+            &[
+                ("ENGINE_DP_MECHANISM", "laplace"),
+                ("ENGINE_DP_EPSILON", "0.5"),
+            ],
+        )
+        .await;
+        let proposal_id =
+            setup
+                .chain
+                .propose(Address::repeat_byte(0x11), QUERY, "not a public key");
+        setup.chain.approve(proposal_id);
+
+        setup.next_round().await;
+
+        assert_eq!(setup.stage(proposal_id).await, Some(Stage::Failed));
+        assert_eq!(
+            setup.pipeline.budget.lock().await.spent("names_and_cities"),
+            0.0
+        );
+    }
+
+    #[tokio::test]
+    async fn reports_queries_over_their_resource_limits() {
+        // The listed sample dataset is 4 bytes.
+        let setup = setup_with_vars(
+            FakeChain::new(APP_ID),
+            &[("ENGINE_QUERY_MAX_SCAN_BYTES", "3")],
+        )
+        .await;
+        let proposal_id = setup.approved_proposal();
+
+        setup.next_round().await;
+
+        assert_eq!(setup.stage(proposal_id).await, Some(Stage::Confirmed));
+        assert_eq!(
+            setup.result(proposal_id),
+            serde_json::json!({
+                "outcome": "resource_exceeded",
+                "resource": "bytes_scanned",
+                "limit": 3,
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn delivers_large_results_through_akave() {
+        let mut setup = setup_with_vars(
+            FakeChain::new(APP_ID),
+            &[("ENGINE_INLINE_RESULT_BYTES", "16")],
+        )
+        .await;
+        let proposal_id = setup.approved_proposal();
+        let uploaded = Arc::new(StdMutex::new(Vec::new()));
+        let body = uploaded.clone();
+        let upload = setup
+            .akave
+            .mock(
+                "PUT",
+                mockito::Matcher::Regex(format!(
+                    "^/datasets/results/0xcdc557d454c09141d7bbb1e67c39bf500a348a5a/{}\\b",
+                    proposal_id
+                )),
+            )
+            .with_status(200)
+            .with_body_from_request(move |request| {
+                *body.lock().unwrap() = request.body().unwrap().clone();
+                Vec::new()
+            })
+            .create_async()
+            .await;
+
+        setup.next_round().await;
+
+        assert_eq!(setup.stage(proposal_id).await, Some(Stage::Confirmed));
+        upload.assert_async().await;
+        let completed = setup.chain.completed_query(proposal_id).unwrap();
+        let pointer = ResultPointer::parse(&completed.encrypted_result)
+            .unwrap()
+            .unwrap();
+        assert_eq!(pointer.bucket, "datasets");
+        assert_eq!(
+            pointer.key,
+            format!(
+                "results/0xcdc557d454c09141d7bbb1e67c39bf500a348a5a/{}",
+                proposal_id
+            )
+        );
+
+        // The requester fetches the envelope, checks it against the pointer and decrypts it.
+        let envelope = String::from_utf8(uploaded.lock().unwrap().clone()).unwrap();
+        pointer.verify(envelope.as_bytes()).unwrap();
+        let result = setup.decrypt(&CompletedQuery {
+            encrypted_result: envelope,
+            ..completed
+        });
+        assert_eq!(result["columns"], serde_json::json!(["n"]));
+    }
+
+    #[tokio::test]
+    async fn consumes_proposals_on_the_compiled_vault() {
+        let setup = setup_with(EvmChain::deploy(APP_ID).unwrap()).await;
+        setup.scan().await;
+
+        let requester = Address::repeat_byte(0x11);
+        let proposal_id = setup
+            .chain
+            .propose_query(requester, QUERY, &setup.public_key)
+            .unwrap();
+        setup.chain.approve(proposal_id).unwrap();
+
+        // The proposal is picked up from the vault's events.
+        setup.chain.advance(1);
+        setup.scan().await;
+
+        assert_eq!(setup.stage(proposal_id).await, Some(Stage::Confirmed));
+        let round = setup.chain.current_round().await.unwrap();
+        let proposal = setup
+            .pipeline
+            .get_proposal(&setup.chain, round, proposal_id)
+            .await
+            .unwrap();
+        assert_eq!(proposal.status, ProposalStatus::Completed);
+        assert_eq!(proposal.requester, requester);
+
+        let completed = setup.chain.completed_query(proposal_id).unwrap();
+        assert_eq!(completed.proposal_id, proposal_id);
+        assert!(completed.completed_timestamp > proposal.timestamp);
+        let result = setup.decrypt(&completed);
+        assert_eq!(result["columns"], serde_json::json!(["n"]));
+        assert_eq!(result["rows"].as_array().unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,721 @@
+//! Policy check of proposal SQL, run before a query reaches DuckDB.
+//!
+//! The vault only checks that a query is non-empty and short, so anything a governance vote
+//! approves would otherwise run inside the TEE. A query passes when it is a single read-only
+//! `SELECT` over the registered dataset tables, calls no function that reads files or reaches the
+//! network, and projects the configured PII columns only through aggregates. Projections are
+//! checked in every `SELECT` of the query, subqueries and CTEs included, as their rows can reach
+//! the result. While PII columns are set, columns may not be renamed by a table alias and whole
+//! rows may not be projected, as either would hide a PII column behind another name. Every
+//! violation is reported, not just the first one.
+use std::collections::HashSet;
+use std::fmt;
+use std::ops::ControlFlow;
+
+use serde::Serialize;
+use sqlparser::ast::{
+    Expr, Function, ObjectName, Query, SelectItem, SetExpr, Statement, TableAlias, TableFactor,
+    Visit, Visitor,
+};
+use sqlparser::dialect::DuckDbDialect;
+use sqlparser::parser::Parser;
+
+use crate::config::PolicyConfig;
+
+/// Aggregates that may take a PII column. `MIN`, `MAX` and friends are missing on purpose, as
+/// they return one of the input values.
+const PII_SAFE_AGGREGATES: &[&str] = &[
+    "count",
+    "approx_count_distinct",
+    "sum",
+    "avg",
+    "mean",
+    "stddev",
+    "stddev_pop",
+    "stddev_samp",
+    "variance",
+    "var_pop",
+    "var_samp",
+];
+
+/// Functions that read files, list directories, run dynamic SQL or reach other databases.
+const EXTERNAL_FUNCTIONS: &[&str] = &[
+    "glob",
+    "getenv",
+    "query",
+    "query_table",
+    "sniff_csv",
+    "load_extension",
+    "parquet_metadata",
+    "parquet_schema",
+    "parquet_file_metadata",
+    "parquet_kv_metadata",
+];
+
+/// A rule a query breaks.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "rule", rename_all = "snake_case")]
+pub enum Violation {
+    /// The query does not parse.
+    Syntax { message: String },
+    /// The query holds no statement or more than one.
+    StatementCount { count: usize },
+    /// A statement other than a query, such as `COPY`, `ATTACH` or `INSTALL`.
+    NotSelect { statement: String },
+    /// `SELECT ... INTO`, which creates a table.
+    SelectInto,
+    /// A table that is neither a registered dataset nor a CTE of the query.
+    UnknownTable { table: String },
+    /// A table function in `FROM`, which can read files or reach the network.
+    TableFunction { function: String },
+    /// A function reading outside the registered datasets.
+    ExternalFunction { function: String },
+    /// A PII column projected outside an aggregate.
+    PiiColumn { column: String },
+    /// A wildcard projection, which can include PII columns.
+    Wildcard,
+    /// A table alias with a column list, which can rename PII columns.
+    ColumnAliases { table: String },
+    /// A table or alias projected as a whole row, which can include PII columns.
+    WholeRow { table: String },
+    /// `PIVOT` or `UNPIVOT`, which can move PII columns into other columns or rows.
+    Pivot { operation: String },
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Violation::Syntax { message } => write!(f, "invalid SQL: {}", message),
+            Violation::StatementCount { count } => {
+                write!(f, "expected a single statement, found {}", count)
+            }
+            Violation::NotSelect { statement } => {
+                write!(f, "{} statements are not allowed", statement)
+            }
+            Violation::SelectInto => f.write_str("SELECT INTO is not allowed"),
+            Violation::UnknownTable { table } => write!(f, "unknown table {}", table),
+            Violation::TableFunction { function } => {
+                write!(f, "table function {} is not allowed", function)
+            }
+            Violation::ExternalFunction { function } => {
+                write!(f, "function {} is not allowed", function)
+            }
+            Violation::PiiColumn { column } => {
+                write!(f, "PII column {} may only be aggregated", column)
+            }
+            Violation::Wildcard => f.write_str("wildcard projections may include PII columns"),
+            Violation::ColumnAliases { table } => {
+                write!(f, "column aliases of {} may rename PII columns", table)
+            }
+            Violation::WholeRow { table } => {
+                write!(f, "whole rows of {} may include PII columns", table)
+            }
+            Violation::Pivot { operation } => {
+                write!(f, "{} may move PII columns out of their columns", operation)
+            }
+        }
+    }
+}
+
+/// Why a query was refused.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Rejection {
+    pub violations: Vec<Violation>,
+}
+
+impl fmt::Display for Rejection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let violations: Vec<String> = self.violations.iter().map(|v| v.to_string()).collect();
+        write!(f, "query rejected by policy: {}", violations.join("; "))
+    }
+}
+
+impl std::error::Error for Rejection {}
+
+/// Check `sql` against the policy, given the names of the registered tables.
+pub fn check(sql: &str, tables: &[String], policy: &PolicyConfig) -> Result<(), Rejection> {
+    let statements = match Parser::parse_sql(&DuckDbDialect {}, sql) {
+        Ok(statements) => statements,
+        Err(err) => {
+            return Err(Rejection {
+                violations: vec![Violation::Syntax {
+                    message: err.to_string(),
+                }],
+            });
+        }
+    };
+
+    let mut violations = Vec::new();
+    if statements.len() != 1 {
+        violations.push(Violation::StatementCount {
+            count: statements.len(),
+        });
+    }
+
+    for statement in &statements {
+        if !matches!(statement, Statement::Query(_)) {
+            violations.push(Violation::NotSelect {
+                statement: statement_kind(statement),
+            });
+            continue;
+        }
+
+        let mut ctes = CteNames::default();
+        let _ = statement.visit(&mut ctes);
+        let mut aliases = TableAliases::default();
+        let _ = statement.visit(&mut aliases);
+
+        let tables: HashSet<String> = tables
+            .iter()
+            .map(|t| t.to_lowercase())
+            .chain(ctes.0)
+            .collect();
+        let mut checker = Checker {
+            relations: tables.iter().cloned().chain(aliases.0).collect(),
+            tables,
+            pii_columns: policy
+                .pii_columns
+                .iter()
+                .map(|c| c.to_lowercase())
+                .collect(),
+            violations: &mut violations,
+        };
+        let _ = statement.visit(&mut checker);
+    }
+
+    if violations.is_empty() {
+        Ok(())
+    } else {
+        violations.dedup();
+        Err(Rejection { violations })
+    }
+}
+
+/// The leading keywords of a statement, such as `COPY` or `CREATE TABLE`.
+fn statement_kind(statement: &Statement) -> String {
+    let sql = statement.to_string();
+    let words: Vec<&str> = sql.split_whitespace().take(2).collect();
+    match words.as_slice() {
+        [first, second] if matches!(first.to_uppercase().as_str(), "CREATE" | "DROP") => {
+            format!("{} {}", first, second).to_uppercase()
+        }
+        [first, ..] => first.to_uppercase(),
+        [] => String::new(),
+    }
+}
+
+/// The last part of a possibly qualified name, in lower case.
+pub fn last_part(name: &ObjectName) -> String {
+    name.0
+        .last()
+        .and_then(|part| part.as_ident())
+        .map(|ident| ident.value.to_lowercase())
+        .unwrap_or_default()
+}
+
+fn is_external_function(name: &str) -> bool {
+    name.starts_with("read_") || name.ends_with("_scan") || EXTERNAL_FUNCTIONS.contains(&name)
+}
+
+/// The alias a table factor is given, if any.
+fn table_alias(table_factor: &TableFactor) -> Option<&TableAlias> {
+    match table_factor {
+        TableFactor::Table { alias, .. }
+        | TableFactor::Derived { alias, .. }
+        | TableFactor::TableFunction { alias, .. }
+        | TableFactor::Function { alias, .. }
+        | TableFactor::UNNEST { alias, .. }
+        | TableFactor::JsonTable { alias, .. }
+        | TableFactor::OpenJsonTable { alias, .. }
+        | TableFactor::NestedJoin { alias, .. }
+        | TableFactor::Pivot { alias, .. }
+        | TableFactor::Unpivot { alias, .. }
+        | TableFactor::MatchRecognize { alias, .. }
+        | TableFactor::XmlTable { alias, .. } => alias.as_ref(),
+    }
+}
+
+/// Collects the names of the CTEs a statement defines.
+#[derive(Default)]
+struct CteNames(Vec<String>);
+
+impl Visitor for CteNames {
+    type Break = ();
+
+    fn pre_visit_query(&mut self, query: &Query) -> ControlFlow<()> {
+        if let Some(with) = &query.with {
+            self.0.extend(
+                with.cte_tables
+                    .iter()
+                    .map(|cte| cte.alias.name.value.to_lowercase()),
+            );
+        }
+        ControlFlow::Continue(())
+    }
+}
+
+/// Collects the aliases a statement gives its tables, in lower case.
+#[derive(Default)]
+struct TableAliases(Vec<String>);
+
+impl Visitor for TableAliases {
+    type Break = ();
+
+    fn pre_visit_table_factor(&mut self, table_factor: &TableFactor) -> ControlFlow<()> {
+        if let Some(alias) = table_alias(table_factor) {
+            self.0.push(alias.name.value.to_lowercase());
+        }
+        ControlFlow::Continue(())
+    }
+}
+
+struct Checker<'a> {
+    /// Registered tables and CTE names, in lower case.
+    tables: HashSet<String>,
+    /// `tables` and the aliases of the statement, which name whole rows in a projection.
+    relations: HashSet<String>,
+    pii_columns: HashSet<String>,
+    violations: &'a mut Vec<Violation>,
+}
+
+impl Checker<'_> {
+    fn check_body(&mut self, body: &SetExpr) {
+        match body {
+            SetExpr::Select(select) => {
+                if select.into.is_some() {
+                    self.violations.push(Violation::SelectInto);
+                }
+                if !self.pii_columns.is_empty() {
+                    for item in &select.projection {
+                        self.check_projection(item);
+                    }
+                }
+            }
+            SetExpr::SetOperation { left, right, .. } => {
+                self.check_body(left);
+                self.check_body(right);
+            }
+            SetExpr::Insert(statement)
+            | SetExpr::Update(statement)
+            | SetExpr::Delete(statement) => self.violations.push(Violation::NotSelect {
+                statement: statement_kind(statement),
+            }),
+            SetExpr::Table(table) => {
+                let name = table.table_name.clone().unwrap_or_default();
+                if !self.tables.contains(&name.to_lowercase()) {
+                    self.violations
+                        .push(Violation::UnknownTable { table: name });
+                }
+            }
+            // Nested queries are visited on their own.
+            SetExpr::Query(_) | SetExpr::Values(_) => {}
+        }
+    }
+
+    fn check_projection(&mut self, item: &SelectItem) {
+        let expr = match item {
+            SelectItem::UnnamedExpr(expr) | SelectItem::ExprWithAlias { expr, .. } => expr,
+            SelectItem::Wildcard(_) | SelectItem::QualifiedWildcard(..) => {
+                self.violations.push(Violation::Wildcard);
+                return;
+            }
+        };
+
+        let mut references = PiiReferences {
+            pii_columns: &self.pii_columns,
+            relations: &self.relations,
+            aggregate_depth: 0,
+            query_depth: 0,
+            wildcard: false,
+            rows: Vec::new(),
+            columns: Vec::new(),
+        };
+        let _ = expr.visit(&mut references);
+        if references.wildcard {
+            self.violations.push(Violation::Wildcard);
+        }
+        self.violations.extend(
+            references
+                .rows
+                .into_iter()
+                .map(|table| Violation::WholeRow { table }),
+        );
+        self.violations.extend(
+            references
+                .columns
+                .into_iter()
+                .map(|column| Violation::PiiColumn { column }),
+        );
+    }
+}
+
+impl Visitor for Checker<'_> {
+    type Break = ();
+
+    fn pre_visit_query(&mut self, query: &Query) -> ControlFlow<()> {
+        self.check_body(&query.body);
+        ControlFlow::Continue(())
+    }
+
+    fn pre_visit_table_factor(&mut self, table_factor: &TableFactor) -> ControlFlow<()> {
+        if !self.pii_columns.is_empty()
+            && let Some(alias) = table_alias(table_factor)
+            && !alias.columns.is_empty()
+        {
+            self.violations.push(Violation::ColumnAliases {
+                table: alias.name.value.clone(),
+            });
+        }
+
+        match table_factor {
+            TableFactor::Table { name, args, .. } => {
+                // DuckDB reads a file named by a string literal in `FROM`.
+                let quoted = name
+                    .0
+                    .iter()
+                    .any(|part| part.as_ident().and_then(|i| i.quote_style) == Some('\''));
+                if args.is_some() {
+                    self.violations.push(Violation::TableFunction {
+                        function: name.to_string(),
+                    });
+                } else if quoted || name.0.len() != 1 || !self.tables.contains(&last_part(name)) {
+                    self.violations.push(Violation::UnknownTable {
+                        table: name.to_string(),
+                    });
+                }
+            }
+            TableFactor::Function { name, .. } => {
+                self.violations.push(Violation::TableFunction {
+                    function: name.to_string(),
+                });
+            }
+            TableFactor::TableFunction { expr, .. } => {
+                self.violations.push(Violation::TableFunction {
+                    function: expr.to_string(),
+                });
+            }
+            TableFactor::JsonTable { .. }
+            | TableFactor::OpenJsonTable { .. }
+            | TableFactor::XmlTable { .. } => {
+                self.violations.push(Violation::TableFunction {
+                    function: table_factor.to_string(),
+                });
+            }
+            TableFactor::Pivot { .. } if !self.pii_columns.is_empty() => {
+                self.violations.push(Violation::Pivot {
+                    operation: "PIVOT".into(),
+                });
+            }
+            TableFactor::Unpivot { .. } if !self.pii_columns.is_empty() => {
+                self.violations.push(Violation::Pivot {
+                    operation: "UNPIVOT".into(),
+                });
+            }
+            _ => {}
+        }
+        ControlFlow::Continue(())
+    }
+
+    fn pre_visit_expr(&mut self, expr: &Expr) -> ControlFlow<()> {
+        if let Expr::Function(Function { name, .. }) = expr
+            && is_external_function(&last_part(name))
+        {
+            self.violations.push(Violation::ExternalFunction {
+                function: name.to_string(),
+            });
+        }
+        ControlFlow::Continue(())
+    }
+}
+
+/// Finds PII columns referenced by a projection outside aggregates. Subqueries are skipped, as
+/// their projections are checked on their own.
+struct PiiReferences<'a> {
+    pii_columns: &'a HashSet<String>,
+    /// Tables, CTEs and aliases, whose bare name projects a whole row as a struct.
+    relations: &'a HashSet<String>,
+    aggregate_depth: usize,
+    query_depth: usize,
+    /// Whether DuckDB's `COLUMNS(...)` star expression is used.
+    wildcard: bool,
+    rows: Vec<String>,
+    columns: Vec<String>,
+}
+
+impl PiiReferences<'_> {
+    fn is_safe_aggregate(expr: &Expr) -> bool {
+        matches!(expr, Expr::Function(Function { name, .. })
+            if PII_SAFE_AGGREGATES.contains(&last_part(name).as_str()))
+    }
+}
+
+impl Visitor for PiiReferences<'_> {
+    type Break = ();
+
+    fn pre_visit_query(&mut self, _query: &Query) -> ControlFlow<()> {
+        self.query_depth += 1;
+        ControlFlow::Continue(())
+    }
+
+    fn post_visit_query(&mut self, _query: &Query) -> ControlFlow<()> {
+        self.query_depth -= 1;
+        ControlFlow::Continue(())
+    }
+
+    fn pre_visit_expr(&mut self, expr: &Expr) -> ControlFlow<()> {
+        if Self::is_safe_aggregate(expr) {
+            self.aggregate_depth += 1;
+        }
+        if self.aggregate_depth > 0 || self.query_depth > 0 {
+            return ControlFlow::Continue(());
+        }
+
+        let column = match expr {
+            Expr::Identifier(ident) if self.relations.contains(&ident.value.to_lowercase()) => {
+                self.rows.push(ident.value.clone());
+                None
+            }
+            Expr::Identifier(ident) => Some(&ident.value),
+            Expr::CompoundIdentifier(idents) => idents.last().map(|ident| &ident.value),
+            Expr::Function(Function { name, .. }) if last_part(name) == "columns" => {
+                self.wildcard = true;
+                None
+            }
+            _ => None,
+        };
+        if let Some(column) = column
+            && self.pii_columns.contains(&column.to_lowercase())
+        {
+            self.columns.push(column.clone());
+        }
+        ControlFlow::Continue(())
+    }
+
+    fn post_visit_expr(&mut self, expr: &Expr) -> ControlFlow<()> {
+        if Self::is_safe_aggregate(expr) {
+            self.aggregate_depth -= 1;
+        }
+        ControlFlow::Continue(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tables() -> Vec<String> {
+        vec!["names_and_cities".into(), "Visits".into()]
+    }
+
+    fn policy() -> PolicyConfig {
+        PolicyConfig {
+            pii_columns: vec!["name".into(), "Email".into()],
+            ..PolicyConfig::default()
+        }
+    }
+
+    fn violations(sql: &str) -> Vec<Violation> {
+        check(sql, &tables(), &policy())
+            .err()
+            .map(|rejection| rejection.violations)
+            .unwrap_or_default()
+    }
+
+    #[test]
+    fn accepts_aggregates_over_registered_tables() {
+        for sql in [
+            "SELECT COUNT(*) AS n FROM names_and_cities",
+            "SELECT city, COUNT(DISTINCT name) FROM names_and_cities GROUP BY city",
+            "select city from NAMES_AND_CITIES join visits using (city) where name = 'x'",
+            "WITH c AS (SELECT city, COUNT(name) AS people FROM names_and_cities GROUP BY city) SELECT city, people FROM c",
+            "SELECT city FROM names_and_cities UNION SELECT city FROM \"visits\"",
+            "SELECT (SELECT COUNT(*) FROM visits) AS v, AVG(LENGTH(email)) FROM names_and_cities",
+        ] {
+            assert_eq!(violations(sql), vec![], "{}", sql);
+        }
+    }
+
+    #[test]
+    fn rejects_statements_other_than_select() {
+        for (sql, statement) in [
+            ("COPY names_and_cities TO '/tmp/out.csv'", "COPY"),
+            ("ATTACH 'other.db' AS other", "ATTACH"),
+            ("INSTALL httpfs", "INSTALL"),
+            ("CREATE TABLE t AS SELECT 1", "CREATE TABLE"),
+            ("DROP TABLE names_and_cities", "DROP TABLE"),
+            ("PRAGMA version", "PRAGMA"),
+        ] {
+            assert_eq!(
+                violations(sql),
+                vec![Violation::NotSelect {
+                    statement: statement.into()
+                }],
+                "{}",
+                sql
+            );
+        }
+
+        assert_eq!(
+            violations("SELECT 1; SELECT 2"),
+            vec![Violation::StatementCount { count: 2 }]
+        );
+        assert_eq!(
+            violations("SELECT city INTO copy FROM names_and_cities"),
+            vec![Violation::SelectInto]
+        );
+        assert!(matches!(
+            violations("SELEC 1").as_slice(),
+            [Violation::Syntax { .. }]
+        ));
+    }
+
+    #[test]
+    fn rejects_files_and_unknown_tables() {
+        assert_eq!(
+            violations("SELECT COUNT(*) FROM read_csv('/etc/passwd')"),
+            vec![Violation::TableFunction {
+                function: "read_csv".into()
+            }]
+        );
+        assert_eq!(
+            violations("SELECT COUNT(*) FROM 'https://example.com/data.parquet'"),
+            vec![Violation::UnknownTable {
+                table: "'https://example.com/data.parquet'".into()
+            }]
+        );
+        assert_eq!(
+            violations("SELECT COUNT(*) FROM other.names_and_cities"),
+            vec![Violation::UnknownTable {
+                table: "other.names_and_cities".into()
+            }]
+        );
+        assert_eq!(
+            violations(
+                "SELECT city FROM names_and_cities WHERE city IN (SELECT city FROM secrets)"
+            ),
+            vec![Violation::UnknownTable {
+                table: "secrets".into()
+            }]
+        );
+        assert_eq!(
+            violations("SELECT COUNT(*), getenv('HOME') FROM names_and_cities"),
+            vec![Violation::ExternalFunction {
+                function: "getenv".into()
+            }]
+        );
+    }
+
+    #[test]
+    fn keeps_pii_columns_aggregated() {
+        assert_eq!(
+            violations("SELECT n.name, email AS e FROM names_and_cities n"),
+            vec![
+                Violation::PiiColumn {
+                    column: "name".into()
+                },
+                Violation::PiiColumn {
+                    column: "email".into()
+                },
+            ]
+        );
+        assert_eq!(
+            violations("SELECT MAX(name) FROM names_and_cities"),
+            vec![Violation::PiiColumn {
+                column: "name".into()
+            }]
+        );
+        assert_eq!(
+            violations("SELECT * FROM names_and_cities"),
+            vec![Violation::Wildcard]
+        );
+        assert_eq!(
+            violations("SELECT COLUMNS('na.*') FROM names_and_cities"),
+            vec![Violation::Wildcard]
+        );
+
+        // Renaming columns in FROM or projecting whole rows would hide PII columns.
+        assert_eq!(
+            violations("SELECT b FROM names_and_cities AS t(a, b)"),
+            vec![Violation::ColumnAliases { table: "t".into() }]
+        );
+        assert_eq!(
+            violations("SELECT x FROM (SELECT city FROM names_and_cities) AS s(x)"),
+            vec![Violation::ColumnAliases { table: "s".into() }]
+        );
+        assert_eq!(
+            violations("SELECT t FROM names_and_cities t"),
+            vec![Violation::WholeRow { table: "t".into() }]
+        );
+        assert_eq!(
+            violations("SELECT to_json(Names_And_Cities) FROM names_and_cities"),
+            vec![Violation::WholeRow {
+                table: "Names_And_Cities".into()
+            }]
+        );
+        assert_eq!(
+            violations("SELECT t.* FROM names_and_cities t"),
+            vec![Violation::Wildcard]
+        );
+        assert_eq!(
+            violations("SELECT COLUMNS(*) FROM names_and_cities"),
+            vec![Violation::Wildcard]
+        );
+        assert_eq!(
+            violations("SELECT COUNT(t) FROM names_and_cities t"),
+            vec![]
+        );
+        assert_eq!(
+            violations(
+                "SELECT v FROM names_and_cities UNPIVOT (v FOR k IN (\"First Name\", city))"
+            ),
+            vec![Violation::Pivot {
+                operation: "UNPIVOT".into()
+            }]
+        );
+        assert_eq!(
+            violations(
+                "SELECT city FROM names_and_cities PIVOT (COUNT(city) FOR name IN ('Alice', 'Bob'))"
+            ),
+            vec![Violation::Pivot {
+                operation: "PIVOT".into()
+            }]
+        );
+
+        // Without PII columns, row-level queries are up to governance.
+        let open = PolicyConfig::default();
+        for sql in [
+            "SELECT * FROM names_and_cities",
+            "SELECT b FROM names_and_cities AS t(a, b)",
+            "SELECT t FROM names_and_cities t",
+            "SELECT v FROM names_and_cities UNPIVOT (v FOR k IN (city))",
+        ] {
+            assert_eq!(check(sql, &tables(), &open), Ok(()), "{}", sql);
+        }
+    }
+
+    #[test]
+    fn reports_every_violation() {
+        let rejection = check(
+            "SELECT name FROM read_parquet('s3://bucket/x.parquet') JOIN secrets USING (id)",
+            &tables(),
+            &policy(),
+        )
+        .unwrap_err();
+
+        assert_eq!(
+            rejection.to_string(),
+            "query rejected by policy: PII column name may only be aggregated; \
+             table function read_parquet is not allowed; unknown table secrets"
+        );
+        assert_eq!(
+            serde_json::to_value(&rejection).unwrap(),
+            serde_json::json!({
+                "violations": [
+                    {"rule": "pii_column", "column": "name"},
+                    {"rule": "table_function", "function": "read_parquet"},
+                    {"rule": "unknown_table", "table": "secrets"},
+                ]
+            })
+        );
+    }
+}
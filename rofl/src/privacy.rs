@@ -0,0 +1,534 @@
+//! Differential privacy of aggregate results.
+//!
+//! With a noise mechanism configured, a query is only run when its outermost `SELECT` releases
+//! plain `COUNT`, `SUM` and `AVG` calls next to its group keys. Each of those outputs is a
+//! measurement that gets Laplace or Gaussian noise calibrated to its sensitivity: one for a count
+//! and the clamping bound for a sum, computed over values clamped to that bound. An `AVG` is
+//! released as a noisy clamped sum over a noisy count, so it takes two measurements. The epsilon
+//! (and delta) of a query is split evenly over its measurements; groups are disjoint, so each
+//! group gets the full share. Gaussian noise uses the classic calibration, so its epsilon has to
+//! stay below 1.
+//!
+//! These sensitivities assume every row of a dataset contributes once, which only holds for a
+//! query over a single table. Joins, comma joins, subqueries in `FROM` and CTEs can repeat a row
+//! any number of times, so they are refused.
+//!
+//! Group keys are released as they are, so which groups exist is not protected; pair this with a
+//! minimum group size. `HAVING`, `ORDER BY`, window functions and subqueries in the projection
+//! are refused, as they would expose exact aggregates.
+use std::ops::ControlFlow;
+
+use anyhow::{Result, anyhow};
+use rand::Rng;
+use serde::Serialize;
+use sqlparser::ast::{
+    Expr, Function, FunctionArg, FunctionArgExpr, FunctionArguments, Ident, Query, SelectItem,
+    SetExpr, Statement, TableFactor, TableWithJoins, Visit, Visitor,
+};
+use sqlparser::dialect::DuckDbDialect;
+use sqlparser::parser::Parser;
+
+use crate::anonymity;
+use crate::config::{Mechanism, PrivacyConfig};
+use crate::policy::last_part;
+use crate::query::{QueryResult, Value};
+
+/// How the privacy of a result was protected, reported to the requester with the result.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct PrivacyReport {
+    pub mechanism: Mechanism,
+    /// Epsilon the query spent on each dataset it read.
+    pub epsilon_spent: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub delta: Option<f64>,
+    pub datasets: Vec<String>,
+}
+
+/// What an output column of the query releases.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Output {
+    /// A group key, released as it is.
+    Key,
+    Count,
+    /// A sum, computed in the given hidden column.
+    Sum {
+        sum: usize,
+    },
+    /// An average, computed from the given hidden columns.
+    Avg {
+        sum: usize,
+        count: usize,
+    },
+}
+
+/// A query rewritten to compute its noisy outputs.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NoisyQuery {
+    /// The query to run, returning the hidden columns after its own.
+    pub sql: String,
+    outputs: Vec<Output>,
+    hidden: usize,
+    measurements: usize,
+}
+
+/// Rewrite a query for differentially private release, or explain why it cannot be released.
+pub fn prepare(sql: &str, privacy: &PrivacyConfig) -> Result<NoisyQuery> {
+    let mut statements = Parser::parse_sql(&DuckDbDialect {}, sql)?;
+    let [Statement::Query(query)] = statements.as_mut_slice() else {
+        return Err(anyhow!("expected a single query"));
+    };
+    if query.order_by.is_some() {
+        return Err(anyhow!("ORDER BY is not allowed with differential privacy"));
+    }
+    let SetExpr::Select(select) = query.body.as_mut() else {
+        return Err(anyhow!(
+            "differential privacy needs a single aggregate SELECT"
+        ));
+    };
+    if !anonymity::is_grouped(&select.group_by, &select.projection) {
+        return Err(anyhow!("differential privacy needs an aggregate query"));
+    }
+    if select.having.is_some() {
+        return Err(anyhow!("HAVING is not allowed with differential privacy"));
+    }
+    let single_table = matches!(
+        select.from.as_slice(),
+        [TableWithJoins {
+            relation: TableFactor::Table { .. },
+            joins,
+        }] if joins.is_empty()
+    );
+    if !single_table || query.with.is_some() {
+        return Err(anyhow!(
+            "differential privacy needs a query over a single table, without joins, subqueries \
+             in FROM or CTEs"
+        ));
+    }
+
+    let mut outputs = Vec::new();
+    let mut hidden = Vec::new();
+    let column = select.projection.len();
+    for item in &select.projection {
+        let expr = match item {
+            SelectItem::UnnamedExpr(expr) | SelectItem::ExprWithAlias { expr, .. } => expr,
+            SelectItem::Wildcard(_) | SelectItem::QualifiedWildcard(..) => {
+                return Err(anyhow!(
+                    "wildcards are not allowed with differential privacy"
+                ));
+            }
+        };
+        let mut exact = ExactAggregates(false);
+        let _ = expr.visit(&mut exact);
+        if exact.0 {
+            return Err(anyhow!(
+                "window functions and subqueries are not allowed with differential privacy: {}",
+                expr
+            ));
+        }
+
+        let output = match aggregate(expr) {
+            Some(("count", _)) => Output::Count,
+            Some(("sum", value)) => {
+                hidden.push(clamped_sum(value?, privacy)?);
+                Output::Sum {
+                    sum: column + hidden.len() - 1,
+                }
+            }
+            Some(("avg" | "mean", value)) => {
+                let value = value?;
+                hidden.push(clamped_sum(value, privacy)?);
+                hidden.push(format!("COUNT({})", value));
+                Output::Avg {
+                    sum: column + hidden.len() - 2,
+                    count: column + hidden.len() - 1,
+                }
+            }
+            _ if anonymity::contains_aggregate(item) => {
+                return Err(anyhow!(
+                    "only COUNT, SUM and AVG can be released with differential privacy: {}",
+                    expr
+                ));
+            }
+            _ => Output::Key,
+        };
+        outputs.push(output);
+    }
+
+    let measurements = outputs
+        .iter()
+        .map(|output| match output {
+            Output::Key => 0,
+            Output::Count | Output::Sum { .. } => 1,
+            Output::Avg { .. } => 2,
+        })
+        .sum();
+    if measurements == 0 {
+        return Err(anyhow!("the query releases no COUNT, SUM or AVG"));
+    }
+
+    for (index, sql) in hidden.iter().enumerate() {
+        let expr = Parser::new(&DuckDbDialect {})
+            .try_with_sql(sql)?
+            .parse_expr()?;
+        select.projection.push(SelectItem::ExprWithAlias {
+            expr,
+            alias: Ident::with_quote('"', format!("__dp_{}", index)),
+        });
+    }
+
+    Ok(NoisyQuery {
+        sql: query.to_string(),
+        outputs,
+        hidden: hidden.len(),
+        measurements,
+    })
+}
+
+/// Replace the aggregates of a result with noisy ones, drop the hidden columns and report the
+/// privacy spent on `datasets`.
+pub fn apply(
+    result: &mut QueryResult,
+    query: &NoisyQuery,
+    privacy: &PrivacyConfig,
+    datasets: Vec<String>,
+    rng: &mut impl Rng,
+) -> Result<()> {
+    let visible = query.outputs.len();
+    if result.columns.len() != visible + query.hidden {
+        return Err(anyhow!("query result is missing its hidden columns"));
+    }
+    result.columns.truncate(visible);
+
+    let measurements = query.measurements as f64;
+    let noise = Noise {
+        mechanism: privacy.mechanism,
+        epsilon: privacy.epsilon / measurements,
+        delta: privacy.delta / measurements,
+    };
+    let bound = privacy.sum_bound.unwrap_or_default();
+
+    for row in &mut result.rows {
+        let hidden = row.split_off(visible);
+        let hidden_value = |column: usize| number(hidden.get(column - visible));
+
+        for (cell, output) in row.iter_mut().zip(&query.outputs) {
+            *cell = match *output {
+                Output::Key => continue,
+                Output::Count => {
+                    let count = noise.add(number(Some(cell))?, 1.0, rng);
+                    Value::Integer(count.round().max(0.0) as i64)
+                }
+                Output::Sum { sum } => Value::Float(noise.add(hidden_value(sum)?, bound, rng)),
+                Output::Avg { sum, count } => {
+                    let sum = noise.add(hidden_value(sum)?, bound, rng);
+                    let count = noise.add(hidden_value(count)?, 1.0, rng).max(1.0);
+                    Value::Float((sum / count).clamp(-bound, bound))
+                }
+            };
+        }
+    }
+
+    result.privacy = Some(PrivacyReport {
+        mechanism: privacy.mechanism,
+        epsilon_spent: privacy.epsilon,
+        delta: (privacy.mechanism == Mechanism::Gaussian).then_some(privacy.delta),
+        datasets,
+    });
+    Ok(())
+}
+
+/// Noise of a single measurement.
+struct Noise {
+    mechanism: Mechanism,
+    epsilon: f64,
+    delta: f64,
+}
+
+impl Noise {
+    /// Add noise calibrated to `sensitivity` to `value`.
+    fn add(&self, value: f64, sensitivity: f64, rng: &mut impl Rng) -> f64 {
+        match self.mechanism {
+            Mechanism::Laplace => value + laplace(sensitivity / self.epsilon, rng),
+            Mechanism::Gaussian => {
+                // The classic calibration, which only holds for epsilon below 1. The
+                // configuration keeps the epsilon of a query, and so each share of it, below 1.
+                let sigma = sensitivity * (2.0 * (1.25 / self.delta).ln()).sqrt() / self.epsilon;
+                value + gaussian(sigma, rng)
+            }
+        }
+    }
+}
+
+/// A sample of the Laplace distribution with the given scale, by inverting its CDF.
+fn laplace(scale: f64, rng: &mut impl Rng) -> f64 {
+    loop {
+        let u = rng.r#gen::<f64>() - 0.5;
+        let tail = 1.0 - 2.0 * u.abs();
+        if tail > 0.0 {
+            return -scale * u.signum() * tail.ln();
+        }
+    }
+}
+
+/// A sample of the normal distribution with the given standard deviation (Box-Muller).
+fn gaussian(sigma: f64, rng: &mut impl Rng) -> f64 {
+    let radius = (-2.0 * (1.0 - rng.r#gen::<f64>()).ln()).sqrt();
+    let angle = 2.0 * std::f64::consts::PI * rng.r#gen::<f64>();
+    sigma * radius * angle.cos()
+}
+
+/// The numeric value of an aggregate. An aggregate over no values is `NULL`, which counts as 0.
+fn number(value: Option<&Value>) -> Result<f64> {
+    match value {
+        Some(Value::Integer(value)) => Ok(*value as f64),
+        Some(Value::Float(value)) => Ok(*value),
+        Some(Value::Null) => Ok(0.0),
+        other => Err(anyhow!("aggregate is not a number: {:?}", other)),
+    }
+}
+
+/// The lower-case name of a plain aggregate call, with its single argument if it has exactly one
+/// plain argument.
+fn aggregate(expr: &Expr) -> Option<(&'static str, Result<&Expr>)> {
+    let Expr::Function(Function {
+        name,
+        args,
+        over: None,
+        filter,
+        ..
+    }) = expr
+    else {
+        return None;
+    };
+    let name = match last_part(name).as_str() {
+        "count" => "count",
+        "sum" => "sum",
+        "avg" => "avg",
+        "mean" => "mean",
+        _ => return None,
+    };
+
+    let value = match args {
+        FunctionArguments::List(list)
+            if filter.is_none()
+                && list.duplicate_treatment.is_none()
+                && list.clauses.is_empty() =>
+        {
+            match list.args.as_slice() {
+                [FunctionArg::Unnamed(FunctionArgExpr::Expr(value))] => Some(value),
+                _ => None,
+            }
+        }
+        _ => None,
+    };
+    Some((
+        name,
+        value.ok_or_else(|| anyhow!("only plain {}(x) is allowed: {}", name, expr)),
+    ))
+}
+
+/// `SUM` over `value` clamped to the sum bound, ignoring `NULL`s as `SUM` does.
+fn clamped_sum(value: &Expr, privacy: &PrivacyConfig) -> Result<String> {
+    let bound = privacy
+        .sum_bound
+        .ok_or_else(|| anyhow!("SUM and AVG need a sum bound with differential privacy"))?;
+    Ok(format!(
+        "SUM(CASE WHEN ({value}) IS NULL THEN NULL \
+         ELSE LEAST(GREATEST(CAST(({value}) AS DOUBLE), {low:?}), {high:?}) END)",
+        value = value,
+        low = -bound,
+        high = bound,
+    ))
+}
+
+/// Finds window functions and subqueries, which could release exact aggregates.
+struct ExactAggregates(bool);
+
+impl Visitor for ExactAggregates {
+    type Break = ();
+
+    fn pre_visit_query(&mut self, _query: &Query) -> ControlFlow<()> {
+        self.0 = true;
+        ControlFlow::Break(())
+    }
+
+    fn pre_visit_expr(&mut self, expr: &Expr) -> ControlFlow<()> {
+        if let Expr::Function(Function { over: Some(_), .. }) = expr {
+            self.0 = true;
+            return ControlFlow::Break(());
+        }
+        ControlFlow::Continue(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::SeedableRng;
+    use rand::rngs::StdRng;
+
+    fn privacy(mechanism: Mechanism) -> PrivacyConfig {
+        PrivacyConfig {
+            mechanism,
+            epsilon: 1.0,
+            delta: 1e-6,
+            sum_bound: Some(100.0),
+            epsilon_budget: 10.0,
+        }
+    }
+
+    #[test]
+    fn rewrites_sums_and_averages() {
+        let query = prepare(
+            "SELECT city, COUNT(*) AS n, SUM(age), AVG(age) AS mean_age FROM people GROUP BY city",
+            &privacy(Mechanism::Laplace),
+        )
+        .unwrap();
+
+        let clamped = "SUM(CASE WHEN (age) IS NULL THEN NULL \
+                       ELSE LEAST(GREATEST(CAST((age) AS DOUBLE), -100.0), 100.0) END)";
+        assert_eq!(
+            query,
+            NoisyQuery {
+                sql: format!(
+                    "SELECT city, COUNT(*) AS n, SUM(age), AVG(age) AS mean_age, \
+                     {clamped} AS \"__dp_0\", {clamped} AS \"__dp_1\", COUNT(age) AS \"__dp_2\" \
+                     FROM people GROUP BY city"
+                ),
+                outputs: vec![
+                    Output::Key,
+                    Output::Count,
+                    Output::Sum { sum: 4 },
+                    Output::Avg { sum: 5, count: 6 },
+                ],
+                hidden: 3,
+                measurements: 4,
+            }
+        );
+    }
+
+    #[test]
+    fn refuses_queries_that_release_exact_values() {
+        let laplace = privacy(Mechanism::Laplace);
+        for sql in [
+            "SELECT city FROM people",
+            "SELECT COUNT(*) FROM people UNION SELECT COUNT(*) FROM visits",
+            "SELECT city, COUNT(*) FROM people GROUP BY city ORDER BY 2",
+            "SELECT city FROM people GROUP BY city HAVING COUNT(*) > 10",
+            "SELECT city FROM people GROUP BY city",
+            "SELECT MAX(age) FROM people",
+            "SELECT COUNT(*) + 1 FROM people",
+            "SELECT COUNT(*), (SELECT COUNT(*) FROM visits) FROM people",
+            "SELECT COUNT(*), COUNT(*) OVER () FROM people",
+            "SELECT SUM(DISTINCT age) FROM people",
+            "SELECT AVG(age) FILTER (WHERE age > 18) FROM people",
+            "SELECT * FROM people GROUP BY ALL",
+        ] {
+            assert!(prepare(sql, &laplace).is_err(), "{}", sql);
+        }
+
+        let unbounded = PrivacyConfig {
+            sum_bound: None,
+            ..laplace
+        };
+        assert!(prepare("SELECT COUNT(DISTINCT city) FROM people", &unbounded).is_ok());
+        assert!(prepare("SELECT SUM(age) FROM people", &unbounded).is_err());
+    }
+
+    #[test]
+    fn refuses_queries_over_more_than_one_table() {
+        let laplace = privacy(Mechanism::Laplace);
+        for sql in [
+            "SELECT COUNT(*) FROM people a, people b WHERE a.name = 'X'",
+            "SELECT COUNT(*) FROM people JOIN visits USING (name)",
+            "SELECT COUNT(*) FROM people a CROSS JOIN people b",
+            "SELECT COUNT(*) FROM (SELECT * FROM people UNION ALL SELECT * FROM people) AS p",
+            "SELECT COUNT(*) FROM (people JOIN visits USING (name))",
+            "WITH p AS (SELECT * FROM people) SELECT COUNT(*) FROM p",
+            "SELECT COUNT(*)",
+        ] {
+            let err = prepare(sql, &laplace).unwrap_err();
+            assert!(err.to_string().contains("single table"), "{}: {}", sql, err);
+        }
+
+        assert!(prepare("SELECT COUNT(*) FROM people p WHERE p.age > 18", &laplace).is_ok());
+    }
+
+    #[test]
+    fn adds_noise_and_reports_the_epsilon_spent() {
+        let privacy = privacy(Mechanism::Gaussian);
+        let query = prepare(
+            "SELECT city, COUNT(*), SUM(age), AVG(age) FROM people GROUP BY city",
+            &privacy,
+        )
+        .unwrap();
+        let mut result = QueryResult {
+            columns: ["city", "count", "sum", "avg", "h0", "h1", "h2"]
+                .map(str::to_owned)
+                .to_vec(),
+            rows: vec![vec![
+                Value::Text("Utrecht".into()),
+                Value::Integer(1000),
+                Value::Integer(40_000),
+                Value::Float(40.0),
+                Value::Float(40_000.0),
+                Value::Float(40_000.0),
+                Value::Integer(1000),
+            ]],
+            ..QueryResult::default()
+        };
+
+        apply(
+            &mut result,
+            &query,
+            &privacy,
+            vec!["people".into()],
+            &mut StdRng::seed_from_u64(7),
+        )
+        .unwrap();
+
+        assert_eq!(result.columns, vec!["city", "count", "sum", "avg"]);
+        let row = &result.rows[0];
+        assert_eq!(row[0], Value::Text("Utrecht".into()));
+        assert!(matches!(row[1], Value::Integer(n) if n != 1000 && (900..=1100).contains(&n)));
+        assert!(matches!(row[2], Value::Float(sum) if (30_000.0..50_000.0).contains(&sum)));
+        assert!(matches!(row[3], Value::Float(avg) if (30.0..50.0).contains(&avg)));
+        assert_eq!(
+            serde_json::to_value(result.privacy.as_ref().unwrap()).unwrap(),
+            serde_json::json!({
+                "mechanism": "gaussian",
+                "epsilon_spent": 1.0,
+                "delta": 1e-6,
+                "datasets": ["people"],
+            })
+        );
+    }
+
+    #[test]
+    fn calibrates_noise_to_epsilon() {
+        let mut rng = StdRng::seed_from_u64(1);
+        let samples = 20_000;
+        let mut variance = |mechanism: Mechanism| {
+            let noise = Noise {
+                mechanism,
+                epsilon: 0.5,
+                delta: 1e-5,
+            };
+            let draws: Vec<f64> = (0..samples)
+                .map(|_| noise.add(0.0, 2.0, &mut rng))
+                .collect();
+            let mean = draws.iter().sum::<f64>() / samples as f64;
+            assert!(mean.abs() < 0.3, "{:?} mean {}", mechanism, mean);
+            draws.iter().map(|d| (d - mean).powi(2)).sum::<f64>() / samples as f64
+        };
+
+        // Laplace with scale b = 2 / 0.5 has variance 2b^2 = 32.
+        let laplace = variance(Mechanism::Laplace);
+        assert!((laplace / 32.0 - 1.0).abs() < 0.1, "{}", laplace);
+
+        // Gaussian with sigma = 2 * sqrt(2 ln(1.25 / 1e-5)) / 0.5.
+        let sigma = 2.0 * (2.0 * (1.25f64 / 1e-5).ln()).sqrt() / 0.5;
+        let gaussian = variance(Mechanism::Gaussian);
+        assert!((gaussian / sigma.powi(2) - 1.0).abs() < 0.1, "{}", gaussian);
+    }
+}
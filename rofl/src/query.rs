@@ -0,0 +1,635 @@
+//! In-TEE execution of proposal SQL over Parquet datasets, backed by an embedded DuckDB.
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::thread;
+
+use anyhow::{Result, anyhow};
+use duckdb::Connection;
+use duckdb::types::{TimeUnit, Value as DuckValue};
+use serde::Serialize;
+
+use crate::anonymity;
+use crate::config::{LimitsConfig, PolicyConfig};
+use crate::limits::{self, Resource, ResourceExceeded};
+use crate::privacy::{self, PrivacyReport};
+
+/// A dataset object, downloaded to a file that queries read in place.
+#[derive(Debug, Clone)]
+pub struct Dataset {
+    pub key: String,
+    pub path: PathBuf,
+}
+
+/// A single cell of a query result.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(untagged)]
+pub enum Value {
+    Null,
+    Boolean(bool),
+    Integer(i64),
+    Float(f64),
+    Text(String),
+    Blob(Vec<u8>),
+}
+
+/// Column names and typed rows returned by a query.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct QueryResult {
+    pub columns: Vec<String>,
+    pub rows: Vec<Vec<Value>>,
+    /// Groups left out for being backed by too few rows.
+    pub suppressed_groups: u64,
+    /// Noise added to the aggregates, when differential privacy is on.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub privacy: Option<PrivacyReport>,
+}
+
+impl QueryResult {
+    /// Serialize the result as the JSON document handed back to the requester.
+    pub fn to_json(&self) -> Result<String> {
+        Ok(serde_json::to_string(self)?)
+    }
+}
+
+/// Table name under which a dataset object is registered. This follows the rule of the
+/// TypeScript prototype: the first `.parquet` is stripped from the key and every character
+/// outside `[A-Za-z0-9_]` is replaced with `_`.
+pub fn table_name(key: &str) -> String {
+    key.replacen(".parquet", "", 1)
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '_' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+/// A path as a DuckDB string literal.
+fn quote(path: &Path) -> String {
+    format!("'{}'", path.to_string_lossy().replace('\'', "''"))
+}
+
+/// Whether an object key refers to a Parquet file.
+pub fn is_parquet(key: &str) -> bool {
+    key.to_lowercase().ends_with(".parquet")
+}
+
+/// An in-memory DuckDB database with the dataset objects registered as views.
+pub struct QueryExecutor {
+    conn: Connection,
+    tables: Vec<String>,
+    /// Files behind the registered tables, still readable once the session is locked down.
+    paths: Vec<PathBuf>,
+    limits: Option<Limits>,
+}
+
+/// Limits enforced by an executor.
+struct Limits {
+    config: LimitsConfig,
+    /// Bytes of the datasets registered so far.
+    scanned: u64,
+    /// Set by the watchdog once the time is up.
+    timed_out: Arc<AtomicBool>,
+    /// Stops the watchdog when dropped.
+    _watchdog: mpsc::Sender<()>,
+}
+
+impl QueryExecutor {
+    pub fn new() -> Result<Self> {
+        Ok(Self {
+            conn: Connection::open_in_memory()?,
+            tables: Vec::new(),
+            paths: Vec::new(),
+            limits: None,
+        })
+    }
+
+    /// An executor that enforces `limits`. DuckDB spills to disk once it reaches the memory limit
+    /// and the time limit starts counting right away.
+    pub fn with_limits(limits: &LimitsConfig) -> Result<Self> {
+        let mut executor = Self::new()?;
+
+        let mut settings = format!(
+            "SET threads = 1; SET memory_limit = '{}MB'; SET max_temp_directory_size = '{}MB';",
+            limits.memory_mb, limits.spill_mb
+        );
+        if let Some(dir) = &limits.spill_dir {
+            fs::create_dir_all(dir)?;
+            settings.push_str(&format!(
+                " SET temp_directory = '{}';",
+                dir.to_string_lossy().replace('\'', "''")
+            ));
+        }
+        executor.conn.execute_batch(&settings)?;
+
+        // The watchdog interrupts whatever runs once the time is up; work started afterwards is
+        // refused through the flag.
+        let timed_out = Arc::new(AtomicBool::new(false));
+        let (watchdog, stopped) = mpsc::channel::<()>();
+        let interrupt = executor.conn.interrupt_handle();
+        let flag = timed_out.clone();
+        let timeout = limits.timeout;
+        thread::spawn(move || {
+            if stopped.recv_timeout(timeout) == Err(RecvTimeoutError::Timeout) {
+                flag.store(true, Ordering::SeqCst);
+                interrupt.interrupt();
+            }
+        });
+
+        executor.limits = Some(Limits {
+            config: limits.clone(),
+            scanned: 0,
+            timed_out,
+            _watchdog: watchdog,
+        });
+        Ok(executor)
+    }
+
+    /// Fail once the time limit is up.
+    fn check_time(&self) -> Result<()> {
+        match &self.limits {
+            Some(limits) if limits.timed_out.load(Ordering::SeqCst) => {
+                Err(ResourceExceeded::new(Resource::Time, &limits.config).into())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Replace an error of DuckDB by the limit that caused it, if any.
+    fn limit_error(&self, err: duckdb::Error) -> anyhow::Error {
+        let Some(limits) = &self.limits else {
+            return err.into();
+        };
+        if limits.timed_out.load(Ordering::SeqCst) {
+            ResourceExceeded::new(Resource::Time, &limits.config).into()
+        } else if err.to_string().contains("Out of Memory Error") {
+            // Also raised once the spill space is used up.
+            ResourceExceeded::new(Resource::Memory, &limits.config).into()
+        } else {
+            err.into()
+        }
+    }
+
+    /// Register a Parquet dataset as a view named after its key and return the view name. The
+    /// view reads the file of the dataset in place, so it is never loaded into memory as a whole.
+    pub fn register(&mut self, dataset: &Dataset) -> Result<String> {
+        if !is_parquet(&dataset.key) {
+            return Err(anyhow!("{} is not a parquet object", dataset.key));
+        }
+
+        let table = table_name(&dataset.key);
+        if self.tables.contains(&table) {
+            return Err(anyhow!(
+                "{} maps to table {}, which is already registered",
+                dataset.key,
+                table
+            ));
+        }
+        self.check_time()?;
+        if let Some(limits) = &mut self.limits {
+            limits.scanned += fs::metadata(&dataset.path)?.len();
+            limits::check_scan(limits.scanned, &limits.config)?;
+        }
+
+        self.conn
+            .execute_batch(&format!(
+                "CREATE VIEW \"{}\" AS SELECT * FROM read_parquet({})",
+                table,
+                quote(&dataset.path)
+            ))
+            .map_err(|err| self.limit_error(err))?;
+
+        self.tables.push(table.clone());
+        self.paths.push(dataset.path.clone());
+        Ok(table)
+    }
+
+    /// Turn off file and network access and freeze the configuration for the rest of the
+    /// session, so queries only see the tables registered so far. The files behind those tables
+    /// stay readable.
+    pub fn lock_down(&self) -> Result<()> {
+        let paths: Vec<String> = self.paths.iter().map(|path| quote(path)).collect();
+        self.conn.execute_batch(&format!(
+            "SET allowed_paths = [{}]; \
+             SET enable_external_access = false; \
+             SET autoinstall_known_extensions = false; \
+             SET autoload_known_extensions = false; \
+             SET lock_configuration = true;",
+            paths.join(", ")
+        ))?;
+        Ok(())
+    }
+
+    /// Names of the registered tables.
+    pub fn tables(&self) -> &[String] {
+        &self.tables
+    }
+
+    /// Run a query against the registered tables.
+    pub fn execute(&self, sql: &str) -> Result<QueryResult> {
+        self.check_time()?;
+        let mut stmt = self
+            .conn
+            .prepare(sql)
+            .map_err(|err| self.limit_error(err))?;
+        let mut rows = stmt.query([]).map_err(|err| self.limit_error(err))?;
+        let max_rows = self
+            .limits
+            .as_ref()
+            .map_or(u64::MAX, |limits| limits.config.max_output_rows);
+
+        let mut result = QueryResult {
+            columns: rows
+                .as_ref()
+                .map(|stmt| stmt.column_names())
+                .unwrap_or_default(),
+            rows: Vec::new(),
+            ..QueryResult::default()
+        };
+        while let Some(row) = rows.next().map_err(|err| self.limit_error(err))? {
+            if result.rows.len() as u64 >= max_rows {
+                let limits = &self.limits.as_ref().expect("rows are limited").config;
+                return Err(ResourceExceeded::new(Resource::OutputRows, limits).into());
+            }
+            self.check_time()?;
+            let mut values = Vec::with_capacity(result.columns.len());
+            for index in 0..result.columns.len() {
+                values.push(convert(row.get::<_, DuckValue>(index)?)?);
+            }
+            result.rows.push(values);
+        }
+
+        Ok(result)
+    }
+}
+
+/// Register every Parquet object in `datasets` and run `sql` over them, suppressing groups smaller
+/// than the minimum group size of the datasets it reads and adding noise to the aggregates when
+/// differential privacy is on. Other objects are skipped, as in the TypeScript prototype. Running
+/// into a limit fails with `ResourceExceeded`.
+pub fn run(
+    datasets: &[Dataset],
+    sql: &str,
+    policy: &PolicyConfig,
+    limits: &LimitsConfig,
+) -> Result<QueryResult> {
+    let mut executor = QueryExecutor::with_limits(limits)?;
+    for dataset in datasets.iter().filter(|d| is_parquet(&d.key)) {
+        executor.register(dataset)?;
+    }
+
+    if executor.tables().is_empty() {
+        return Err(anyhow!("no parquet datasets to query"));
+    }
+
+    executor.lock_down()?;
+
+    let noisy = match &policy.privacy {
+        Some(privacy) => Some(privacy::prepare(sql, privacy)?),
+        None => None,
+    };
+    let sql = noisy.as_ref().map_or(sql, |noisy| noisy.sql.as_str());
+
+    let query = anonymity::prepare(sql)?;
+    let min_group_size = policy.min_group_size(query.tables.iter().map(String::as_str));
+    let mut result = if min_group_size <= 1 {
+        executor.execute(sql)?
+    } else {
+        let mut result = executor.execute(&query.sql)?;
+        anonymity::suppress(&mut result, &query, min_group_size)?;
+        result
+    };
+
+    if let (Some(noisy), Some(privacy)) = (&noisy, &policy.privacy) {
+        privacy::apply(
+            &mut result,
+            noisy,
+            privacy,
+            query.tables,
+            &mut rand::rngs::OsRng,
+        )?;
+    }
+    Ok(result)
+}
+
+fn convert(value: DuckValue) -> Result<Value> {
+    let integer = |value: i128| match i64::try_from(value) {
+        Ok(value) => Value::Integer(value),
+        // Keep the exact value rather than losing precision in a float.
+        Err(_) => Value::Text(value.to_string()),
+    };
+
+    Ok(match value {
+        DuckValue::Null => Value::Null,
+        DuckValue::Boolean(v) => Value::Boolean(v),
+        DuckValue::TinyInt(v) => Value::Integer(v.into()),
+        DuckValue::SmallInt(v) => Value::Integer(v.into()),
+        DuckValue::Int(v) => Value::Integer(v.into()),
+        DuckValue::BigInt(v) => Value::Integer(v),
+        DuckValue::HugeInt(v) => integer(v),
+        DuckValue::UTinyInt(v) => Value::Integer(v.into()),
+        DuckValue::USmallInt(v) => Value::Integer(v.into()),
+        DuckValue::UInt(v) => Value::Integer(v.into()),
+        DuckValue::UBigInt(v) => integer(v.into()),
+        DuckValue::UHugeInt(v) => match i128::try_from(v) {
+            Ok(v) => integer(v),
+            Err(_) => Value::Text(v.to_string()),
+        },
+        DuckValue::Float(v) => Value::Float(v.into()),
+        DuckValue::Double(v) => Value::Float(v),
+        DuckValue::Decimal(v) => Value::Float(v.to_string().parse()?),
+        DuckValue::Text(v) | DuckValue::Enum(v) => Value::Text(v),
+        DuckValue::Blob(v) => Value::Blob(v),
+        DuckValue::Date32(days) => Value::Text(
+            chrono::DateTime::UNIX_EPOCH
+                .date_naive()
+                .checked_add_signed(chrono::TimeDelta::days(days.into()))
+                .ok_or_else(|| anyhow!("date out of range"))?
+                .to_string(),
+        ),
+        DuckValue::Timestamp(unit, v) => Value::Text(
+            chrono::DateTime::from_timestamp_micros(to_micros(unit, v))
+                .ok_or_else(|| anyhow!("timestamp out of range"))?
+                .naive_utc()
+                .to_string(),
+        ),
+        other => {
+            return Err(anyhow!(
+                "unsupported value in query result: {:?}",
+                other.data_type()
+            ));
+        }
+    })
+}
+
+fn to_micros(unit: TimeUnit, value: i64) -> i64 {
+    match unit {
+        TimeUnit::Second => value.saturating_mul(1_000_000),
+        TimeUnit::Millisecond => value.saturating_mul(1_000),
+        TimeUnit::Microsecond => value,
+        TimeUnit::Nanosecond => value / 1_000,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{Mechanism, PrivacyConfig};
+    use std::time::Duration;
+
+    fn sample() -> Dataset {
+        Dataset {
+            key: "names-and-cities.parquet".into(),
+            path: PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+                .join("../rofl-bun/names-and-cities.parquet"),
+        }
+    }
+
+    #[test]
+    fn table_names_follow_prototype_rule() {
+        assert_eq!(table_name("names-and-cities.parquet"), "names_and_cities");
+        assert_eq!(table_name("dataset/v1/part-0.parquet"), "dataset_v1_part_0");
+        assert_eq!(table_name("a.parquet.parquet"), "a_parquet");
+        assert_eq!(table_name("plain_key"), "plain_key");
+        assert!(is_parquet("DATA.PARQUET"));
+        assert!(!is_parquet("names-and-cities"));
+    }
+
+    #[test]
+    fn queries_sample_dataset() {
+        let result = run(
+            &[sample()],
+            "SELECT COUNT(*) AS n FROM names_and_cities",
+            &PolicyConfig::default(),
+            &LimitsConfig::default(),
+        )
+        .unwrap();
+
+        assert_eq!(result.columns, vec!["n"]);
+        assert_eq!(result.rows.len(), 1);
+        assert!(matches!(result.rows[0][0], Value::Integer(n) if n > 0));
+    }
+
+    #[test]
+    fn returns_typed_rows() {
+        let mut executor = QueryExecutor::new().unwrap();
+        executor.register(&sample()).unwrap();
+
+        let result = executor
+            .execute(
+                "SELECT 1 AS i, 2.5 AS f, 'x' AS s, NULL AS n, true AS b, \
+                 DATE '2024-06-01' AS d, 12.50::DECIMAL(4, 2) AS dec",
+            )
+            .unwrap();
+        assert_eq!(
+            result.rows,
+            vec![vec![
+                Value::Integer(1),
+                Value::Float(2.5),
+                Value::Text("x".into()),
+                Value::Null,
+                Value::Boolean(true),
+                Value::Text("2024-06-01".into()),
+                Value::Float(12.5),
+            ]]
+        );
+        assert_eq!(
+            result.to_json().unwrap(),
+            r#"{"columns":["i","f","s","n","b","d","dec"],"rows":[[1,2.5,"x",null,true,"2024-06-01",12.5]],"suppressed_groups":0}"#
+        );
+    }
+
+    #[test]
+    fn keeps_columns_of_empty_results() {
+        let mut executor = QueryExecutor::new().unwrap();
+        executor.register(&sample()).unwrap();
+
+        let result = executor
+            .execute("SELECT * FROM names_and_cities WHERE false")
+            .unwrap();
+        assert!(result.rows.is_empty());
+        assert!(!result.columns.is_empty());
+    }
+
+    #[test]
+    fn suppresses_groups_below_the_minimum_size() {
+        let policy = PolicyConfig {
+            min_group_size: 2,
+            ..PolicyConfig::default()
+        };
+        let cities = "FROM (VALUES ('Utrecht'), ('Utrecht'), ('Den Haag')) AS people(city)";
+
+        let result = run(
+            &[sample()],
+            &format!("SELECT city, COUNT(*) AS n {} GROUP BY city", cities),
+            &policy,
+            &LimitsConfig::default(),
+        )
+        .unwrap();
+        assert_eq!(result.columns, vec!["city", "n"]);
+        assert_eq!(
+            result.rows,
+            vec![vec![Value::Text("Utrecht".into()), Value::Integer(2)]]
+        );
+        assert_eq!(result.suppressed_groups, 1);
+
+        let result = run(
+            &[sample()],
+            &format!("SELECT city {}", cities),
+            &policy,
+            &LimitsConfig::default(),
+        )
+        .unwrap();
+        assert!(result.rows.is_empty());
+        assert_eq!(result.suppressed_groups, 3);
+    }
+
+    #[test]
+    fn adds_noise_under_differential_privacy() {
+        let policy = PolicyConfig {
+            privacy: Some(PrivacyConfig {
+                mechanism: Mechanism::Laplace,
+                epsilon: 1.0,
+                delta: 1e-6,
+                sum_bound: Some(10.0),
+                epsilon_budget: 10.0,
+            }),
+            ..PolicyConfig::default()
+        };
+
+        let result = run(
+            &[sample()],
+            "SELECT COUNT(*) AS n, AVG(length(\"First Name\")) AS name_length FROM names_and_cities",
+            &policy,
+            &LimitsConfig::default(),
+        )
+        .unwrap();
+        assert_eq!(result.columns, vec!["n", "name_length"]);
+        assert!(matches!(result.rows[0][0], Value::Integer(n) if n >= 0));
+        // Noise can move the average of a small sample anywhere within the sum bound.
+        assert!(matches!(result.rows[0][1], Value::Float(avg) if (-10.0..=10.0).contains(&avg)));
+        let privacy = result.privacy.as_ref().unwrap();
+        assert_eq!(privacy.epsilon_spent, 1.0);
+        assert_eq!(privacy.datasets, vec!["names_and_cities"]);
+
+        assert!(
+            run(
+                &[sample()],
+                "SELECT * FROM names_and_cities",
+                &policy,
+                &LimitsConfig::default()
+            )
+            .is_err()
+        );
+    }
+
+    #[test]
+    fn locked_down_sessions_only_read_registered_tables() {
+        let mut executor = QueryExecutor::new().unwrap();
+        executor.register(&sample()).unwrap();
+        executor.lock_down().unwrap();
+
+        assert!(
+            executor
+                .execute("SELECT COUNT(*) FROM names_and_cities")
+                .is_ok()
+        );
+        assert!(
+            executor
+                .execute("SELECT * FROM read_csv('/etc/hosts')")
+                .is_err()
+        );
+        assert!(
+            executor
+                .execute("SET enable_external_access = true")
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn enforces_resource_limits() {
+        let exceeded = |limits: LimitsConfig, sql: &str| {
+            let mut executor = QueryExecutor::with_limits(&limits)?;
+            executor.register(&sample())?;
+            executor.execute(sql).map(|_| ())
+        };
+        let resource = |result: Result<()>| {
+            result
+                .unwrap_err()
+                .downcast::<ResourceExceeded>()
+                .unwrap()
+                .resource
+        };
+
+        let rows = LimitsConfig {
+            max_output_rows: 10,
+            ..LimitsConfig::default()
+        };
+        assert!(exceeded(rows.clone(), "SELECT * FROM range(10)").is_ok());
+        assert_eq!(
+            resource(exceeded(rows, "SELECT * FROM range(11)")),
+            Resource::OutputRows
+        );
+
+        let scan = LimitsConfig {
+            max_scan_bytes: fs::metadata(sample().path).unwrap().len() - 1,
+            ..LimitsConfig::default()
+        };
+        assert_eq!(resource(exceeded(scan, "SELECT 1")), Resource::BytesScanned);
+
+        let time = LimitsConfig {
+            timeout: Duration::from_millis(200),
+            ..LimitsConfig::default()
+        };
+        assert_eq!(
+            resource(exceeded(
+                time,
+                "SELECT SUM(hash(i)) FROM range(100000000000) AS t(i)"
+            )),
+            Resource::Time
+        );
+
+        let memory = LimitsConfig {
+            memory_mb: 16,
+            spill_mb: 0,
+            ..LimitsConfig::default()
+        };
+        assert_eq!(
+            resource(exceeded(
+                memory,
+                "SELECT list(i ORDER BY i DESC) FROM range(100000000) AS t(i)"
+            )),
+            Resource::Memory
+        );
+    }
+
+    #[test]
+    fn rejects_duplicate_tables_and_missing_data() {
+        let mut executor = QueryExecutor::new().unwrap();
+        executor.register(&sample()).unwrap();
+        let mut duplicate = sample();
+        duplicate.key = "names_and-cities.parquet".into();
+        assert!(executor.register(&duplicate).is_err());
+
+        let not_parquet = Dataset {
+            key: "names-and-cities".into(),
+            path: sample().path,
+        };
+        assert!(
+            run(
+                &[not_parquet],
+                "SELECT 1",
+                &PolicyConfig::default(),
+                &LimitsConfig::default()
+            )
+            .is_err()
+        );
+        assert!(executor.execute("SELECT * FROM missing_table").is_err());
+    }
+}
@@ -0,0 +1,360 @@
+//! Decoding of the reasons vault calls fail with.
+//!
+//! The EVM module reports a reverted call as `reverted: <reason>`, where the reason is the
+//! message of an `Error(string)` revert or, for any other revert data, its base64 encoding.
+//! Running out of gas is reported by the EVM module as `execution failed: OutOfGas` and by the
+//! core module as `out of gas (...)`.
+use std::fmt;
+
+use base64::Engine as _;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use ethabi::{Address, ParamType, Token, Uint};
+
+const REVERTED_PREFIX: &str = "reverted: ";
+
+/// Reason the EVM module reports for a revert without data.
+const NO_REASON: &str = "no revert reason";
+
+/// A typed reason for a failed contract call.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ContractError {
+    /// `Error(string)`, raised by `require` and `revert("...")`.
+    Revert(String),
+    /// `Panic(uint256)`, raised by failed assertions, arithmetic errors and the like.
+    Panic(Uint),
+    /// OpenZeppelin `Pausable.EnforcedPause()`.
+    EnforcedPause,
+    /// OpenZeppelin `Pausable.ExpectedPause()`.
+    ExpectedPause,
+    /// OpenZeppelin `Ownable.OwnableUnauthorizedAccount(address)`.
+    OwnableUnauthorizedAccount(Address),
+    /// OpenZeppelin `Ownable.OwnableInvalidOwner(address)`.
+    OwnableInvalidOwner(Address),
+    /// OpenZeppelin `ReentrancyGuard.ReentrancyGuardReentrantCall()`.
+    ReentrancyGuardReentrantCall,
+    /// A revert without data.
+    Empty,
+    /// Revert data that matches none of the known errors.
+    Unknown(Vec<u8>),
+    /// The call ran out of gas.
+    OutOfGas,
+}
+
+impl ContractError {
+    /// Decode raw revert data.
+    pub fn decode(data: &[u8]) -> Self {
+        if data.is_empty() {
+            return Self::Empty;
+        }
+        if data.len() < 4 {
+            return Self::Unknown(data.to_vec());
+        }
+
+        let (selector, args) = data.split_at(4);
+        let decoded = if selector == ethabi::short_signature("Error", &[ParamType::String]) {
+            decode_args(args, ParamType::String)
+                .and_then(|token| token.into_string().map(Self::Revert))
+        } else if selector == ethabi::short_signature("Panic", &[ParamType::Uint(256)]) {
+            decode_args(args, ParamType::Uint(256))
+                .and_then(|token| token.into_uint().map(Self::Panic))
+        } else if selector == ethabi::short_signature("EnforcedPause", &[]) {
+            Some(Self::EnforcedPause)
+        } else if selector == ethabi::short_signature("ExpectedPause", &[]) {
+            Some(Self::ExpectedPause)
+        } else if selector
+            == ethabi::short_signature("OwnableUnauthorizedAccount", &[ParamType::Address])
+        {
+            decode_args(args, ParamType::Address)
+                .and_then(|token| token.into_address().map(Self::OwnableUnauthorizedAccount))
+        } else if selector == ethabi::short_signature("OwnableInvalidOwner", &[ParamType::Address])
+        {
+            decode_args(args, ParamType::Address)
+                .and_then(|token| token.into_address().map(Self::OwnableInvalidOwner))
+        } else if selector == ethabi::short_signature("ReentrancyGuardReentrantCall", &[]) {
+            Some(Self::ReentrancyGuardReentrantCall)
+        } else {
+            None
+        };
+
+        decoded.unwrap_or_else(|| Self::Unknown(data.to_vec()))
+    }
+
+    /// Decode the message of a failed call as reported by the runtime. Returns `None` if the
+    /// message does not describe a revert or an out-of-gas failure.
+    pub fn from_message(message: &str) -> Option<Self> {
+        if message.contains("OutOfGas") || message.contains("out of gas") {
+            return Some(Self::OutOfGas);
+        }
+
+        let reason = &message[message.find(REVERTED_PREFIX)? + REVERTED_PREFIX.len()..];
+        if reason == NO_REASON {
+            return Some(Self::Empty);
+        }
+
+        // Plain messages are never valid base64 of known revert data, so anything that does not
+        // decode into a known error is the message of an `Error(string)` revert.
+        match BASE64.decode(reason).map(|data| Self::decode(&data)) {
+            Ok(Self::Unknown(_)) | Err(_) => Some(Self::Revert(reason.to_owned())),
+            Ok(error) => Some(error),
+        }
+    }
+
+    /// Find the contract error behind `err`, either as a typed error or in one of the messages of
+    /// its chain.
+    pub fn from_error(err: &anyhow::Error) -> Option<Self> {
+        if let Some(error) = err.downcast_ref::<Self>() {
+            return Some(error.clone());
+        }
+
+        err.chain()
+            .find_map(|cause| Self::from_message(&cause.to_string()))
+    }
+
+    /// Whether the call may succeed when retried. Only running out of gas is, as the gas is
+    /// estimated again; reverts depend on the vault's state and fail the same way again.
+    pub fn is_transient(&self) -> bool {
+        matches!(self, Self::OutOfGas)
+    }
+}
+
+/// Message the EVM module reports for a call that reverted with `data`, as parsed by
+/// [`ContractError::from_message`]. The in-process EVM of the tests reports reverts with it.
+#[cfg(test)]
+pub fn reverted_message(data: &[u8]) -> String {
+    let reason = match ContractError::decode(data) {
+        ContractError::Revert(reason) => reason,
+        ContractError::Empty => NO_REASON.to_owned(),
+        _ => BASE64.encode(data),
+    };
+    format!("{}{}", REVERTED_PREFIX, reason)
+}
+
+/// Attach the contract error behind `err`, if any, so callers can recover it with
+/// `downcast_ref::<ContractError>()` and logs show the decoded reason first.
+pub fn decode_error(err: anyhow::Error) -> anyhow::Error {
+    match ContractError::from_error(&err) {
+        Some(error) if err.downcast_ref::<ContractError>().is_none() => err.context(error),
+        _ => err,
+    }
+}
+
+impl fmt::Display for ContractError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Revert(reason) => write!(f, "reverted: {}", reason),
+            Self::Panic(code) => write!(f, "panicked: {} (0x{:02x})", panic_reason(*code), code),
+            Self::EnforcedPause => write!(f, "reverted: vault is paused"),
+            Self::ExpectedPause => write!(f, "reverted: vault is not paused"),
+            Self::OwnableUnauthorizedAccount(account) => {
+                write!(f, "reverted: {:?} is not the vault owner", account)
+            }
+            Self::OwnableInvalidOwner(owner) => write!(f, "reverted: invalid owner {:?}", owner),
+            Self::ReentrancyGuardReentrantCall => write!(f, "reverted: reentrant call"),
+            Self::Empty => write!(f, "reverted without a reason"),
+            Self::Unknown(data) => write!(f, "reverted with unknown data 0x{}", hex::encode(data)),
+            Self::OutOfGas => write!(f, "out of gas"),
+        }
+    }
+}
+
+impl std::error::Error for ContractError {}
+
+fn decode_args(data: &[u8], kind: ParamType) -> Option<Token> {
+    ethabi::decode(&[kind], data).ok()?.pop()
+}
+
+/// Description of a Solidity panic code.
+fn panic_reason(code: Uint) -> &'static str {
+    if code > Uint::from(u8::MAX) {
+        return "unknown panic";
+    }
+
+    match code.low_u64() {
+        0x00 => "generic compiler panic",
+        0x01 => "assertion failed",
+        0x11 => "arithmetic overflow or underflow",
+        0x12 => "division or modulo by zero",
+        0x21 => "invalid enum value",
+        0x22 => "invalid storage byte array",
+        0x31 => "pop on empty array",
+        0x32 => "array index out of bounds",
+        0x41 => "out of memory",
+        0x51 => "call to invalid internal function",
+        _ => "unknown panic",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::anyhow;
+    use ethabi::Contract;
+
+    fn revert_data(name: &str, params: &[ParamType], args: &[Token]) -> Vec<u8> {
+        let mut data = ethabi::short_signature(name, params).to_vec();
+        data.extend(ethabi::encode(args));
+        data
+    }
+
+    #[test]
+    fn covers_vault_abi_errors() {
+        let artifact: serde_json::Value =
+            serde_json::from_str(include_str!("../../rofl-bun/abis/Vault.json")).unwrap();
+        let abi: Contract = serde_json::from_value(artifact["abi"].clone()).unwrap();
+
+        for error in abi.errors() {
+            let params: Vec<ParamType> = error.inputs.iter().map(|p| p.kind.clone()).collect();
+            let args: Vec<Token> = params
+                .iter()
+                .map(|kind| match kind {
+                    ParamType::Address => Token::Address(Address::repeat_byte(0x11)),
+                    other => panic!("unexpected parameter {:?}", other),
+                })
+                .collect();
+
+            let data = error.encode(&args).unwrap();
+            assert!(
+                !matches!(ContractError::decode(&data), ContractError::Unknown(_)),
+                "{}",
+                error.name
+            );
+        }
+    }
+
+    #[test]
+    fn decodes_revert_data() {
+        assert_eq!(
+            ContractError::decode(&revert_data(
+                "Error",
+                &[ParamType::String],
+                &[Token::String("Proposal expired".into())]
+            )),
+            ContractError::Revert("Proposal expired".into())
+        );
+        assert_eq!(
+            ContractError::decode(&revert_data(
+                "Panic",
+                &[ParamType::Uint(256)],
+                &[Token::Uint(0x11.into())]
+            )),
+            ContractError::Panic(0x11.into())
+        );
+        assert_eq!(
+            ContractError::decode(&revert_data(
+                "OwnableUnauthorizedAccount",
+                &[ParamType::Address],
+                &[Token::Address(Address::repeat_byte(0xab))]
+            )),
+            ContractError::OwnableUnauthorizedAccount(Address::repeat_byte(0xab))
+        );
+        assert_eq!(
+            ContractError::decode(&revert_data("EnforcedPause", &[], &[])),
+            ContractError::EnforcedPause
+        );
+        assert_eq!(ContractError::decode(&[]), ContractError::Empty);
+        assert_eq!(
+            ContractError::decode(&[1, 2, 3, 4, 5]),
+            ContractError::Unknown(vec![1, 2, 3, 4, 5])
+        );
+        // Known selector with arguments that do not decode.
+        let truncated = ethabi::short_signature("Error", &[ParamType::String]).to_vec();
+        assert!(matches!(
+            ContractError::decode(&truncated),
+            ContractError::Unknown(_)
+        ));
+    }
+
+    #[test]
+    fn decodes_runtime_messages() {
+        assert_eq!(
+            ContractError::from_message("reverted: Proposal not approved"),
+            Some(ContractError::Revert("Proposal not approved".into()))
+        );
+        assert_eq!(
+            ContractError::from_message("reverted: no revert reason"),
+            Some(ContractError::Empty)
+        );
+
+        let paused = BASE64.encode(revert_data("EnforcedPause", &[], &[]));
+        assert_eq!(
+            ContractError::from_message(&format!("reverted: {}", paused)),
+            Some(ContractError::EnforcedPause)
+        );
+        let panic = BASE64.encode(revert_data(
+            "Panic",
+            &[ParamType::Uint(256)],
+            &[Token::Uint(0x12.into())],
+        ));
+        assert_eq!(
+            ContractError::from_message(&format!("reverted: {}", panic)),
+            Some(ContractError::Panic(0x12.into()))
+        );
+
+        assert_eq!(
+            ContractError::from_message("execution failed: OutOfGas"),
+            Some(ContractError::OutOfGas)
+        );
+        assert_eq!(
+            ContractError::from_message("out of gas (limit: 100 wanted: 200)"),
+            Some(ContractError::OutOfGas)
+        );
+        assert_eq!(ContractError::from_message("connection refused"), None);
+
+        for data in [
+            revert_data(
+                "Error",
+                &[ParamType::String],
+                &[Token::String("Proposal expired".into())],
+            ),
+            revert_data("EnforcedPause", &[], &[]),
+            vec![],
+        ] {
+            assert_eq!(
+                ContractError::from_message(&reverted_message(&data)),
+                Some(ContractError::decode(&data))
+            );
+        }
+    }
+
+    #[test]
+    fn finds_errors_in_chain() {
+        let err = anyhow!("reverted: Proposal expired").context("query failed");
+        assert_eq!(
+            ContractError::from_error(&err),
+            Some(ContractError::Revert("Proposal expired".into()))
+        );
+
+        let err = anyhow::Error::new(ContractError::OutOfGas).context("submitting failed");
+        let error = ContractError::from_error(&err).unwrap();
+        assert!(error.is_transient());
+        assert!(!ContractError::EnforcedPause.is_transient());
+
+        assert_eq!(ContractError::from_error(&anyhow!("timeout")), None);
+    }
+
+    #[test]
+    fn attaches_decoded_errors() {
+        let err = decode_error(anyhow!("call failed: reverted: Proposal expired"));
+        assert_eq!(
+            err.downcast_ref::<ContractError>(),
+            Some(&ContractError::Revert("Proposal expired".into()))
+        );
+        assert_eq!(err.to_string(), "reverted: Proposal expired");
+
+        let err = decode_error(anyhow!("timeout"));
+        assert!(err.downcast_ref::<ContractError>().is_none());
+        assert_eq!(err.to_string(), "timeout");
+    }
+
+    #[test]
+    fn describes_errors() {
+        assert_eq!(
+            ContractError::Panic(0x12.into()).to_string(),
+            "panicked: division or modulo by zero (0x12)"
+        );
+        assert_eq!(
+            ContractError::Revert("Proposal expired".into()).to_string(),
+            "reverted: Proposal expired"
+        );
+    }
+}
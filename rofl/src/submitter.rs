@@ -0,0 +1,209 @@
+//! Policy for submitting `consumeProposal` transactions: gas sizing and retries.
+use std::fmt;
+use std::time::Duration;
+
+use anyhow::{Result, anyhow};
+
+use crate::config::GasConfig;
+use crate::revert::ContractError;
+
+/// Number of attempts made for each step of a submission before giving up.
+pub const MAX_ATTEMPTS: u32 = 4;
+
+/// Delay before the first retry; it doubles with every further attempt.
+const BASE_BACKOFF: Duration = Duration::from_secs(2);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+/// Intrinsic cost of an EVM transaction and of each calldata byte. Results are stored as
+/// strings, so the calldata dominates the cost of large ones.
+const TX_BASE_GAS: u64 = 21_000;
+const CALLDATA_GAS_PER_BYTE: u64 = 16;
+
+/// Gas limit for a transaction with the given calldata, based on the simulated estimate. The
+/// estimate is never taken below the intrinsic cost of the calldata, and gets the configured
+/// margin on top as it is taken against the latest round while the transaction executes in a
+/// later one.
+pub fn gas_limit(estimate: u64, calldata_len: usize, config: &GasConfig) -> Result<u64> {
+    let intrinsic = (calldata_len as u64)
+        .saturating_mul(CALLDATA_GAS_PER_BYTE)
+        .saturating_add(TX_BASE_GAS);
+    let gas = estimate.max(intrinsic);
+    let gas = gas.saturating_add(gas.saturating_mul(config.margin_percent) / 100);
+
+    if gas > config.max_tx_gas {
+        return Err(anyhow!(
+            "transaction needs {} gas, more than the limit of {}",
+            gas,
+            config.max_tx_gas
+        ));
+    }
+
+    Ok(gas)
+}
+
+/// Fee paid for `gas` at the given price per unit of gas.
+pub fn fee_amount(gas: u64, gas_price: u128) -> u128 {
+    u128::from(gas).saturating_mul(gas_price)
+}
+
+/// Delay before retrying after the given (1-based) failed attempt.
+pub fn backoff(attempt: u32) -> Duration {
+    BASE_BACKOFF
+        .saturating_mul(1 << attempt.saturating_sub(1).min(16))
+        .min(MAX_BACKOFF)
+}
+
+/// Run `op` until it succeeds, a non-transient error occurs or the attempts run out, backing off
+/// between attempts.
+pub async fn retry<T, F, Fut>(what: &str, mut op: F) -> Result<T>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T>>,
+{
+    let mut attempt = 1;
+    loop {
+        match op().await {
+            Ok(value) => return Ok(value),
+            Err(err) if attempt < MAX_ATTEMPTS && is_transient(&err) => {
+                let delay = backoff(attempt);
+                tracing::warn!(
+                    "Failed to {} (attempt {}), retrying in {:?}: {:?}",
+                    what,
+                    attempt,
+                    delay,
+                    err
+                );
+                tokio::time::sleep(delay).await;
+                attempt += 1;
+            }
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+/// A transaction that was included in a block but failed to execute.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TxFailed {
+    pub module: String,
+    pub code: u32,
+    pub message: String,
+}
+
+impl TxFailed {
+    /// The contract error the transaction failed with, if it reverted or ran out of gas.
+    pub fn contract_error(&self) -> Option<ContractError> {
+        ContractError::from_message(&self.message)
+    }
+}
+
+impl fmt::Display for TxFailed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.contract_error() {
+            Some(error) => write!(f, "transaction failed: {}", error),
+            None => write!(
+                f,
+                "transaction failed in module {} with code {}: {}",
+                self.module, self.code, self.message
+            ),
+        }
+    }
+}
+
+impl std::error::Error for TxFailed {}
+
+/// Whether an operation that failed with `err` is worth retrying. Failed transactions and
+/// reverted calls fail the same way again, while errors unrelated to the contract (network,
+/// node) are assumed to be temporary.
+pub fn is_transient(err: &anyhow::Error) -> bool {
+    if err.is::<TxFailed>() {
+        return false;
+    }
+
+    match ContractError::from_error(err) {
+        Some(error) => error.is_transient(),
+        None => true,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sizes_gas_to_estimate_and_calldata() {
+        let config = GasConfig::default();
+        assert_eq!(gas_limit(100_000, 100, &config).unwrap(), 120_000);
+        // A low estimate is raised to the intrinsic cost of the calldata.
+        assert_eq!(
+            gas_limit(0, 10_000, &config).unwrap(),
+            (21_000 + 160_000) * 6 / 5
+        );
+        assert!(gas_limit(config.max_tx_gas, 0, &config).is_err());
+        assert!(gas_limit(0, 1 << 20, &config).is_err());
+
+        let config = GasConfig {
+            margin_percent: 0,
+            ..GasConfig::default()
+        };
+        assert_eq!(gas_limit(100_000, 100, &config).unwrap(), 100_000);
+
+        assert_eq!(fee_amount(120_000, 100_000_000_000), 12_000_000_000_000_000);
+        assert_eq!(fee_amount(u64::MAX, u128::MAX), u128::MAX);
+    }
+
+    #[test]
+    fn backs_off_exponentially() {
+        assert_eq!(backoff(1), Duration::from_secs(2));
+        assert_eq!(backoff(2), Duration::from_secs(4));
+        assert_eq!(backoff(3), Duration::from_secs(8));
+        assert_eq!(backoff(10), MAX_BACKOFF);
+        assert_eq!(backoff(u32::MAX), MAX_BACKOFF);
+    }
+
+    #[tokio::test]
+    async fn retries_transient_failures_only() {
+        let mut calls = 0;
+        let value = retry("flaky", || {
+            calls += 1;
+            let attempt = calls;
+            async move {
+                match attempt {
+                    1 => Err(anyhow!("connection reset")),
+                    _ => Ok(attempt),
+                }
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(value, 2);
+
+        let mut calls = 0;
+        let err = retry("revert", || {
+            calls += 1;
+            async { Err::<(), _>(anyhow!("reverted: Proposal expired")) }
+        })
+        .await
+        .unwrap_err();
+        assert_eq!(calls, 1);
+        assert!(err.to_string().contains("Proposal expired"));
+    }
+
+    #[test]
+    fn classifies_failures() {
+        let failed = anyhow::Error::new(TxFailed {
+            module: "evm".into(),
+            code: 8,
+            message: "reverted: Proposal not approved".into(),
+        });
+        assert!(!is_transient(&failed));
+        assert_eq!(
+            failed.to_string(),
+            "transaction failed: reverted: Proposal not approved"
+        );
+        assert!(!is_transient(
+            &anyhow!("reverted: Proposal expired").context("gas estimation failed")
+        ));
+        assert!(is_transient(&anyhow!("execution failed: OutOfGas")));
+        assert!(is_transient(&anyhow!("connection reset")));
+    }
+}
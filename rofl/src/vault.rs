@@ -0,0 +1,550 @@
+//! Typed bindings for the subset of the `Vault` contract ABI used by the engine.
+//!
+//! Every function has an `encode_*` helper producing calldata and, where the function returns
+//! something, a `decode_*` helper for the `evm.SimulateCall` response. The parameter types are
+//! checked against `rofl-bun/abis/Vault.json` in the tests below.
+use anyhow::{Result, anyhow};
+use ethabi::{Address, ParamType, Token, Uint};
+
+/// Mirror of the `Vault.ProposalStatus` enum.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ProposalStatus {
+    Pending = 0,
+    Approved = 1,
+    Completed = 2,
+    Rejected = 3,
+    Expired = 4,
+}
+
+impl TryFrom<u8> for ProposalStatus {
+    type Error = anyhow::Error;
+
+    fn try_from(value: u8) -> Result<Self> {
+        match value {
+            0 => Ok(Self::Pending),
+            1 => Ok(Self::Approved),
+            2 => Ok(Self::Completed),
+            3 => Ok(Self::Rejected),
+            4 => Ok(Self::Expired),
+            _ => Err(anyhow!("unknown proposal status {}", value)),
+        }
+    }
+}
+
+/// Mirror of the `Vault.QueryProposal` struct.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QueryProposal {
+    pub id: u64,
+    pub requester: Address,
+    pub sql_query: String,
+    pub public_key: String,
+    pub timestamp: u64,
+    pub expiration_time: u64,
+    pub status: ProposalStatus,
+    pub governance_proposal_id: Uint,
+}
+
+/// Mirror of the `Vault.CompletedQuery` struct.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CompletedQuery {
+    pub proposal_id: u64,
+    pub original_query: String,
+    pub public_key: String,
+    pub encrypted_result: String,
+    pub completed_timestamp: u64,
+}
+
+fn query_proposal_param_type() -> ParamType {
+    ParamType::Tuple(vec![
+        ParamType::Uint(256), // id
+        ParamType::Address,   // requester
+        ParamType::String,    // sqlQuery
+        ParamType::String,    // publicKey
+        ParamType::Uint(256), // timestamp
+        ParamType::Uint(256), // expirationTime
+        ParamType::Uint(8),   // status
+        ParamType::Uint(256), // governanceProposalId
+    ])
+}
+
+fn completed_query_param_type() -> ParamType {
+    ParamType::Tuple(vec![
+        ParamType::Uint(256), // proposalId
+        ParamType::String,    // originalQuery
+        ParamType::String,    // publicKey
+        ParamType::String,    // encryptedResult
+        ParamType::Uint(256), // completedTimestamp
+    ])
+}
+
+impl QueryProposal {
+    fn from_token(token: Token) -> Result<Self> {
+        let [
+            id,
+            requester,
+            sql_query,
+            public_key,
+            timestamp,
+            expiration_time,
+            status,
+            governance_proposal_id,
+        ] = into_fields::<8>(token, "QueryProposal")?;
+
+        Ok(Self {
+            id: into_u64(id)?,
+            requester: requester
+                .into_address()
+                .ok_or_else(|| anyhow!("expected an address for QueryProposal.requester"))?,
+            sql_query: into_string(sql_query)?,
+            public_key: into_string(public_key)?,
+            timestamp: into_u64(timestamp)?,
+            expiration_time: into_u64(expiration_time)?,
+            status: into_status(status)?,
+            governance_proposal_id: into_uint(governance_proposal_id)?,
+        })
+    }
+
+    /// ABI encoding of the struct, as the contract returns it.
+    pub fn to_token(&self) -> Token {
+        Token::Tuple(vec![
+            Token::Uint(self.id.into()),
+            Token::Address(self.requester),
+            Token::String(self.sql_query.clone()),
+            Token::String(self.public_key.clone()),
+            Token::Uint(self.timestamp.into()),
+            Token::Uint(self.expiration_time.into()),
+            Token::Uint((self.status as u8).into()),
+            Token::Uint(self.governance_proposal_id),
+        ])
+    }
+}
+
+impl CompletedQuery {
+    fn from_token(token: Token) -> Result<Self> {
+        let [
+            proposal_id,
+            original_query,
+            public_key,
+            encrypted_result,
+            completed_timestamp,
+        ] = into_fields::<5>(token, "CompletedQuery")?;
+
+        Ok(Self {
+            proposal_id: into_u64(proposal_id)?,
+            original_query: into_string(original_query)?,
+            public_key: into_string(public_key)?,
+            encrypted_result: into_string(encrypted_result)?,
+            completed_timestamp: into_u64(completed_timestamp)?,
+        })
+    }
+
+    /// ABI encoding of the struct, as the contract returns it.
+    pub fn to_token(&self) -> Token {
+        Token::Tuple(vec![
+            Token::Uint(self.proposal_id.into()),
+            Token::String(self.original_query.clone()),
+            Token::String(self.public_key.clone()),
+            Token::String(self.encrypted_result.clone()),
+            Token::Uint(self.completed_timestamp.into()),
+        ])
+    }
+}
+
+fn into_fields<const N: usize>(token: Token, name: &str) -> Result<[Token; N]> {
+    token
+        .into_tuple()
+        .ok_or_else(|| anyhow!("expected a {} tuple", name))?
+        .try_into()
+        .map_err(|fields: Vec<Token>| {
+            anyhow!("expected {} fields in {}, got {}", N, name, fields.len())
+        })
+}
+
+fn into_uint(token: Token) -> Result<Uint> {
+    token
+        .into_uint()
+        .ok_or_else(|| anyhow!("expected a uint value"))
+}
+
+fn into_u64(token: Token) -> Result<u64> {
+    let value = into_uint(token)?;
+    u64::try_from(value).map_err(|_| anyhow!("uint value {} does not fit in u64", value))
+}
+
+fn into_status(token: Token) -> Result<ProposalStatus> {
+    let value = into_uint(token)?;
+    let value = u8::try_from(value).map_err(|_| anyhow!("invalid proposal status {}", value))?;
+    ProposalStatus::try_from(value)
+}
+
+fn into_string(token: Token) -> Result<String> {
+    token
+        .into_string()
+        .ok_or_else(|| anyhow!("expected a string value"))
+}
+
+fn encode_call(name: &str, params: &[ParamType], values: &[Token]) -> Vec<u8> {
+    let function_signature = ethabi::short_signature(name, params);
+    [function_signature.to_vec(), ethabi::encode(values)].concat()
+}
+
+/// Decode the single return value of a call.
+fn decode_output(kind: ParamType, data: &[u8]) -> Result<Token> {
+    ethabi::decode(&[kind], data)?
+        .pop()
+        .ok_or_else(|| anyhow!("empty call output"))
+}
+
+fn decode_proposals(data: &[u8]) -> Result<Vec<QueryProposal>> {
+    decode_output(
+        ParamType::Array(Box::new(query_proposal_param_type())),
+        data,
+    )?
+    .into_array()
+    .ok_or_else(|| anyhow!("expected a QueryProposal array"))?
+    .into_iter()
+    .map(QueryProposal::from_token)
+    .collect()
+}
+
+/// `getProposalsByStatus(uint8 status) returns (QueryProposal[])`
+pub fn encode_get_proposals_by_status(status: ProposalStatus) -> Vec<u8> {
+    encode_call(
+        "getProposalsByStatus",
+        &[ParamType::Uint(8)],
+        &[Token::Uint((status as u8).into())],
+    )
+}
+
+pub fn decode_get_proposals_by_status(data: &[u8]) -> Result<Vec<QueryProposal>> {
+    decode_proposals(data)
+}
+
+/// `getProposal(uint256 proposalId) returns (QueryProposal)`
+pub fn encode_get_proposal(proposal_id: u64) -> Vec<u8> {
+    encode_call(
+        "getProposal",
+        &[ParamType::Uint(256)],
+        &[Token::Uint(proposal_id.into())],
+    )
+}
+
+pub fn decode_get_proposal(data: &[u8]) -> Result<QueryProposal> {
+    QueryProposal::from_token(decode_output(query_proposal_param_type(), data)?)
+}
+
+/// `getApprovedProposals(uint256 offset, uint256 limit) returns (QueryProposal[])`
+pub fn encode_get_approved_proposals(offset: u64, limit: u64) -> Vec<u8> {
+    encode_call(
+        "getApprovedProposals",
+        &[ParamType::Uint(256), ParamType::Uint(256)],
+        &[Token::Uint(offset.into()), Token::Uint(limit.into())],
+    )
+}
+
+pub fn decode_get_approved_proposals(data: &[u8]) -> Result<Vec<QueryProposal>> {
+    decode_proposals(data)
+}
+
+/// `getCompletedQuery(uint256 proposalId) returns (CompletedQuery)`
+pub fn encode_get_completed_query(proposal_id: u64) -> Vec<u8> {
+    encode_call(
+        "getCompletedQuery",
+        &[ParamType::Uint(256)],
+        &[Token::Uint(proposal_id.into())],
+    )
+}
+
+pub fn decode_get_completed_query(data: &[u8]) -> Result<CompletedQuery> {
+    CompletedQuery::from_token(decode_output(completed_query_param_type(), data)?)
+}
+
+/// `consumeProposal(uint256 proposalId, string encryptedResult)`
+pub fn encode_consume_proposal(proposal_id: u64, encrypted_result: &str) -> Vec<u8> {
+    encode_call(
+        "consumeProposal",
+        &[ParamType::Uint(256), ParamType::String],
+        &[
+            Token::Uint(proposal_id.into()),
+            Token::String(encrypted_result.to_owned()),
+        ],
+    )
+}
+
+/// `proposeQuery(string sqlQuery, string publicKey) returns (uint256)`
+pub fn encode_propose_query(sql_query: &str, public_key: &str) -> Vec<u8> {
+    encode_call(
+        "proposeQuery",
+        &[ParamType::String, ParamType::String],
+        &[
+            Token::String(sql_query.to_owned()),
+            Token::String(public_key.to_owned()),
+        ],
+    )
+}
+
+pub fn decode_propose_query(data: &[u8]) -> Result<u64> {
+    into_u64(decode_output(ParamType::Uint(256), data)?)
+}
+
+/// `createGovernanceProposal(uint256 proposalId)`
+pub fn encode_create_governance_proposal(proposal_id: u64) -> Vec<u8> {
+    encode_call(
+        "createGovernanceProposal",
+        &[ParamType::Uint(256)],
+        &[Token::Uint(proposal_id.into())],
+    )
+}
+
+/// `approveProposal(uint256 proposalId)`
+pub fn encode_approve_proposal(proposal_id: u64) -> Vec<u8> {
+    encode_call(
+        "approveProposal",
+        &[ParamType::Uint(256)],
+        &[Token::Uint(proposal_id.into())],
+    )
+}
+
+/// `setGovernor(address _governor)`
+pub fn encode_set_governor(governor: Address) -> Vec<u8> {
+    encode_call(
+        "setGovernor",
+        &[ParamType::Address],
+        &[Token::Address(governor)],
+    )
+}
+
+/// `checkAndUpdateExpiredProposals()`
+pub fn encode_check_and_update_expired_proposals() -> Vec<u8> {
+    encode_call("checkAndUpdateExpiredProposals", &[], &[])
+}
+
+/// `appId() returns (bytes21)`
+pub fn encode_app_id() -> Vec<u8> {
+    encode_call("appId", &[], &[])
+}
+
+pub fn decode_app_id(data: &[u8]) -> Result<[u8; 21]> {
+    decode_output(ParamType::FixedBytes(21), data)?
+        .into_fixed_bytes()
+        .ok_or_else(|| anyhow!("expected bytes21"))?
+        .try_into()
+        .map_err(|_| anyhow!("expected 21 bytes for appId"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ethabi::{Contract, ethereum_types::U256};
+
+    fn vault_abi() -> Contract {
+        let artifact: serde_json::Value =
+            serde_json::from_str(include_str!("../../rofl-bun/abis/Vault.json")).unwrap();
+        serde_json::from_value(artifact["abi"].clone()).unwrap()
+    }
+
+    /// Asserts that the calldata built by the bindings matches what the ABI file produces.
+    fn assert_calldata(name: &str, values: &[Token], calldata: Vec<u8>) {
+        let abi = vault_abi();
+        let function = abi.function(name).unwrap();
+        assert_eq!(function.encode_input(values).unwrap(), calldata, "{}", name);
+    }
+
+    fn assert_output(name: &str, kind: ParamType) {
+        let abi = vault_abi();
+        let function = abi.function(name).unwrap();
+        let outputs: Vec<ParamType> = function.outputs.iter().map(|o| o.kind.clone()).collect();
+        assert_eq!(outputs, vec![kind], "{}", name);
+    }
+
+    fn proposal(id: u64, status: ProposalStatus) -> QueryProposal {
+        QueryProposal {
+            id,
+            requester: Address::repeat_byte(0x11),
+            sql_query: "SELECT city, COUNT(*) FROM names_and_cities GROUP BY city".into(),
+            public_key: "0x02deadbeef".into(),
+            timestamp: 1_700_000_000,
+            expiration_time: 1_700_604_800,
+            status,
+            governance_proposal_id: U256::MAX,
+        }
+    }
+
+    fn proposal_token(p: &QueryProposal) -> Token {
+        Token::Tuple(vec![
+            Token::Uint(p.id.into()),
+            Token::Address(p.requester),
+            Token::String(p.sql_query.clone()),
+            Token::String(p.public_key.clone()),
+            Token::Uint(p.timestamp.into()),
+            Token::Uint(p.expiration_time.into()),
+            Token::Uint((p.status as u8).into()),
+            Token::Uint(p.governance_proposal_id),
+        ])
+    }
+
+    #[test]
+    fn calldata_matches_abi() {
+        assert_calldata(
+            "getProposalsByStatus",
+            &[Token::Uint(1.into())],
+            encode_get_proposals_by_status(ProposalStatus::Approved),
+        );
+        assert_calldata(
+            "getProposal",
+            &[Token::Uint(7.into())],
+            encode_get_proposal(7),
+        );
+        assert_calldata(
+            "getApprovedProposals",
+            &[Token::Uint(20.into()), Token::Uint(10.into())],
+            encode_get_approved_proposals(20, 10),
+        );
+        assert_calldata(
+            "getCompletedQuery",
+            &[Token::Uint(3.into())],
+            encode_get_completed_query(3),
+        );
+        assert_calldata(
+            "consumeProposal",
+            &[Token::Uint(3.into()), Token::String("result".into())],
+            encode_consume_proposal(3, "result"),
+        );
+        assert_calldata(
+            "proposeQuery",
+            &[
+                Token::String("SELECT 1".into()),
+                Token::String("0x02".into()),
+            ],
+            encode_propose_query("SELECT 1", "0x02"),
+        );
+        assert_calldata(
+            "createGovernanceProposal",
+            &[Token::Uint(3.into())],
+            encode_create_governance_proposal(3),
+        );
+        assert_calldata(
+            "approveProposal",
+            &[Token::Uint(3.into())],
+            encode_approve_proposal(3),
+        );
+        assert_calldata(
+            "setGovernor",
+            &[Token::Address(Address::repeat_byte(0xa0))],
+            encode_set_governor(Address::repeat_byte(0xa0)),
+        );
+        assert_calldata(
+            "checkAndUpdateExpiredProposals",
+            &[],
+            encode_check_and_update_expired_proposals(),
+        );
+        assert_calldata("appId", &[], encode_app_id());
+    }
+
+    #[test]
+    fn output_types_match_abi() {
+        let proposals = ParamType::Array(Box::new(query_proposal_param_type()));
+        assert_output("getProposalsByStatus", proposals.clone());
+        assert_output("getApprovedProposals", proposals);
+        assert_output("getProposal", query_proposal_param_type());
+        assert_output("getCompletedQuery", completed_query_param_type());
+        assert_output("appId", ParamType::FixedBytes(21));
+        assert_output("proposeQuery", ParamType::Uint(256));
+    }
+
+    #[test]
+    fn status_enum_matches_contract() {
+        let source = include_str!("../../scaffold-eth/packages/hardhat/contracts/Vault.sol");
+        let start = source.find("enum ProposalStatus {").unwrap();
+        let end = start + source[start..].find('}').unwrap();
+        let variants: Vec<&str> = source[start..end]
+            .trim_start_matches("enum ProposalStatus {")
+            .split(',')
+            .map(str::trim)
+            .collect();
+
+        assert_eq!(
+            variants,
+            ["Pending", "Approved", "Completed", "Rejected", "Expired"]
+        );
+        for (index, name) in variants.iter().enumerate() {
+            let status = ProposalStatus::try_from(index as u8).unwrap();
+            assert_eq!(format!("{:?}", status), *name);
+        }
+        assert!(ProposalStatus::try_from(5).is_err());
+    }
+
+    #[test]
+    fn decodes_proposal_arrays() {
+        let expected = vec![
+            proposal(1, ProposalStatus::Approved),
+            proposal(2, ProposalStatus::Approved),
+        ];
+        let data = ethabi::encode(&[Token::Array(expected.iter().map(proposal_token).collect())]);
+
+        assert_eq!(decode_get_approved_proposals(&data).unwrap(), expected);
+        assert_eq!(decode_get_proposals_by_status(&data).unwrap(), expected);
+        assert!(
+            decode_get_approved_proposals(&ethabi::encode(&[Token::Array(vec![])]))
+                .unwrap()
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn decodes_single_proposal() {
+        let expected = proposal(9, ProposalStatus::Pending);
+        let data = ethabi::encode(&[proposal_token(&expected)]);
+        assert_eq!(decode_get_proposal(&data).unwrap(), expected);
+        assert_eq!(expected.to_token(), proposal_token(&expected));
+    }
+
+    #[test]
+    fn decodes_completed_query() {
+        let data = ethabi::encode(&[Token::Tuple(vec![
+            Token::Uint(4.into()),
+            Token::String("SELECT 1".into()),
+            Token::String("0x02deadbeef".into()),
+            Token::String("ciphertext".into()),
+            Token::Uint(1_700_000_100u64.into()),
+        ])]);
+
+        let expected = CompletedQuery {
+            proposal_id: 4,
+            original_query: "SELECT 1".into(),
+            public_key: "0x02deadbeef".into(),
+            encrypted_result: "ciphertext".into(),
+            completed_timestamp: 1_700_000_100,
+        };
+        assert_eq!(decode_get_completed_query(&data).unwrap(), expected);
+        assert_eq!(ethabi::encode(&[expected.to_token()]), data);
+    }
+
+    #[test]
+    fn decodes_app_id() {
+        let app_id = [7u8; 21];
+        let data = ethabi::encode(&[Token::FixedBytes(app_id.to_vec())]);
+        assert_eq!(decode_app_id(&data).unwrap(), app_id);
+    }
+
+    #[test]
+    fn rejects_malformed_outputs() {
+        // Truncated data.
+        assert!(decode_get_approved_proposals(&[0u8; 16]).is_err());
+
+        // Out-of-range status.
+        let bad = proposal(1, ProposalStatus::Approved);
+        let mut token = proposal_token(&bad);
+        if let Token::Tuple(fields) = &mut token {
+            fields[6] = Token::Uint(9.into());
+        }
+        assert!(decode_get_proposal(&ethabi::encode(&[token])).is_err());
+
+        // Proposal ID that does not fit the engine's u64 IDs.
+        let mut token = proposal_token(&bad);
+        if let Token::Tuple(fields) = &mut token {
+            fields[0] = Token::Uint(U256::MAX);
+        }
+        assert!(decode_get_proposal(&ethabi::encode(&[token])).is_err());
+    }
+}